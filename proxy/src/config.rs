@@ -0,0 +1,55 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{net::SocketAddr, time::Duration};
+
+pub const USAGE: &str =
+    "Usage: proxy --listen <host:port> --backend <host:port> [--max-backoff <seconds>]
+
+Relays miner connections to a stratum-server running with `with_proxy(true)`, prefixing each
+connection with a PROXY protocol header that carries the miner's address.";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Where miners connect.
+    pub listen: SocketAddr,
+    /// The stratum-server's `host:port`.
+    pub backend: String,
+    /// The delay before the first reconnect attempt to the backend. It doubles after every failed
+    /// attempt, up to `max_backoff`.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Config {
+    /// Parses the command line, without the program name.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut listen = None;
+        let mut backend = None;
+        let mut max_backoff = Duration::from_secs(30);
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{arg} requires a value"));
+
+            match arg.as_str() {
+                "--listen" => {
+                    listen = Some(value()?.parse().context("--listen must be a host:port")?);
+                }
+                "--backend" => backend = Some(value()?),
+                "--max-backoff" => {
+                    max_backoff = Duration::from_secs(
+                        value()?
+                            .parse()
+                            .context("--max-backoff must be a number of seconds")?,
+                    );
+                }
+                _ => bail!("Unknown argument {arg}"),
+            }
+        }
+
+        Ok(Config {
+            listen: listen.ok_or_else(|| anyhow!("--listen is required"))?,
+            backend: backend.ok_or_else(|| anyhow!("--backend is required"))?,
+            min_backoff: Duration::from_secs(1).min(max_backoff),
+            max_backoff,
+        })
+    }
+}
@@ -1,47 +1,70 @@
-fn main() {
-        let listening_host = format!("{}:{}", self.config.host, self.config.port);
-        let listener = TcpListener::bind(&listening_host).await?;
-        let mut incoming = listener.incoming();
-
-        info!("Listening on {}", listening_host);
-
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
-            //If Proxy === true, then we don't get this information from the stream....
-            //We need to read a newline, and then parse the proxy info from that. AND Then
-            //create a new miner from that information.
-            //
-            let mut buf = String::new();
-            let (rh, wh) = stream.split();
-            let mut buffer_stream = BufReader::new(rh);
-            buffer_stream.read_line(&mut buf).await?;
-
-            //Buf will be of the format "PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n"
-            //Trim the \r\n off
-            let buf = buf.trim();
-            //Might want to not be ascii whitespace and just normal here.
-            // let pieces = buf.split_ascii_whitespace();
-
-            let pieces: Vec<&str> = buf.split(' ').collect();
-            let addr: SocketAddr = format!("{}:{}", pieces[2], pieces[5]).parse().unwrap();
-
-            if addr.port() != self.config.port {
-                continue;
-            }
+#![warn(clippy::pedantic)]
+
+//! A stateless relay that sits in front of `StratumServer` instances running with
+//! `with_proxy(true)`. Each miner connection is relayed line by line to the backend, prefixed with
+//! a PROXY protocol header so that the server sees the miner's real address.
+
+mod config;
+mod relay;
 
-            task::spawn(async move {
-                info!("Accepting stream from: {}", addr);
+use anyhow::Result;
+use config::{Config, USAGE};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-            //1. Make a connection to Stratum FROM the above information.
-            //2. Pass in the first line of PROXy PROTOCOL
-            //3. Loop, Read a line from the stream.
-            //4. If strts with DISCONNECT then drop the connection.
-            //5. othrwise forward the message upstream.
-            //6. IF our connection to Stratum errors outs, instead of panicing on that thread.
-            //7. We attempt to reconnect.
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
 
-                info!("Closing stream from: {}", addr);
-            });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return Ok(());
+    }
+
+    let config = match Config::from_args(args.into_iter()) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
         }
-        Ok(())
+    };
+
+    let listener = TcpListener::bind(config.listen).await?;
+    info!(
+        "Listening on {}, relaying to {}",
+        config.listen, config.backend
+    );
+
+    loop {
+        let (stream, address) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(cause = %e, "Unable to accept connection");
+                    continue;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        };
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            info!("Accepting stream from: {address}");
+
+            if let Err(e) = relay::run(stream, &config).await {
+                warn!(cause = %e, "Stream from {address} failed");
+            }
+
+            info!("Closing stream from: {address}");
+        });
+    }
+
+    info!("Shutting down");
+
+    Ok(())
 }
@@ -0,0 +1,460 @@
+//! Relays a single miner connection to the backend.
+//!
+//! The backend connection is re-established whenever it drops, without dropping the miner. The
+//! miner's session setup requests (`mining.configure`, `mining.subscribe`,
+//! `mining.extranonce.subscribe` and `mining.authorize`) are replayed on the new connection and
+//! their responses are swallowed. If the new subscription has a different extranonce the miner is
+//! sent a `mining.set_extranonce`. Anything the miner sends while the backend is away is buffered
+//! and sent once it is back.
+//!
+//! A line from the backend starting with `DISCONNECT` closes the miner's connection.
+
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::{collections::HashSet, io, net::SocketAddr};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    task::JoinHandle,
+};
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tracing::{debug, warn};
+
+const REPLAYED_METHODS: [&str; 4] = [
+    "mining.configure",
+    "mining.subscribe",
+    "mining.extranonce.subscribe",
+    "mining.authorize",
+];
+
+/// The most lines buffered from a miner while the backend is reconnecting.
+const MAX_BUFFERED_LINES: usize = 64;
+
+/// The longest line accepted from a miner, the same as the server's default `max_frame_size`.
+const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// The PROXY protocol (v1) header describing a connection from `source` to `destination`.
+pub fn proxy_header(source: SocketAddr, destination: SocketAddr) -> String {
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            return format!(
+                "PROXY TCP4 {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            );
+        }
+        (source, destination) => (source, destination),
+    };
+
+    let ipv6 = |address: SocketAddr| match address {
+        SocketAddr::V4(address) => address.ip().to_ipv6_mapped(),
+        SocketAddr::V6(address) => *address.ip(),
+    };
+
+    format!(
+        "PROXY TCP6 {} {} {} {}\r\n",
+        ipv6(source),
+        ipv6(destination),
+        source.port(),
+        destination.port()
+    )
+}
+
+pub async fn run(miner: TcpStream, config: &Config) -> Result<()> {
+    let header = proxy_header(miner.peer_addr()?, miner.local_addr()?);
+    let (reader, mut miner_writer) = miner.into_split();
+    let mut miner_lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+
+    let mut relay = Relay::default();
+    let mut backend = State::Connecting(reconnect(config, &header));
+
+    let result = loop {
+        let next = match &mut backend {
+            State::Connecting(handle) => tokio::select! {
+                connected = handle => {
+                    let mut connected = connected?;
+
+                    match connected.send_all(&relay.reconnected()).await {
+                        Ok(()) => {
+                            relay.flushed();
+                            Some(State::Connected(connected))
+                        }
+                        Err(e) => {
+                            warn!(cause = %e, "Backend {} failed while replaying", config.backend);
+                            Some(State::Connecting(reconnect(config, &header)))
+                        }
+                    }
+                }
+                line = miner_lines.next() => match line.transpose()? {
+                    Some(line) => {
+                        relay.miner_sent(&line);
+                        if !relay.buffer(line) {
+                            break Err(anyhow!("Miner sent too much while the backend was away"));
+                        }
+                        None
+                    }
+                    None => break Ok(()),
+                },
+            },
+            State::Connected(connected) => tokio::select! {
+                line = miner_lines.next() => match line.transpose()? {
+                    Some(line) => {
+                        relay.miner_sent(&line);
+
+                        if let Err(e) = connected.send(&line).await {
+                            warn!(cause = %e, "Backend {} failed, reconnecting", config.backend);
+                            if !relay.buffer(line) {
+                                break Err(anyhow!("Miner sent too much while the backend was away"));
+                            }
+                            Some(State::Connecting(reconnect(config, &header)))
+                        } else {
+                            None
+                        }
+                    }
+                    None => break Ok(()),
+                },
+                line = connected.lines.next_line() => match line {
+                    Ok(Some(line)) => {
+                        if line.starts_with("DISCONNECT") {
+                            debug!("Backend asked to disconnect the miner");
+                            break Ok(());
+                        }
+
+                        for line in relay.backend_sent(&line) {
+                            miner_writer.write_all(line.as_bytes()).await?;
+                            miner_writer.write_all(b"\n").await?;
+                        }
+                        None
+                    }
+                    Ok(None) => {
+                        warn!("Backend {} closed the connection, reconnecting", config.backend);
+                        Some(State::Connecting(reconnect(config, &header)))
+                    }
+                    Err(e) => {
+                        warn!(cause = %e, "Backend {} failed, reconnecting", config.backend);
+                        Some(State::Connecting(reconnect(config, &header)))
+                    }
+                },
+            },
+        };
+
+        if let Some(next) = next {
+            backend = next;
+        }
+    };
+
+    if let State::Connecting(handle) = backend {
+        handle.abort();
+    }
+
+    result
+}
+
+enum State {
+    Connecting(JoinHandle<Backend>),
+    Connected(Backend),
+}
+
+struct Backend {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Backend {
+    async fn send(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await
+    }
+
+    async fn send_all(&mut self, lines: &[String]) -> io::Result<()> {
+        for line in lines {
+            self.send(line).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the backend in the background, retrying with backoff until it succeeds. The PROXY
+/// header is the first thing sent on every connection.
+fn reconnect(config: &Config, header: &str) -> JoinHandle<Backend> {
+    let address = config.backend.clone();
+    let header = header.to_string();
+    let (min_backoff, max_backoff) = (config.min_backoff, config.max_backoff);
+
+    tokio::spawn(async move {
+        let mut backoff = min_backoff;
+
+        loop {
+            match connect(&address, &header).await {
+                Ok(backend) => return backend,
+                Err(e) => warn!(cause = %e, "Unable to connect to backend {address}"),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    })
+}
+
+async fn connect(address: &str, header: &str) -> io::Result<Backend> {
+    let stream = TcpStream::connect(address).await?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(header.as_bytes()).await?;
+
+    Ok(Backend {
+        lines: BufReader::new(reader).lines(),
+        writer,
+    })
+}
+
+/// What the relay remembers about the miner's session.
+#[derive(Default)]
+struct Relay {
+    /// The latest request for each of `REPLAYED_METHODS`, in the order they were first sent.
+    replay: Vec<(String, String)>,
+    /// Ids of replayed requests, whose responses are not meant for the miner.
+    replayed_ids: HashSet<String>,
+    subscribe_id: Option<String>,
+    /// The extranonce1 and extranonce2 size the miner is currently working with.
+    extranonce: Option<(Value, Value)>,
+    buffered: Vec<String>,
+}
+
+impl Relay {
+    fn miner_sent(&mut self, line: &str) {
+        let Ok(request) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        let Some(method) = request["method"].as_str() else {
+            return;
+        };
+        if !REPLAYED_METHODS.contains(&method) {
+            return;
+        }
+
+        if method == "mining.subscribe" {
+            self.subscribe_id = Some(request["id"].to_string());
+        }
+
+        match self.replay.iter_mut().find(|(m, _)| m == method) {
+            Some((_, replay)) => line.clone_into(replay),
+            None => self.replay.push((method.to_string(), line.to_string())),
+        }
+    }
+
+    /// Returns the lines that should be sent on to the miner.
+    fn backend_sent(&mut self, line: &str) -> Vec<String> {
+        let Ok(message) = serde_json::from_str::<Value>(line) else {
+            return vec![line.to_string()];
+        };
+
+        // Notifications and requests from the backend always go through.
+        if !message["method"].is_null() {
+            return vec![line.to_string()];
+        }
+
+        let id = message["id"].to_string();
+        let is_subscribe = self.subscribe_id.as_ref() == Some(&id);
+        let extranonce =
+            is_subscribe.then(|| (message["result"][1].clone(), message["result"][2].clone()));
+
+        if !self.replayed_ids.remove(&id) {
+            if let Some(extranonce) = extranonce {
+                self.extranonce = Some(extranonce);
+            }
+            return vec![line.to_string()];
+        }
+
+        match (extranonce, &self.extranonce) {
+            (Some(new), Some(old)) if new != *old && !new.0.is_null() => {
+                let notification = json!({
+                    "id": null,
+                    "method": "mining.set_extranonce",
+                    "params": [new.0, new.1],
+                });
+                self.extranonce = Some(new);
+                vec![notification.to_string()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Buffers a line while the backend is away. Returns false once too much has been buffered.
+    fn buffer(&mut self, line: String) -> bool {
+        self.buffered.push(line);
+        self.buffered.len() <= MAX_BUFFERED_LINES
+    }
+
+    /// The lines to send on a new backend connection: the replayed session setup, followed by
+    /// anything buffered. Replays the miner already sent while the backend was away are skipped.
+    fn reconnected(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for (_, line) in &self.replay {
+            if self.buffered.contains(line) {
+                continue;
+            }
+
+            if let Ok(request) = serde_json::from_str::<Value>(line) {
+                self.replayed_ids.insert(request["id"].to_string());
+            }
+            lines.push(line.clone());
+        }
+
+        lines.extend(self.buffered.iter().cloned());
+        lines
+    }
+
+    /// Called once everything from `reconnected` has been sent.
+    fn flushed(&mut self) {
+        self.buffered.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    async fn accept(listener: &TcpListener) -> (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        (BufReader::new(reader).lines(), writer)
+    }
+
+    async fn next_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> String {
+        tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn proxy_headers() {
+        assert_eq!(
+            proxy_header(
+                "92.118.161.17:55867".parse().unwrap(),
+                "172.20.42.228:8080".parse().unwrap()
+            ),
+            "PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n"
+        );
+        assert_eq!(
+            proxy_header(
+                "[2001:db8::1]:55867".parse().unwrap(),
+                "172.20.42.228:8080".parse().unwrap()
+            ),
+            "PROXY TCP6 2001:db8::1 ::ffff:172.20.42.228 55867 8080\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_across_backend_reconnects() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            listen: relay.local_addr().unwrap(),
+            backend: backend.local_addr().unwrap().to_string(),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        let miner = TcpStream::connect(config.listen).await.unwrap();
+        let miner_address = miner.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = relay.accept().await.unwrap();
+            run(stream, &config).await
+        });
+        let (reader, mut miner_writer) = miner.into_split();
+        let mut miner_lines = BufReader::new(reader).lines();
+
+        let subscribe = r#"{"id":1,"method":"mining.subscribe","params":[]}"#;
+        miner_writer
+            .write_all(format!("{subscribe}\n").as_bytes())
+            .await
+            .unwrap();
+
+        let (mut lines, mut writer) = accept(&backend).await;
+        let header = next_line(&mut lines).await;
+        assert!(header.starts_with(&format!("PROXY TCP4 {} ", miner_address.ip())));
+        assert!(header.contains(&miner_address.port().to_string()));
+        assert_eq!(next_line(&mut lines).await, subscribe);
+        writer
+            .write_all(b"{\"id\":1,\"result\":[[],\"aaaa\",4],\"error\":null}\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            next_line(&mut miner_lines).await,
+            r#"{"id":1,"result":[[],"aaaa",4],"error":null}"#
+        );
+
+        // The backend goes away; the miner stays connected and its subscription is replayed.
+        drop((lines, writer));
+        let (mut lines, mut writer) = accept(&backend).await;
+        assert!(next_line(&mut lines).await.starts_with("PROXY TCP4 "));
+        assert_eq!(next_line(&mut lines).await, subscribe);
+        writer
+            .write_all(b"{\"id\":1,\"result\":[[],\"bbbb\",4],\"error\":null}\n")
+            .await
+            .unwrap();
+
+        let notification: Value = serde_json::from_str(&next_line(&mut miner_lines).await).unwrap();
+        assert_eq!(
+            notification,
+            json!({"id": null, "method": "mining.set_extranonce", "params": ["bbbb", 4]})
+        );
+
+        let submit = r#"{"id":2,"method":"mining.submit","params":[]}"#;
+        miner_writer
+            .write_all(format!("{submit}\n").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(next_line(&mut lines).await, submit);
+
+        writer.write_all(b"DISCONNECT\n").await.unwrap();
+        let mut rest = Vec::new();
+        let mut reader = miner_lines.into_inner();
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_close_the_miner() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            listen: relay.local_addr().unwrap(),
+            backend: backend.local_addr().unwrap().to_string(),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        let mut miner = TcpStream::connect(config.listen).await.unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = relay.accept().await.unwrap();
+            run(stream, &config).await
+        });
+
+        miner
+            .write_all(&vec![b'a'; MAX_LINE_LENGTH + 1])
+            .await
+            .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+    }
+}
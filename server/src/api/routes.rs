@@ -1,6 +1,7 @@
 use crate::{
     api::Context,
     ban_manager::{self, BanInfo},
+    ConnectionStats, DrainProgress,
 };
 use axum::{extract::State, Json};
 use hyper::StatusCode;
@@ -31,3 +32,51 @@ pub(crate) async fn remove_banned(
 ) -> Json<Option<BanInfo>> {
     Json(state.ban_manager.remove_ban(payload))
 }
+
+#[cfg(feature = "tls")]
+#[allow(clippy::unused_async)]
+pub(crate) async fn reload_tls(State(state): State<Context>) -> (StatusCode, String) {
+    let Some(tls) = state.tls else {
+        return (
+            StatusCode::NOT_FOUND,
+            crate::Error::TlsNotEnabled.to_string(),
+        );
+    };
+
+    match tls.reload() {
+        Ok(()) => (StatusCode::OK, String::new()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+#[cfg(unix)]
+#[allow(clippy::unused_async)]
+pub(crate) async fn handoff(State(state): State<Context>) -> (StatusCode, String) {
+    let Some(handoff) = state.handoff else {
+        return (
+            StatusCode::NOT_FOUND,
+            crate::Error::HandoffNotEnabled.to_string(),
+        );
+    };
+
+    handoff.trigger();
+
+    (StatusCode::ACCEPTED, String::new())
+}
+
+#[allow(clippy::unused_async)]
+pub(crate) async fn drain(State(state): State<Context>) -> StatusCode {
+    state.drain.trigger();
+
+    StatusCode::ACCEPTED
+}
+
+#[allow(clippy::unused_async)]
+pub(crate) async fn drain_progress(State(state): State<Context>) -> Json<DrainProgress> {
+    Json(state.drain.progress())
+}
+
+#[allow(clippy::unused_async)]
+pub(crate) async fn connections(State(state): State<Context>) -> Json<ConnectionStats> {
+    Json(state.connections.stats())
+}
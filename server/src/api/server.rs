@@ -23,8 +23,7 @@ pub struct Api {
 }
 
 impl Api {
-    pub async fn build(addr: SocketAddr, state: Context) -> Result<Self> {
-        let tcp = TcpListener::bind(addr).await?;
+    pub fn build(tcp: TcpListener, state: Context) -> Result<Self> {
         let info = tcp.local_addr()?;
 
         Ok(Self {
@@ -51,6 +50,16 @@ impl Api {
                     "/banned",
                     get(routes::get_banned).post(routes::remove_banned),
                 )
+                .route("/drain", get(routes::drain_progress).post(routes::drain))
+                .route("/connections", get(routes::connections));
+
+            #[cfg(feature = "tls")]
+            let app = app.route("/tls/reload", axum::routing::post(routes::reload_tls));
+
+            #[cfg(unix)]
+            let app = app.route("/handoff", axum::routing::post(routes::handoff));
+
+            let app = app
                 .layer(
                     //@todo set these more explicitly so that we lock down the sercurity of this bad
                     //boy.
@@ -4,4 +4,10 @@ use crate::{ban_manager, ReadyIndicator};
 pub struct Context {
     pub(crate) ban_manager: ban_manager::BanManager,
     pub(crate) ready_indicator: ReadyIndicator,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<crate::tls::Tls>,
+    #[cfg(unix)]
+    pub(crate) handoff: Option<crate::Handoff>,
+    pub(crate) drain: crate::Drain,
+    pub(crate) connections: std::sync::Arc<crate::session_list::ConnectionCounter>,
 }
@@ -0,0 +1,374 @@
+//! BTC Agent ex-messages.
+//!
+//! A BTC Agent multiplexes many workers over a single connection. Alongside the regular Stratum V1
+//! JSON it sends binary ex-messages, each starting with `EX_MAGIC_NUMBER`:
+//!
+//! ```text
+//! magic_number  u8   always 0x7F
+//! cmd           u8   message type
+//! length        u16  message length, including this header
+//! body          [u8]
+//! ```
+//!
+//! All integers are little endian. Ex-messages arrive as `Frame::ExMessage` and are routed by
+//! `ExMessage::method`, e.g. `server.add(btcagent::SUBMIT_SHARE, handler)`. Each message carries
+//! the agent's 16 bit session id for the worker it concerns, exposed to handlers as a `SessionID`
+//! through the `WorkerSession` extractor. Ex-messages are sent back with
+//! `Session::send_ex_message`.
+
+use crate::{Error, Result, SessionID, EX_MAGIC_NUMBER};
+use bytes::{Buf, BufMut};
+
+/// `magic_number: u8, cmd: u8, length: u16`.
+pub const EX_HEADER_SIZE: usize = 4;
+
+pub const REGISTER_WORKER: &str = "exMessageRegisterWorker";
+pub const SUBMIT_SHARE: &str = "exMessageSubmitShare";
+pub const UNREGISTER_WORKER: &str = "exMessageUnregisterWorker";
+pub const MINING_SET_DIFF: &str = "exMessageMiningSetDiff";
+
+const CMD_REGISTER_WORKER: u8 = 0x01;
+const CMD_SUBMIT_SHARE: u8 = 0x02;
+const CMD_SUBMIT_SHARE_WITH_TIME: u8 = 0x03;
+const CMD_UNREGISTER_WORKER: u8 = 0x04;
+const CMD_MINING_SET_DIFF: u8 = 0x05;
+const CMD_SUBMIT_SHARE_WITH_VERSION: u8 = 0x12;
+const CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION: u8 = 0x13;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ExMessage {
+    RegisterWorker(RegisterWorker),
+    SubmitShare(SubmitShare),
+    UnregisterWorker(UnregisterWorker),
+    MiningSetDiff(MiningSetDiff),
+}
+
+/// A new worker behind the agent, sent before any of its shares.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RegisterWorker {
+    pub session_id: u16,
+    pub client_agent: String,
+    pub worker_name: String,
+}
+
+/// A share from one of the agent's workers. `time` and `version_mask` are only sent when the
+/// worker rolled them, which selects one of the four submit commands.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SubmitShare {
+    pub job_id: u8,
+    pub session_id: u16,
+    pub extra_nonce2: u32,
+    pub nonce: u32,
+    pub time: Option<u32>,
+    pub version_mask: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnregisterWorker {
+    pub session_id: u16,
+}
+
+/// Sets the difficulty of several workers at once, to `2^diff_exp`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MiningSetDiff {
+    pub diff_exp: u8,
+    pub session_ids: Vec<u16>,
+}
+
+impl ExMessage {
+    /// The name the message is routed by. All submit variants share `SUBMIT_SHARE`.
+    #[must_use]
+    pub fn method(&self) -> &'static str {
+        match self {
+            ExMessage::RegisterWorker(_) => REGISTER_WORKER,
+            ExMessage::SubmitShare(_) => SUBMIT_SHARE,
+            ExMessage::UnregisterWorker(_) => UNREGISTER_WORKER,
+            ExMessage::MiningSetDiff(_) => MINING_SET_DIFF,
+        }
+    }
+
+    /// The worker the message concerns. `MiningSetDiff` addresses several workers and has none.
+    #[must_use]
+    pub fn session_id(&self) -> Option<SessionID> {
+        let session_id = match self {
+            ExMessage::RegisterWorker(msg) => msg.session_id,
+            ExMessage::SubmitShare(msg) => msg.session_id,
+            ExMessage::UnregisterWorker(msg) => msg.session_id,
+            ExMessage::MiningSetDiff(_) => return None,
+        };
+
+        Some(SessionID::from(u32::from(session_id)))
+    }
+
+    fn cmd(&self) -> u8 {
+        match self {
+            ExMessage::RegisterWorker(_) => CMD_REGISTER_WORKER,
+            ExMessage::SubmitShare(msg) => match (msg.time, msg.version_mask) {
+                (None, None) => CMD_SUBMIT_SHARE,
+                (Some(_), None) => CMD_SUBMIT_SHARE_WITH_TIME,
+                (None, Some(_)) => CMD_SUBMIT_SHARE_WITH_VERSION,
+                (Some(_), Some(_)) => CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION,
+            },
+            ExMessage::UnregisterWorker(_) => CMD_UNREGISTER_WORKER,
+            ExMessage::MiningSetDiff(_) => CMD_MINING_SET_DIFF,
+        }
+    }
+
+    /// The length of the whole message, read from a header.
+    pub(crate) fn length(header: [u8; EX_HEADER_SIZE]) -> Result<usize> {
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+        if header[0] != EX_MAGIC_NUMBER || length < EX_HEADER_SIZE {
+            return Err(Error::BrokenExHeader);
+        }
+
+        Ok(length)
+    }
+
+    /// Decodes a whole message, header included.
+    pub fn decode(message: &[u8]) -> Result<Self> {
+        if message.len() < EX_HEADER_SIZE {
+            return Err(Error::BrokenExHeader);
+        }
+
+        let (header, mut body) = message.split_at(EX_HEADER_SIZE);
+        let header: [u8; EX_HEADER_SIZE] = header.try_into().expect("split at the header size");
+
+        if Self::length(header)? != message.len() {
+            return Err(Error::BrokenExHeader);
+        }
+
+        let message = match header[1] {
+            CMD_REGISTER_WORKER => ExMessage::RegisterWorker(RegisterWorker {
+                session_id: read_u16(&mut body)?,
+                client_agent: read_string(&mut body)?,
+                worker_name: read_string(&mut body)?,
+            }),
+            cmd @ (CMD_SUBMIT_SHARE
+            | CMD_SUBMIT_SHARE_WITH_TIME
+            | CMD_SUBMIT_SHARE_WITH_VERSION
+            | CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION) => {
+                let job_id = read_u8(&mut body)?;
+                let session_id = read_u16(&mut body)?;
+                let extra_nonce2 = read_u32(&mut body)?;
+                let nonce = read_u32(&mut body)?;
+                let time = match cmd {
+                    CMD_SUBMIT_SHARE_WITH_TIME | CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION => {
+                        Some(read_u32(&mut body)?)
+                    }
+                    _ => None,
+                };
+                let version_mask = match cmd {
+                    CMD_SUBMIT_SHARE_WITH_VERSION | CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION => {
+                        Some(read_u32(&mut body)?)
+                    }
+                    _ => None,
+                };
+
+                ExMessage::SubmitShare(SubmitShare {
+                    job_id,
+                    session_id,
+                    extra_nonce2,
+                    nonce,
+                    time,
+                    version_mask,
+                })
+            }
+            CMD_UNREGISTER_WORKER => ExMessage::UnregisterWorker(UnregisterWorker {
+                session_id: read_u16(&mut body)?,
+            }),
+            CMD_MINING_SET_DIFF => {
+                let diff_exp = read_u8(&mut body)?;
+                let count = read_u16(&mut body)?;
+                let session_ids = (0..count)
+                    .map(|_| read_u16(&mut body))
+                    .collect::<Result<_>>()?;
+
+                ExMessage::MiningSetDiff(MiningSetDiff {
+                    diff_exp,
+                    session_ids,
+                })
+            }
+            cmd => return Err(Error::UnknownExMessage(cmd)),
+        };
+
+        if !body.is_empty() {
+            return Err(Error::InvalidExMessage("trailing bytes after body"));
+        }
+
+        Ok(message)
+    }
+
+    /// Encodes the whole message, header included.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![EX_MAGIC_NUMBER, self.cmd(), 0, 0];
+
+        match self {
+            ExMessage::RegisterWorker(msg) => {
+                buf.put_u16_le(msg.session_id);
+                put_string(&mut buf, &msg.client_agent)?;
+                put_string(&mut buf, &msg.worker_name)?;
+            }
+            ExMessage::SubmitShare(msg) => {
+                buf.put_u8(msg.job_id);
+                buf.put_u16_le(msg.session_id);
+                buf.put_u32_le(msg.extra_nonce2);
+                buf.put_u32_le(msg.nonce);
+                if let Some(time) = msg.time {
+                    buf.put_u32_le(time);
+                }
+                if let Some(version_mask) = msg.version_mask {
+                    buf.put_u32_le(version_mask);
+                }
+            }
+            ExMessage::UnregisterWorker(msg) => buf.put_u16_le(msg.session_id),
+            ExMessage::MiningSetDiff(msg) => {
+                let count = u16::try_from(msg.session_ids.len())
+                    .map_err(|_| Error::InvalidExMessage("too many session ids"))?;
+                buf.put_u8(msg.diff_exp);
+                buf.put_u16_le(count);
+                for session_id in &msg.session_ids {
+                    buf.put_u16_le(*session_id);
+                }
+            }
+        }
+
+        let length = u16::try_from(buf.len())
+            .map_err(|_| Error::InvalidExMessage("message longer than 65535 bytes"))?;
+        buf[2..EX_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+
+        Ok(buf)
+    }
+}
+
+fn read_u8(body: &mut &[u8]) -> Result<u8> {
+    if body.remaining() < 1 {
+        return Err(Error::InvalidExMessage("body ended early"));
+    }
+    Ok(body.get_u8())
+}
+
+fn read_u16(body: &mut &[u8]) -> Result<u16> {
+    if body.remaining() < 2 {
+        return Err(Error::InvalidExMessage("body ended early"));
+    }
+    Ok(body.get_u16_le())
+}
+
+fn read_u32(body: &mut &[u8]) -> Result<u32> {
+    if body.remaining() < 4 {
+        return Err(Error::InvalidExMessage("body ended early"));
+    }
+    Ok(body.get_u32_le())
+}
+
+/// Strings are null terminated.
+fn read_string(body: &mut &[u8]) -> Result<String> {
+    let Some(end) = body.iter().position(|b| *b == 0) else {
+        return Err(Error::InvalidExMessage("string is not null terminated"));
+    };
+
+    let string = std::str::from_utf8(&body[..end])
+        .map_err(|_| Error::InvalidExMessage("string is not valid UTF-8"))?
+        .to_string();
+    body.advance(end + 1);
+
+    Ok(string)
+}
+
+fn put_string(buf: &mut Vec<u8>, string: &str) -> Result<()> {
+    if string.as_bytes().contains(&0) {
+        return Err(Error::InvalidExMessage("string contains a null byte"));
+    }
+
+    buf.put_slice(string.as_bytes());
+    buf.put_u8(0);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_worker_round_trips() {
+        let message = ExMessage::RegisterWorker(RegisterWorker {
+            session_id: 0x0102,
+            client_agent: String::from("cgminer/4.10.0"),
+            worker_name: String::from("user.worker1"),
+        });
+
+        let encoded = message.encode().unwrap();
+        assert_eq!(&encoded[..6], &[0x7F, 0x01, 34, 0, 0x02, 0x01]);
+        assert_eq!(ExMessage::decode(&encoded).unwrap(), message);
+        assert_eq!(message.session_id(), Some(SessionID::from(0x0102)));
+    }
+
+    #[test]
+    fn submit_share_variants_pick_their_command() {
+        let share = SubmitShare {
+            job_id: 7,
+            session_id: 3,
+            extra_nonce2: 0xAABB_CCDD,
+            nonce: 0x1122_3344,
+            time: None,
+            version_mask: None,
+        };
+
+        for (time, version_mask, cmd, length) in [
+            (None, None, CMD_SUBMIT_SHARE, 15),
+            (Some(1), None, CMD_SUBMIT_SHARE_WITH_TIME, 19),
+            (None, Some(2), CMD_SUBMIT_SHARE_WITH_VERSION, 19),
+            (Some(1), Some(2), CMD_SUBMIT_SHARE_WITH_TIME_AND_VERSION, 23),
+        ] {
+            let message = ExMessage::SubmitShare(SubmitShare {
+                time,
+                version_mask,
+                ..share
+            });
+
+            let encoded = message.encode().unwrap();
+            assert_eq!(encoded[1], cmd);
+            assert_eq!(encoded.len(), length);
+            assert_eq!(ExMessage::decode(&encoded).unwrap(), message);
+            assert_eq!(message.method(), SUBMIT_SHARE);
+        }
+    }
+
+    #[test]
+    fn mining_set_diff_round_trips() {
+        let message = ExMessage::MiningSetDiff(MiningSetDiff {
+            diff_exp: 14,
+            session_ids: vec![1, 2, 3],
+        });
+
+        let encoded = message.encode().unwrap();
+        assert_eq!(encoded, [0x7F, 0x05, 13, 0, 14, 3, 0, 1, 0, 2, 0, 3, 0]);
+        assert_eq!(ExMessage::decode(&encoded).unwrap(), message);
+        assert_eq!(message.session_id(), None);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // Length shorter than the header.
+        assert!(matches!(
+            ExMessage::decode(&[0x7F, 0x04, 2, 0]),
+            Err(Error::BrokenExHeader)
+        ));
+        // Length disagrees with the message.
+        assert!(matches!(
+            ExMessage::decode(&[0x7F, 0x04, 7, 0, 1, 0]),
+            Err(Error::BrokenExHeader)
+        ));
+        // Body too short for an UnregisterWorker.
+        assert!(matches!(
+            ExMessage::decode(&[0x7F, 0x04, 5, 0, 1]),
+            Err(Error::InvalidExMessage(_))
+        ));
+        assert!(matches!(
+            ExMessage::decode(&[0x7F, 0x09, 4, 0]),
+            Err(Error::UnknownExMessage(0x09))
+        ));
+    }
+}
@@ -1,13 +1,21 @@
 use crate::{
-    config::{BanManagerConfig, ConnectionConfig, DifficultyConfig},
+    config::{
+        BanManagerConfig, ConnectionConfig, DifficultyConfig, ListenerConfig, MessageRateLimit,
+        OverflowPolicy,
+    },
+    connection::{Stream, Transport},
+    connection_limiter::ConnectionLimiter,
+    handoff::InheritedSockets,
     id_manager::IDManager,
     router::Router,
+    server::{ListenAddress, Listener},
     types::ReadyIndicator,
-    BanManager, Config, ConfigManager, Result, SessionList, StratumServer,
+    BanManager, Config, ConfigManager, Error, Result, SessionList, SocketOptions, StratumServer,
 };
 use extended_primitives::Buffer;
-use std::{marker::PhantomData, sync::Arc};
-use tokio::{net::TcpListener, task::JoinSet};
+use futures::StreamExt;
+use std::{collections::HashSet, marker::PhantomData, net::IpAddr, sync::Arc, time::Duration};
+use tokio::task::JoinSet;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::CancellationToken;
 
@@ -28,6 +36,23 @@ pub struct StratumServerBuilder<State, CState> {
     pub shutdown_message: Option<Buffer>,
     pub cancel_token: Option<CancellationToken>,
     pub ban_manager_enabled: bool,
+    pub ban_score_allowed: u64,
+    pub unknown_method_ban_score: u64,
+    pub frame_violation_ban: bool,
+    #[cfg(feature = "v2")]
+    pub v2_config: Option<crate::v2::V2Config>,
+    #[cfg(feature = "upstream")]
+    pub upstream_config: Option<crate::UpstreamConfig>,
+    #[cfg(feature = "tls")]
+    pub tls_config: Option<crate::TlsConfig>,
+    #[cfg(feature = "websocket")]
+    pub websocket_port: Option<u16>,
+    pub listeners: Vec<ListenerConfig>,
+    pub inherited_sockets: InheritedSockets,
+    #[cfg(unix)]
+    pub handoff_config: Option<crate::HandoffConfig>,
+    #[cfg(feature = "v1")]
+    pub drain_config: Option<crate::DrainConfig<CState>>,
 }
 
 impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync + 'static>
@@ -56,20 +81,32 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
                 target_time: 10,
                 variance_percent: 30.0,
             },
-            // #[cfg(feature = "upstream")]
-            // upstream_config: UpstreamConfig {
-            //     enabled: false,
-            //     url: String::from(""),
-            // },
             shutdown_message: None,
             cancel_token: None,
             ban_manager_enabled: false,
+            ban_score_allowed: 100,
+            unknown_method_ban_score: 10,
+            frame_violation_ban: false,
+            #[cfg(feature = "v2")]
+            v2_config: None,
+            #[cfg(feature = "upstream")]
+            upstream_config: None,
+            #[cfg(feature = "tls")]
+            tls_config: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            listeners: Vec::new(),
+            inherited_sockets: InheritedSockets::default(),
+            #[cfg(unix)]
+            handoff_config: None,
+            #[cfg(feature = "v1")]
+            drain_config: None,
         }
     }
 
     #[must_use]
     pub fn with_host(mut self, host: &str) -> Self {
-        self.host = host.to_owned();
+        host.clone_into(&mut self.host);
         self
     }
 
@@ -82,7 +119,7 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
     #[cfg(feature = "api")]
     #[must_use]
     pub fn with_api_host(mut self, host: &str) -> Self {
-        self.api_host = host.to_owned();
+        host.clone_into(&mut self.api_host);
         self
     }
 
@@ -93,18 +130,159 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
         self
     }
 
+    /// Closes new connections once `max_connections` sessions are open.
     #[must_use]
     pub fn with_max_connections(mut self, max_connections: usize) -> Self {
         self.connection_config.max_connections = Some(max_connections);
         self
     }
 
+    /// Closes new connections from an IP address that already has `max` open. Connections on a
+    /// listener with the PROXY protocol enabled aren't limited, as they all come from the load
+    /// balancer.
+    #[must_use]
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.connection_config.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Closes new connections from an IP address beyond `per_second` a second, after an initial
+    /// `burst`.
+    #[must_use]
+    pub fn with_connection_rate_per_ip(mut self, per_second: f64, burst: u32) -> Self {
+        self.connection_config.connection_rate = Some(per_second);
+        self.connection_config.connection_burst = burst;
+        self
+    }
+
+    /// Applies the per IP limits to whole subnets instead, e.g. `(24, 64)` counts every
+    /// connection from an IPv4 /24 or an IPv6 /64 together. Defaults to `(32, 128)`.
+    #[must_use]
+    pub fn with_connection_subnets(mut self, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        self.connection_config.ipv4_prefix = ipv4_prefix.min(32);
+        self.connection_config.ipv6_prefix = ipv6_prefix.min(128);
+        self
+    }
+
+    /// Bans an IP address once the per IP limits have closed `violations` of its connections.
+    /// Requires the ban manager.
+    #[must_use]
+    pub fn with_connection_limit_ban(mut self, violations: u32) -> Self {
+        self.connection_config.limit_violation_ban = Some(violations);
+        self
+    }
+
+    /// Limits how quickly each session may send messages. Messages over the limit are not handled,
+    /// see `MessageRateLimit`.
+    #[must_use]
+    pub fn with_message_rate_limit(mut self, limit: MessageRateLimit) -> Self {
+        self.connection_config.message_rate_limit = Some(limit);
+        self
+    }
+
+    /// Marks the ready indicator not ready once connections reach `not_ready_percent` of
+    /// `max_connections`, and ready again once they drop to `ready_percent`. Defaults to 95% and
+    /// 85%.
+    #[must_use]
+    pub fn with_readiness_watermarks(mut self, not_ready_percent: f64, ready_percent: f64) -> Self {
+        self.connection_config.not_ready_percent = not_ready_percent;
+        self.connection_config.ready_percent = ready_percent;
+        self
+    }
+
+    /// Sends miners that connect while the server is full a `client.reconnect` to `host:port`
+    /// before closing the connection.
+    #[cfg(feature = "v1")]
+    #[must_use]
+    pub fn with_overflow_reconnect(mut self, host: &str, port: u16) -> Self {
+        self.connection_config.overflow_reconnect = Some(crate::v1::Reconnect {
+            host: Some(host.to_string()),
+            port: Some(port),
+            wait_time: None,
+        });
+        self
+    }
+
+    /// The longest line a miner may send, in bytes, before it is disconnected. Defaults to 16 KiB.
+    #[must_use]
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.connection_config.max_frame_size = size;
+        self
+    }
+
+    /// How long a miner has to finish a line once its first byte arrived, before it is
+    /// disconnected. Defaults to 10 seconds.
+    #[must_use]
+    pub fn with_frame_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_config.frame_timeout = timeout;
+        self
+    }
+
+    /// Queues at most `capacity` messages for each miner, after which `overflow` decides what
+    /// happens. Defaults to 1024 messages and `OverflowPolicy::DropOldest`.
+    #[must_use]
+    pub fn with_send_queue(mut self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        self.connection_config.send_queue_capacity = capacity;
+        self.connection_config.send_queue_overflow = overflow;
+        self
+    }
+
+    /// Writes the messages queued for a miner together, in one write and flush, until they reach
+    /// `size` bytes. A size of 0 writes one message at a time. Defaults to 64 KiB.
+    #[must_use]
+    pub fn with_write_batch_size(mut self, size: usize) -> Self {
+        self.connection_config.write_batch_size = size;
+        self
+    }
+
+    /// Sets TCP keepalive, `TCP_NODELAY`, buffer sizes and the backlog on every TCP listener and
+    /// the streams it accepts.
+    #[must_use]
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.connection_config.socket_options = options;
+        self
+    }
+
+    /// Runs `acceptors` accept loops for each TCP listener, each on its own task and its own
+    /// socket bound to the same port with `SO_REUSEPORT`, so that the OS spreads new connections
+    /// between them. Building fails where `SO_REUSEPORT` isn't supported. Acceptors of a listener
+    /// that inherits fewer sockets than this share the inherited ones. Defaults to 1.
+    #[must_use]
+    pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+        self.connection_config.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Adds a listener with its own address and settings, next to the main `host:port` one.
+    /// Listener names must be unique, `build` fails otherwise.
+    #[must_use]
+    pub fn with_listener(mut self, config: ListenerConfig) -> Self {
+        self.listeners.push(config);
+        self
+    }
+
     #[must_use]
     pub fn with_proxy(mut self, value: bool) -> Self {
         self.connection_config.proxy_protocol = value;
         self
     }
 
+    /// Only accepts PROXY headers from these addresses, i.e. the load balancers. Connections from
+    /// anywhere else are dropped when the PROXY protocol is enabled.
+    #[must_use]
+    pub fn with_proxy_trusted_sources(mut self, sources: &[IpAddr]) -> Self {
+        self.connection_config.proxy_trusted_sources = sources.to_vec();
+        self
+    }
+
+    /// Drops proxied connections whose PROXY header says they were made to a port other than
+    /// `port` on the load balancer.
+    #[must_use]
+    pub fn with_proxy_destination_port(mut self, port: u16) -> Self {
+        self.connection_config.proxy_destination_port = Some(port);
+        self
+    }
+
     #[must_use]
     pub fn with_var_diff(mut self, value: bool) -> Self {
         self.var_diff_config.var_diff = value;
@@ -159,6 +337,14 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
         self
     }
 
+    /// How long the miners are given to disconnect on shutdown, or once a drain has sent out its
+    /// redirects, before they are closed. Defaults to 2 minutes.
+    #[must_use]
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_config.shutdown_timeout = timeout;
+        self
+    }
+
     #[must_use]
     pub fn with_cancel_token(mut self, token: CancellationToken) -> Self {
         self.cancel_token = Some(token);
@@ -171,26 +357,180 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
         self
     }
 
-    pub async fn build(self) -> Result<StratumServer<State, CState>> {
+    #[must_use]
+    pub fn with_ban_score_allowed(mut self, score: u64) -> Self {
+        self.ban_score_allowed = score;
+        self
+    }
+
+    #[must_use]
+    pub fn with_unknown_method_ban_score(mut self, score: u64) -> Self {
+        self.unknown_method_ban_score = score;
+        self
+    }
+
+    /// Bans miners whose connection is closed for sending a line that is too long, see
+    /// `with_max_frame_size`, or too slow, see `with_frame_timeout`.
+    #[must_use]
+    pub fn with_frame_violation_ban(mut self, value: bool) -> Self {
+        self.frame_violation_ban = value;
+        self
+    }
+
+    /// Enables a second listener that speaks Stratum V2 on `config.port`, on the same host.
+    #[cfg(feature = "v2")]
+    #[must_use]
+    pub fn with_v2(mut self, config: crate::v2::V2Config) -> Self {
+        self.v2_config = Some(config);
+        self
+    }
+
+    /// Connects the server to a parent pool once it starts. Routes for messages from the pool are
+    /// added with `StratumServer::add_upstream`.
+    #[cfg(feature = "upstream")]
+    #[must_use]
+    pub fn with_upstream(mut self, config: crate::UpstreamConfig) -> Self {
+        self.upstream_config = Some(config);
+        self
+    }
+
+    /// Enables a second listener that serves stratum+ssl on `config.port`, on the same host. The
+    /// certificate is reloaded from disk on `SIGHUP`, `StratumServer::reload_tls` or a `POST` to
+    /// the API's `/tls/reload`.
+    #[cfg(feature = "tls")]
+    #[must_use]
+    pub fn with_tls(mut self, config: crate::TlsConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    /// Enables a second listener that serves Stratum over WebSocket on `port`, on the same host.
+    /// Each text message is one Stratum frame.
+    #[cfg(feature = "websocket")]
+    #[must_use]
+    pub fn with_websocket(mut self, port: u16) -> Self {
+        self.websocket_port = Some(port);
+        self
+    }
+
+    /// Takes over `sockets`, taken with `InheritedSockets::take` from those passed under
+    /// `LISTEN_FDS` by systemd socket activation or by a server handing off, for the listeners
+    /// bound to the same addresses. Listeners without one bind a new socket as usual.
+    #[must_use]
+    pub fn with_inherited_sockets(mut self, sockets: InheritedSockets) -> Self {
+        self.inherited_sockets = sockets;
+        self
+    }
+
+    /// Hands the listening sockets off to `config`'s process on `SIGUSR2`, `Handoff::trigger` or a
+    /// `POST` to the API's `/handoff`, then stops accepting and drains the sessions like on
+    /// shutdown. The replacement takes them over with `with_inherited_sockets`. Whatever
+    /// supervises the server must not stop the replacement when this process exits.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn with_handoff(mut self, config: crate::HandoffConfig) -> Self {
+        self.handoff_config = Some(config);
+        self
+    }
+
+    /// Redirects each session as `config` says when the server drains, on `Drain::trigger` or a
+    /// `POST` to the API's `/drain`. Without it, a drain sends the shutdown message instead.
+    #[cfg(feature = "v1")]
+    #[must_use]
+    pub fn with_drain(mut self, config: crate::DrainConfig<CState>) -> Self {
+        self.drain_config = Some(config);
+        self
+    }
+
+    /// Binds the main listener, then those of the enabled transports and of `with_listener`.
+    async fn bind_all(
+        &self,
+        config_manager: &ConfigManager,
+        inherited: &mut InheritedSockets,
+    ) -> Result<Vec<Listener>> {
+        #[allow(unused_mut)]
+        let mut transports = vec![(self.port, Transport::Stratum)];
+
+        #[cfg(feature = "v2")]
+        if let Some(config) = &self.v2_config {
+            transports.push((config.port, Transport::V2));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls_config {
+            transports.push((config.port, Transport::Tls));
+        }
+
+        #[cfg(feature = "websocket")]
+        if let Some(port) = self.websocket_port {
+            transports.push((port, Transport::WebSocket));
+        }
+
+        let mut listeners = Vec::new();
+
+        for (port, transport) in transports {
+            listeners.extend(bind(&self.host, port, transport, config_manager, inherited).await?);
+        }
+
+        for listener in &self.listeners {
+            listeners.extend(bind_listener(listener, config_manager, inherited).await?);
+        }
+
+        Ok(listeners)
+    }
+
+    /// The configuration the listeners start out with.
+    #[cfg_attr(
+        not(any(feature = "v2", feature = "tls")),
+        allow(clippy::unnecessary_wraps)
+    )]
+    fn config(&self) -> Result<Config> {
         let ban_manager_config = BanManagerConfig {
             enabled: self.ban_manager_enabled,
+            ban_score_allowed: self.ban_score_allowed,
+            unknown_method_ban_score: self.unknown_method_ban_score,
+            frame_violation_ban: self.frame_violation_ban,
             ..Default::default()
         };
 
-        let config = Config {
-            connection: self.connection_config,
-            difficulty: self.var_diff_config,
+        Ok(Config {
+            connection: self.connection_config.clone(),
+            difficulty: self.var_diff_config.clone(),
             bans: ban_manager_config,
-        };
+            #[cfg(feature = "v2")]
+            v2_keys: self
+                .v2_config
+                .as_ref()
+                .map(crate::v2::noise::NoiseKeys::new)
+                .transpose()?,
+            #[cfg(feature = "tls")]
+            tls: self
+                .tls_config
+                .clone()
+                .map(crate::tls::Tls::new)
+                .transpose()?,
+            listener: None,
+        })
+    }
 
-        let config_manager = ConfigManager::new(config);
+    pub async fn build(mut self) -> Result<StratumServer<State, CState>> {
+        let mut names = HashSet::new();
+        if let Some(listener) = self
+            .listeners
+            .iter()
+            .find(|l| !names.insert(l.name.as_str()))
+        {
+            return Err(Error::DuplicateListener(listener.name.clone()));
+        }
 
-        let listener = TcpListener::bind(format!("{}:{}", self.host, self.port)).await?;
+        let config_manager = ConfigManager::new(self.config()?);
 
-        //This will fail if unable to find a local port.
-        let listen_address = listener.local_addr()?;
-        let listener = TcpListenerStream::new(listener);
-        let session_list = SessionList::new(config_manager.clone());
+        let mut inherited = std::mem::take(&mut self.inherited_sockets);
+
+        let listeners = self.bind_all(&config_manager, &mut inherited).await?;
+
+        let session_list = SessionList::new(config_manager.clone())
+            .with_ready_indicator(self.ready_indicator.create_new());
 
         let cancel_token = if let Some(cancel_token) = self.cancel_token {
             cancel_token
@@ -200,34 +540,256 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
 
         let ban_manager = BanManager::new(config_manager.clone(), cancel_token.child_token());
 
+        #[cfg(feature = "api")]
+        let api_listener = {
+            let api_address: std::net::SocketAddr =
+                format!("{}:{}", self.api_host, self.api_port).parse()?;
+
+            match inherited.take_tcp(&self.api_host, self.api_port).await? {
+                Some(listener) => listener,
+                None => tokio::net::TcpListener::bind(api_address).await?,
+            }
+        };
+
+        #[cfg(feature = "api")]
+        let api_cancel_token = cancel_token.child_token();
+
+        #[cfg(unix)]
+        let handoff = self
+            .handoff_config
+            .map(|config| {
+                handoff(
+                    config,
+                    &listeners,
+                    #[cfg(feature = "api")]
+                    &api_listener,
+                )
+            })
+            .transpose()?;
+
+        let drain = crate::Drain::default();
+
         #[cfg(feature = "api")]
         let api = {
             let state = crate::api::Context {
                 ban_manager: ban_manager.clone(),
                 ready_indicator: self.ready_indicator.create_new(),
+                #[cfg(feature = "tls")]
+                tls: config_manager.tls().cloned(),
+                #[cfg(unix)]
+                handoff: handoff.clone(),
+                drain: drain.clone(),
+                connections: session_list.connection_counter(),
             };
 
-            let api_address = format!("{}:{}", self.api_host, self.api_port).parse()?;
-
-            crate::api::Api::build(api_address, state).await?
+            crate::api::Api::build(api_listener, state)?
         };
 
         Ok(StratumServer {
             id: self.server_id,
-            listener,
-            listen_address,
+            listeners,
             session_list,
-            config_manager,
             state: self.state,
+            connection_limiter: ConnectionLimiter::new(ban_manager.clone()),
             ban_manager,
             router: Arc::new(Router::new()),
+            #[cfg(feature = "upstream")]
+            upstream: self
+                .upstream_config
+                .map(|config| crate::upstream::Upstream::new(config, CancellationToken::new())),
+            #[cfg(feature = "upstream")]
+            upstream_router: Arc::new(Router::new()),
             session_id_manager: IDManager::new(self.server_id),
             cancel_token,
+            stop_accepting: CancellationToken::new(),
+            #[cfg(unix)]
+            handoff,
+            drain,
+            #[cfg(feature = "v1")]
+            drain_config: self.drain_config,
             global_thread_list: JoinSet::new(),
             ready_indicator: self.ready_indicator,
             shutdown_message: self.shutdown_message,
             #[cfg(feature = "api")]
             api,
+            #[cfg(feature = "api")]
+            api_cancel_token,
         })
     }
 }
+
+/// Binds a TCP listener for each of the configured acceptors, all on the port the first is given.
+async fn bind(
+    host: &str,
+    port: u16,
+    transport: Transport,
+    config_manager: &ConfigManager,
+    inherited: &mut InheritedSockets,
+) -> Result<Vec<Listener>> {
+    let config = config_manager.connection_config();
+    let reuse_port = config.acceptors > 1;
+
+    let mut listeners = Vec::with_capacity(config.acceptors);
+    let mut port = port;
+    // The last inherited socket, which acceptors left without one accept from too. Binding next
+    // to it fails, as it was not bound with SO_REUSEPORT.
+    let mut shared: Option<std::net::TcpListener> = None;
+
+    for _ in 0..config.acceptors {
+        let listener = if let Some(listener) = inherited.take_tcp(host, port).await? {
+            let listener = listener.into_std()?;
+            shared = Some(listener.try_clone()?);
+            tokio::net::TcpListener::from_std(listener)?
+        } else if let Some(shared) = &shared {
+            tokio::net::TcpListener::from_std(shared.try_clone()?)?
+        } else {
+            config.socket_options.bind(host, port, reuse_port).await?
+        };
+        //This will fail if unable to find a local port.
+        let address = listener.local_addr()?;
+        port = address.port();
+
+        let options = config.socket_options.clone();
+        listeners.push(Listener {
+            address: ListenAddress::Tcp(address),
+            transport,
+            config_manager: config_manager.clone(),
+            #[cfg(unix)]
+            socket: std::os::fd::AsFd::as_fd(&listener).try_clone_to_owned()?,
+            incoming: Some(
+                TcpListenerStream::new(listener)
+                    .map(move |stream| {
+                        let stream = stream?;
+                        options.apply(&stream)?;
+                        let address = stream.peer_addr()?;
+                        Ok((Box::new(stream) as Box<dyn Stream>, address))
+                    })
+                    .boxed(),
+            ),
+        });
+    }
+
+    Ok(listeners)
+}
+
+/// Binds a listener added with `with_listener`, with its settings applied over `config_manager`'s.
+async fn bind_listener(
+    config: &ListenerConfig,
+    config_manager: &ConfigManager,
+    inherited: &mut InheritedSockets,
+) -> Result<Vec<Listener>> {
+    let config_manager = config_manager.for_listener(config);
+
+    #[cfg(unix)]
+    if let Some(path) = &config.path {
+        return Ok(vec![bind_unix(path, &config_manager, inherited)?]);
+    }
+
+    bind(
+        &config.host,
+        config.port,
+        Transport::Stratum,
+        &config_manager,
+        inherited,
+    )
+    .await
+}
+
+#[cfg(unix)]
+fn bind_unix(
+    path: &std::path::Path,
+    config_manager: &ConfigManager,
+    inherited: &mut InheritedSockets,
+) -> Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let listener = if let Some(listener) = inherited.take_unix(path)? {
+        listener
+    } else {
+        // A socket left behind by a previous run would make binding fail, anything else is kept.
+        if std::fs::symlink_metadata(path)
+            .map_or(false, |metadata| metadata.file_type().is_socket())
+        {
+            std::fs::remove_file(path)?;
+        }
+
+        tokio::net::UnixListener::bind(path)?
+    };
+
+    Ok(Listener {
+        address: ListenAddress::Unix(path.to_path_buf()),
+        transport: Transport::Stratum,
+        config_manager: config_manager.clone(),
+        socket: std::os::fd::AsFd::as_fd(&listener).try_clone_to_owned()?,
+        incoming: Some(
+            tokio_stream::wrappers::UnixListenerStream::new(listener)
+                .map(|stream| {
+                    Ok((
+                        Box::new(stream?) as Box<dyn Stream>,
+                        crate::connection::unix_peer_address(),
+                    ))
+                })
+                .boxed(),
+        ),
+    })
+}
+
+/// A `Handoff` to `config`'s process, holding on to every listening socket.
+#[cfg(unix)]
+fn handoff(
+    config: crate::HandoffConfig,
+    listeners: &[Listener],
+    #[cfg(feature = "api")] api: &tokio::net::TcpListener,
+) -> Result<crate::Handoff> {
+    #[allow(unused_mut)]
+    let mut sockets = listeners
+        .iter()
+        .map(|listener| Ok((listener.name(), listener.socket.try_clone()?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    #[cfg(feature = "api")]
+    sockets.push((
+        "api".to_string(),
+        std::os::fd::AsFd::as_fd(api).try_clone_to_owned()?,
+    ));
+
+    Ok(crate::Handoff::new(config, sockets))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn acceptors_share_an_inherited_socket() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut inherited = InheritedSockets {
+            tcp: vec![(address, socket)],
+            unix: Vec::new(),
+        };
+
+        let mut config = Config::default();
+        config.connection.acceptors = 2;
+        let config_manager = ConfigManager::new(config);
+
+        let mut listeners = bind(
+            "127.0.0.1",
+            address.port(),
+            Transport::Stratum,
+            &config_manager,
+            &mut inherited,
+        )
+        .await
+        .unwrap();
+        assert_eq!(listeners.len(), 2);
+
+        for listener in &mut listeners {
+            let client = tokio::net::TcpStream::connect(address).await.unwrap();
+            let incoming = listener.incoming.as_mut().unwrap();
+            let (_, peer) = incoming.next().await.unwrap().unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{frame::Request, session::SendInformation, Error, Frame};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::trace;
+
+/// Frames Stratum V1 traffic: newline delimited JSON, along with BTC Agent ex-messages which can
+/// be interleaved with it. Requests are deserialized straight from the read buffer, and responses
+/// are written into the write buffer, so neither allocates an intermediate `String`.
+pub(crate) struct StratumCodec {
+    /// The longest line accepted, newline excluded.
+    max_frame_size: usize,
+    /// How far into the buffer has already been searched for a newline.
+    next_index: usize,
+}
+
+impl StratumCodec {
+    pub(crate) fn new(max_frame_size: usize) -> Self {
+        StratumCodec {
+            max_frame_size,
+            next_index: 0,
+        }
+    }
+
+    /// The longest frame accepted, also applied to SV2 frames which are read without the codec.
+    #[cfg(feature = "v2")]
+    pub(crate) fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Takes the next ex-message off `src`, once all of it has arrived.
+    #[cfg(feature = "btcagent")]
+    fn decode_ex_message(src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        use crate::btcagent::{ExMessage, EX_HEADER_SIZE};
+
+        if src.len() < EX_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0; EX_HEADER_SIZE];
+        header.copy_from_slice(&src[..EX_HEADER_SIZE]);
+        let length = ExMessage::length(header)?;
+
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let message = ExMessage::decode(&src.split_to(length))?;
+
+        trace!("Received ExMessage: {message:?}");
+
+        Ok(Some(Frame::ExMessage(message)))
+    }
+}
+
+impl Decoder for StratumCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        loop {
+            #[cfg(feature = "btcagent")]
+            if self.next_index == 0 && src.first() == Some(&crate::EX_MAGIC_NUMBER) {
+                return Self::decode_ex_message(src);
+            }
+
+            let Some(offset) = src[self.next_index..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+            else {
+                if src.len() > self.max_frame_size {
+                    return Err(Error::FrameTooLarge(self.max_frame_size));
+                }
+
+                self.next_index = src.len();
+                return Ok(None);
+            };
+
+            let newline = self.next_index + offset;
+            self.next_index = 0;
+
+            if newline > self.max_frame_size {
+                return Err(Error::FrameTooLarge(self.max_frame_size));
+            }
+
+            let line = src.split_to(newline + 1);
+            let line = &line[..newline];
+
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            //@todo when revamping logging, put connection id into here.
+            trace!("Received Message: {}", String::from_utf8_lossy(line).trim());
+
+            //@todo I think we may want to log the buf here if it fails on trace - Right now we
+            //can't see what these connections are sending.
+            let request: Request = serde_json::from_slice(line)?;
+
+            return Ok(Some(Frame::V1(request)));
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+
+        // A last line without a newline is still a request.
+        if src.iter().all(u8::is_ascii_whitespace) {
+            src.clear();
+            return Ok(None);
+        }
+
+        self.next_index = 0;
+        let request: Request = serde_json::from_slice(&src.split())?;
+
+        Ok(Some(Frame::V1(request)))
+    }
+}
+
+impl Encoder<SendInformation> for StratumCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: SendInformation, dst: &mut BytesMut) -> Result<(), Error> {
+        match item {
+            SendInformation::Json(json) => dst.put_slice(&json),
+            SendInformation::Text(text) => dst.put_slice(text.as_bytes()),
+            SendInformation::Raw(buffer) => dst.put_slice(&buffer),
+            #[cfg(feature = "v2")]
+            SendInformation::V2(_) => trace!("Dropping SV2 frame on a V1 connection"),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut StratumCodec, src: &mut BytesMut) -> Vec<String> {
+        let mut methods = Vec::new();
+        while let Some(frame) = codec.decode(src).unwrap() {
+            methods.push(frame.method().to_string());
+        }
+        methods
+    }
+
+    #[test]
+    fn decodes_lines_across_reads() {
+        let mut codec = StratumCodec::new(1024);
+        let mut src = BytesMut::from(&b"{\"id\":1,\"method\":\"mining.subscribe\",\"par"[..]);
+
+        assert!(decode_all(&mut codec, &mut src).is_empty());
+
+        src.extend_from_slice(
+            b"ams\":[]}\r\n\n  \n{\"id\":2,\"method\":\"mining.authorize\",\"params\":[]}\n{",
+        );
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            ["mining.subscribe", "mining.authorize"]
+        );
+        assert_eq!(&src[..], b"{");
+    }
+
+    #[test]
+    fn decodes_a_last_line_without_newline() {
+        let mut codec = StratumCodec::new(1024);
+        let mut src =
+            BytesMut::from(&b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}"[..]);
+
+        let frame = codec.decode_eof(&mut src).unwrap().unwrap();
+        assert_eq!(frame.method(), "mining.subscribe");
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_lines() {
+        let mut codec = StratumCodec::new(8);
+
+        let mut src = BytesMut::from(&b"123456789"[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::FrameTooLarge(8))
+        ));
+
+        let mut src = BytesMut::from(&b"123456789\n"[..]);
+        assert!(matches!(
+            StratumCodec::new(8).decode(&mut src),
+            Err(Error::FrameTooLarge(8))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        let mut src = BytesMut::from(&b"not json\n"[..]);
+
+        assert!(matches!(
+            StratumCodec::new(1024).decode(&mut src),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn encodes_into_the_buffer() {
+        let mut codec = StratumCodec::new(1024);
+        let mut dst = BytesMut::new();
+
+        codec
+            .encode(
+                SendInformation::json(&serde_json::json!({"id": 1})).unwrap(),
+                &mut dst,
+            )
+            .unwrap();
+        codec
+            .encode(SendInformation::Text("text".to_string()), &mut dst)
+            .unwrap();
+
+        assert_eq!(&dst[..], b"{\"id\":1}\ntext");
+    }
+}
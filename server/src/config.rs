@@ -1,4 +1,4 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
 //@todo wrap this in a Mutex<Arc. Then when a new config is refreshed, everyone can just get it
 //from a clone.
@@ -18,6 +18,12 @@ impl ConfigManager {
         self.config.clone()
     }
 
+    /// The manager of a listener added with `with_listener`, its settings applied over this one's.
+    /// Listener managers are always derived from the server wide manager, never built on their own.
+    pub(crate) fn for_listener(&self, listener: &ListenerConfig) -> Self {
+        Self::new(listener.apply(&self.config))
+    }
+
     // Helpers
     pub(crate) fn proxy_protocol(&self) -> bool {
         self.config.connection.proxy_protocol
@@ -38,20 +44,60 @@ impl ConfigManager {
     pub(crate) fn ban_manager_enabled(&self) -> bool {
         self.config.bans.enabled
     }
+
+    pub(crate) fn ban_score_allowed(&self) -> u64 {
+        self.config.bans.ban_score_allowed
+    }
+
+    pub(crate) fn unknown_method_ban_score(&self) -> u64 {
+        self.config.bans.unknown_method_ban_score
+    }
+
+    pub(crate) fn frame_violation_ban(&self) -> bool {
+        self.config.bans.frame_violation_ban
+    }
+
+    pub(crate) fn listener(&self) -> Option<&str> {
+        self.config.listener.as_deref()
+    }
+
+    #[cfg(feature = "v2")]
+    pub(crate) fn v2_keys(&self) -> Option<&crate::v2::noise::NoiseKeys> {
+        self.config.v2_keys.as_ref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls(&self) -> Option<&crate::tls::Tls> {
+        self.config.tls.as_ref()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Config {
+    /// The name of the extra listener these settings are for, `None` for the main listener.
+    pub(crate) listener: Option<String>,
     pub(crate) connection: ConnectionConfig,
     pub(crate) difficulty: DifficultyConfig,
     pub(crate) bans: BanManagerConfig,
+    /// The keys used for the Noise handshake on the SV2 listener, if it is enabled.
+    #[cfg(feature = "v2")]
+    pub(crate) v2_keys: Option<crate::v2::noise::NoiseKeys>,
+    /// The certificates for the TLS listener, if it is enabled.
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<crate::tls::Tls>,
 }
 
 #[derive(Clone, Debug)]
 pub struct BanManagerConfig {
     pub(crate) enabled: bool,
     pub(crate) default_ban_duration: Duration,
-    pub(crate) _ban_score_allowed: u64,
+    /// Ban Score Allowed is the score a Session can accumulate from errors before it is banned.
+    pub(crate) ban_score_allowed: u64,
+    /// Unknown Method Ban Score is added to a Session's ban score each time it calls a method
+    /// that has no route, unless a custom fallback has been set.
+    pub(crate) unknown_method_ban_score: u64,
+    /// Whether to ban miners that send a line that is too long or too slow to arrive.
+    pub(crate) frame_violation_ban: bool,
     pub(crate) _whitelisted_ips: Vec<IpAddr>,
     pub(crate) _perma_ban_starting_list: Vec<IpAddr>,
 }
@@ -61,7 +107,9 @@ impl Default for BanManagerConfig {
         BanManagerConfig {
             enabled: false,
             default_ban_duration: Duration::from_secs(3600),
-            _ban_score_allowed: 100,
+            ban_score_allowed: 100,
+            unknown_method_ban_score: 10,
+            frame_violation_ban: false,
             _whitelisted_ips: Vec::new(),
             _perma_ban_starting_list: Vec::new(),
         }
@@ -71,7 +119,35 @@ impl Default for BanManagerConfig {
 #[derive(Clone, Debug)]
 pub struct ConnectionConfig {
     pub(crate) proxy_protocol: bool,
+    /// The load balancers allowed to send a PROXY header. Any peer may if this is empty.
+    pub(crate) proxy_trusted_sources: Vec<IpAddr>,
+    /// The port miners are expected to have connected to on the load balancer, if it is checked.
+    pub(crate) proxy_destination_port: Option<u16>,
     pub(crate) max_connections: Option<usize>,
+    /// Percent of `max_connections` at which the server marks itself not ready. In full-interval
+    /// format e.g. 95.0 = 95%.
+    pub(crate) not_ready_percent: f64,
+    /// Percent of `max_connections` at which a server marked not ready becomes ready again.
+    pub(crate) ready_percent: f64,
+    /// Where miners are sent with a `client.reconnect` when the server is full.
+    #[cfg(feature = "v1")]
+    pub(crate) overflow_reconnect: Option<crate::v1::Reconnect>,
+    pub(crate) socket_options: crate::SocketOptions,
+    /// How many accept loops each TCP listener runs, on sockets sharing its port through
+    /// `SO_REUSEPORT` when there is more than one.
+    pub(crate) acceptors: usize,
+    /// The most connections open at once from one IP address, or one subnet, see `ipv4_prefix`.
+    pub(crate) max_connections_per_ip: Option<usize>,
+    /// New connections allowed per second from one IP address or subnet.
+    pub(crate) connection_rate: Option<f64>,
+    /// New connections allowed in a burst above `connection_rate`.
+    pub(crate) connection_burst: u32,
+    /// The prefix lengths addresses are grouped by for the per IP limits, e.g. 24 to limit each
+    /// IPv4 /24 as one address.
+    pub(crate) ipv4_prefix: u8,
+    pub(crate) ipv6_prefix: u8,
+    /// Connections refused by the per IP limits after which the address is banned.
+    pub(crate) limit_violation_ban: Option<u32>,
     /// Active Timeout is how long with no activity before we disconnect a miner.
     pub(crate) active_timeout: u64,
     /// Initial Timeout is how long we wait for an initial message from a miner. Once they are
@@ -83,17 +159,131 @@ pub struct ConnectionConfig {
     /// Invalid Percent is the percent of shares that are rejected or stale before we ban a miner.
     /// In full-interval format e.g. 50.0 = 50%.
     pub(crate) invalid_percent: f64,
+    /// The longest line a miner may send, in bytes.
+    pub(crate) max_frame_size: usize,
+    /// How long a miner has to finish a line once it has started sending it.
+    pub(crate) frame_timeout: Duration,
+    /// The most messages queued for a miner before the overflow policy applies.
+    pub(crate) send_queue_capacity: usize,
+    pub(crate) send_queue_overflow: OverflowPolicy,
+    /// The most bytes of queued messages written to a miner at once.
+    pub(crate) write_batch_size: usize,
+    /// How long the sessions are given to disconnect on shutdown, or once a drain has sent out
+    /// its redirects, before they are closed.
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) message_rate_limit: Option<MessageRateLimit>,
+}
+
+/// What to do when a miner's send queue is full, usually because the miner stopped reading.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest queued `mining.notify`, as a newer job supersedes it. If none is queued,
+    /// a new notify is dropped and anything else disconnects the miner.
+    #[default]
+    DropOldest,
+    /// Disconnects the miner.
+    Disconnect,
+    /// Refuses the message with `Error::SendQueueFull`, so the sender can wait on
+    /// `Session::writable` and try again.
+    Backpressure,
+}
+
+/// How quickly one session may send messages, added with
+/// `StratumServerBuilder::with_message_rate_limit`. Each budget is a token bucket that allows
+/// `burst` messages at once and refills at `per_second`.
+#[derive(Clone, Debug, Default)]
+pub struct MessageRateLimit {
+    pub(crate) session: Option<Budget>,
+    pub(crate) methods: HashMap<String, Budget>,
+    pub(crate) action: RateLimitAction,
+    pub(crate) ban_violations: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Budget {
+    pub(crate) per_second: f64,
+    pub(crate) burst: u32,
+}
+
+impl MessageRateLimit {
+    /// No limits, until they are added with `with_rate` and `with_method`.
+    #[must_use]
+    pub fn new() -> Self {
+        MessageRateLimit::default()
+    }
+
+    /// Limits every message the session sends.
+    #[must_use]
+    pub fn with_rate(mut self, per_second: f64, burst: u32) -> Self {
+        self.session = Some(Budget { per_second, burst });
+        self
+    }
+
+    /// Limits `method` on its own, on top of the session wide limit, e.g. a tighter budget for
+    /// `mining.subscribe` than for `mining.submit`.
+    #[must_use]
+    pub fn with_method(mut self, method: &str, per_second: f64, burst: u32) -> Self {
+        self.methods
+            .insert(method.to_string(), Budget { per_second, burst });
+        self
+    }
+
+    /// What happens to a message over the limit. Defaults to `RateLimitAction::Respond`.
+    #[must_use]
+    pub fn with_action(mut self, action: RateLimitAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Bans the session once `violations` of its messages were over the limit without it slowing
+    /// down, i.e. without its budgets filling back up in between.
+    #[must_use]
+    pub fn with_ban(mut self, violations: u32) -> Self {
+        self.ban_violations = Some(violations);
+        self
+    }
+}
+
+/// What to do with a message over the `MessageRateLimit`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Answers a request with an error instead of handling it. Notifications are dropped.
+    #[default]
+    Respond,
+    /// Drops the message without an answer.
+    Drop,
 }
 
 impl Default for ConnectionConfig {
     fn default() -> Self {
         ConnectionConfig {
             proxy_protocol: false,
+            proxy_trusted_sources: Vec::new(),
+            proxy_destination_port: None,
             max_connections: None,
+            not_ready_percent: 95.0,
+            ready_percent: 85.0,
+            #[cfg(feature = "v1")]
+            overflow_reconnect: None,
+            socket_options: crate::SocketOptions::default(),
+            acceptors: 1,
+            max_connections_per_ip: None,
+            connection_rate: None,
+            connection_burst: 1,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            limit_violation_ban: None,
             active_timeout: 600,
             inital_timeout: 15,
             check_threshold: 500,
             invalid_percent: 50.0,
+            max_frame_size: 16 * 1024,
+            frame_timeout: Duration::from_secs(10),
+            send_queue_capacity: 1024,
+            send_queue_overflow: OverflowPolicy::DropOldest,
+            write_batch_size: 64 * 1024,
+            shutdown_timeout: Duration::from_secs(120),
+            message_rate_limit: None,
         }
     }
 }
@@ -128,15 +318,190 @@ impl Default for DifficultyConfig {
     }
 }
 
+/// An extra listener with its own settings, added with `StratumServerBuilder::with_listener`.
+/// Anything not set here is taken from the server wide settings.
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub(crate) name: String,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) initial_difficulty: Option<u64>,
+    pub(crate) minimum_difficulty: Option<u64>,
+    pub(crate) maximum_difficulty: Option<u64>,
+    pub(crate) proxy_protocol: Option<bool>,
+    pub(crate) active_timeout: Option<u64>,
+    pub(crate) initial_timeout: Option<u64>,
+    /// Set for a Unix socket listener, in place of `host` and `port`.
+    #[cfg(unix)]
+    pub(crate) path: Option<std::path::PathBuf>,
+}
+
+impl ListenerConfig {
+    /// Listens on `host:port`. Sessions accepted here report `name` from `Session::listener`.
+    #[must_use]
+    pub fn new(name: &str, host: &str, port: u16) -> Self {
+        ListenerConfig {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+            initial_difficulty: None,
+            minimum_difficulty: None,
+            maximum_difficulty: None,
+            proxy_protocol: None,
+            active_timeout: None,
+            initial_timeout: None,
+            #[cfg(unix)]
+            path: None,
+        }
+    }
+
+    /// Listens on a Unix socket at `path`, replacing a stale socket left there. Peers on a Unix
+    /// socket have no address, so each session is given a unique one in `100::/64`, unless the
+    /// PROXY protocol is enabled with `with_proxy` and the header carries the miner's.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn unix(name: &str, path: impl Into<std::path::PathBuf>) -> Self {
+        ListenerConfig {
+            path: Some(path.into()),
+            ..ListenerConfig::new(name, "", 0)
+        }
+    }
+
+    #[must_use]
+    pub fn with_initial_difficulty(mut self, difficulty: u64) -> Self {
+        self.initial_difficulty = Some(difficulty);
+        self
+    }
+
+    #[must_use]
+    pub fn with_minimum_difficulty(mut self, difficulty: u64) -> Self {
+        self.minimum_difficulty = Some(difficulty);
+        self
+    }
+
+    #[must_use]
+    pub fn with_maximum_difficulty(mut self, difficulty: u64) -> Self {
+        self.maximum_difficulty = Some(difficulty);
+        self
+    }
+
+    #[must_use]
+    pub fn with_proxy(mut self, value: bool) -> Self {
+        self.proxy_protocol = Some(value);
+        self
+    }
+
+    /// Seconds without activity before a miner is disconnected.
+    #[must_use]
+    pub fn with_active_timeout(mut self, timeout: u64) -> Self {
+        self.active_timeout = Some(timeout);
+        self
+    }
+
+    /// Seconds to wait for a miner's first message.
+    #[must_use]
+    pub fn with_initial_timeout(mut self, timeout: u64) -> Self {
+        self.initial_timeout = Some(timeout);
+        self
+    }
+
+    /// The server wide `config` with this listener's settings applied on top.
+    pub(crate) fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.listener = Some(self.name.clone());
+
+        let difficulty = &mut config.difficulty;
+        difficulty.initial_difficulty = self
+            .initial_difficulty
+            .unwrap_or(difficulty.initial_difficulty);
+        difficulty.minimum_difficulty = self
+            .minimum_difficulty
+            .unwrap_or(difficulty.minimum_difficulty);
+        difficulty.maximum_difficulty = self
+            .maximum_difficulty
+            .unwrap_or(difficulty.maximum_difficulty);
+
+        let connection = &mut config.connection;
+        connection.proxy_protocol = self.proxy_protocol.unwrap_or(connection.proxy_protocol);
+        connection.active_timeout = self.active_timeout.unwrap_or(connection.active_timeout);
+        connection.inital_timeout = self.initial_timeout.unwrap_or(connection.inital_timeout);
+
+        config
+    }
+}
+
 impl DifficultyConfig {
     pub(crate) fn initial_retarget_time(&self, now: u128) -> u128 {
         now - ((self.retarget_time as u128 * 1000) / 2)
     }
 }
 
-// #[cfg(feature = "upstream")]
-// #[derive(Clone, Debug, Default)]
-// pub struct UpstreamConfig {
-//     pub(crate) enabled: bool,
-//     pub(crate) url: String,
-// }
+/// Settings for the connection to a parent pool.
+#[cfg(feature = "upstream")]
+#[derive(Clone, Debug)]
+pub struct UpstreamConfig {
+    /// The pool's `host:port`.
+    pub(crate) url: String,
+    /// The delay before the first reconnect attempt. It doubles after every failed attempt.
+    pub(crate) min_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    /// How long to wait for the pool to answer a request.
+    pub(crate) request_timeout: Duration,
+    /// Requests sent ahead of anything else each time the connection is established.
+    pub(crate) login: Vec<(String, serde_json::Value)>,
+}
+
+#[cfg(feature = "upstream")]
+impl UpstreamConfig {
+    #[must_use]
+    pub fn new(url: &str) -> Self {
+        UpstreamConfig {
+            url: url.to_string(),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(30),
+            login: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+
+    #[must_use]
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Sends `method` to the pool every time the connection is established, before anything
+    /// queued, e.g. `mining.subscribe` followed by `mining.authorize`, so that a reconnected pool
+    /// knows who it is talking to. Requests are sent in the order they were added.
+    #[must_use]
+    pub fn with_login(mut self, method: &str, params: serde_json::Value) -> Self {
+        self.login.push((method.to_string(), params));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListenerConfig;
+
+    #[test]
+    fn listener_managers_are_derived_from_the_shared_one() {
+        let mut config = crate::Config::default();
+        config.difficulty.minimum_difficulty = 64;
+        let manager = crate::ConfigManager::new(config);
+
+        let listener = manager.for_listener(
+            &ListenerConfig::new("high", "127.0.0.1", 0).with_initial_difficulty(1 << 20),
+        );
+        assert_eq!(listener.listener(), Some("high"));
+        assert_eq!(listener.difficulty_config().initial_difficulty, 1 << 20);
+        assert_eq!(listener.difficulty_config().minimum_difficulty, 64);
+    }
+}
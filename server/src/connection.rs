@@ -1,128 +1,263 @@
-use crate::{frame::Request, session::SendInformation, types::ConnectionID, Error, Frame, Result};
+#[cfg(feature = "v2")]
+use crate::v2::noise::{self, Decryptor, Encryptor, NoiseKeys};
+use crate::{
+    codec::StratumCodec,
+    proxy_protocol::{self, ProxyHeader},
+    send_queue::SendQueue,
+    session::SendInformation,
+    types::ConnectionID,
+    ConfigManager, Error, Frame, Result,
+};
 use bytes::BytesMut;
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+#[cfg(feature = "v2")]
+use tokio::io::AsyncBufReadExt;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
     task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+use tokio_util::{
+    codec::{Decoder, Encoder},
+    sync::CancellationToken,
 };
-use tokio_util::sync::CancellationToken;
 use tracing::trace;
 
+/// Any stream that miners can connect over, e.g. a `TcpStream` or a TLS stream wrapping one.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// Peers on a Unix socket are given addresses in `100::/64`, the IPv6 discard prefix, which no
+/// real miner can connect from.
+const UNIX_PEER_PREFIX: u128 = 0x0100 << 112;
+
+/// The initial size of the read and write buffers of each connection.
+const BUFFER_SIZE: usize = 4 * 1024;
+
+/// How long the messages still queued when a connection is cancelled may take to write.
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The stream stays buffered until `init`, so anything read ahead while parsing the PROXY header or
+// a handshake is not lost when it is split.
+pub(crate) type Reader = BufReader<ReadHalf<BufReader<Box<dyn Stream>>>>;
+type Writer = WriteHalf<BufReader<Box<dyn Stream>>>;
+
+/// The listener a connection was accepted on, which decides the handshake it goes through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Stratum,
+    #[cfg(feature = "v2")]
+    V2,
+    #[cfg(feature = "tls")]
+    Tls,
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// A unique address for a peer on a Unix socket, so it can be keyed like any other session.
+#[cfg(unix)]
+pub(crate) fn unix_peer_address() -> SocketAddr {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+    let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    SocketAddr::new(Ipv6Addr::from(UNIX_PEER_PREFIX | u128::from(id)).into(), 0)
+}
+
+/// Whether `address` was given to a peer on a Unix socket by `unix_peer_address`.
+pub(crate) fn is_unix_peer(address: SocketAddr) -> bool {
+    match address.ip() {
+        IpAddr::V6(ip) => u128::from(ip) >> 64 == UNIX_PEER_PREFIX >> 64,
+        IpAddr::V4(_) => false,
+    }
+}
+
 //@todo convert this to return ConnectionWriter to be used in Sessions.
 
-#[derive(Debug)]
 pub struct Connection {
     _id: ConnectionID,
-    writer: OwnedWriteHalf,
-    reader: BufReader<OwnedReadHalf>,
+    stream: BufReader<Box<dyn Stream>>,
     cancel_token: CancellationToken,
-
-    //@todo implement this, but move to it Reader.
-    // The buffer for reading frames.
-    _buffer: BytesMut,
     pub(crate) address: SocketAddr,
+    /// Set once an SV2 handshake has completed.
+    #[cfg(feature = "v2")]
+    noise: Option<(Encryptor, Decryptor)>,
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Connection {
+    /// `address` is the peer's address, as the stream itself may not know it.
     pub(crate) fn new(
         id: ConnectionID,
-        socket: TcpStream,
+        stream: Box<dyn Stream>,
+        address: SocketAddr,
         cancel_token: CancellationToken,
-    ) -> Result<Self> {
-        let addr = socket.peer_addr()?;
-
-        let (read_half, write_half) = socket.into_split();
-
-        Ok(Connection {
+    ) -> Self {
+        Connection {
             _id: id,
-            address: addr,
-            writer: write_half,
-            reader: BufReader::new(read_half),
+            address,
+            stream: BufReader::new(stream),
             cancel_token,
-            _buffer: BytesMut::new(),
-        })
+            #[cfg(feature = "v2")]
+            noise: None,
+        }
     }
 
     pub(crate) fn init(
         self,
-    ) -> (
-        ConnectionReader,
-        UnboundedSender<SendInformation>,
-        JoinHandle<Result<()>>,
-    ) {
+        config_manager: &ConfigManager,
+    ) -> (ConnectionReader, SendQueue, JoinHandle<Result<()>>) {
+        #[cfg(feature = "v2")]
+        let (encryptor, decryptor) = match self.noise {
+            Some((encryptor, decryptor)) => (Some(encryptor), Some(decryptor)),
+            None => (None, None),
+        };
+
+        let (read_half, writer) = tokio::io::split(self.stream);
+
+        let config = config_manager.connection_config();
+
         let reader = ConnectionReader {
-            reader: self.reader,
+            reader: BufReader::new(read_half),
+            buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            codec: StratumCodec::new(config.max_frame_size),
+            frame_timeout: config.frame_timeout,
+            #[cfg(feature = "v2")]
+            decryptor,
         };
 
-        let (tx, rx): (
-            UnboundedSender<SendInformation>,
-            UnboundedReceiver<SendInformation>,
-        ) = unbounded_channel();
+        let tx = SendQueue::new(config.send_queue_capacity, config.send_queue_overflow);
+        let queue = tx.clone();
 
         //@todo let's review this thoroughly.
         //@todo I think that we need to return this thread so it can be joined.
         let cancel_token = self.cancel_token.clone();
-        let handle =
-            tokio::spawn(async move { write_message(cancel_token, rx, self.writer).await });
+        let codec = StratumCodec::new(config.max_frame_size);
+        let max_batch = config.write_batch_size;
+        let handle = tokio::spawn(async move {
+            let result = write_message(
+                cancel_token,
+                &queue,
+                codec,
+                writer,
+                max_batch,
+                #[cfg(feature = "v2")]
+                encryptor,
+            )
+            .await;
+
+            // Sessions can't queue any more once nothing is writing them out.
+            queue.close();
+
+            result
+        });
 
         (reader, tx, handle)
     }
 
-    //@todo this prob panics in multiple scenarios, so this really needs to be cleaned up.
-    //@todo polish this up and support both v1 and v2.
-    pub(crate) async fn proxy_protocol(&mut self) -> Result<SocketAddr> {
-        let mut buf = String::new();
+    /// Reads the PROXY protocol header that the connection starts with.
+    pub(crate) async fn proxy_protocol(&mut self) -> Result<ProxyHeader> {
+        Ok(proxy_protocol::read_header(&mut self.stream).await?)
+    }
+
+    /// Completes the SV2 Noise handshake, after which all frames are encrypted.
+    #[cfg(feature = "v2")]
+    pub(crate) async fn v2_handshake(&mut self, keys: &NoiseKeys) -> Result<()> {
+        let mut initiator_message = [0; noise::INITIATOR_MESSAGE_SIZE];
+        self.stream.read_exact(&mut initiator_message).await?;
+
+        let (reply, encryptor, decryptor) = noise::respond(keys, initiator_message)?;
+        self.stream.write_all(&reply).await?;
+        self.stream.flush().await?;
+
+        self.noise = Some((encryptor, decryptor));
+
+        Ok(())
+    }
+
+    /// Completes the TLS handshake, after which the connection is read and written through it.
+    #[cfg(feature = "tls")]
+    pub(crate) async fn start_tls(self, acceptor: &tokio_rustls::TlsAcceptor) -> Result<Self> {
+        let stream: Box<dyn Stream> = Box::new(acceptor.accept(self.stream).await?);
 
-        //@todo This may be the memory leak here.
-        // Check for Proxy Protocol.
-        self.reader.read_line(&mut buf).await?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            ..self
+        })
+    }
 
-        //Buf will be of the format "PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n"
-        //Trim the \r\n off
-        let buf = buf.trim();
-        //Might want to not be ascii whitespace and just normal here.
-        // let pieces = buf.split_ascii_whitespace();
+    /// Completes the WebSocket upgrade, after which each message is read as one frame. Messages,
+    /// fragmented or not, are held to `max_frame_size` as tungstenite buffers them before any
+    /// reach the codec.
+    #[cfg(feature = "websocket")]
+    pub(crate) async fn start_websocket(self, max_frame_size: usize) -> Result<Self> {
+        let config = tokio_tungstenite::tungstenite::protocol::WebSocketConfig::default()
+            .max_message_size(Some(max_frame_size))
+            .max_frame_size(Some(max_frame_size));
 
-        let pieces: Vec<&str> = buf.split(' ').collect();
+        let socket = tokio_tungstenite::accept_async_with_config(self.stream, Some(config))
+            .await
+            .map_err(Box::new)?;
+        let stream: Box<dyn Stream> = Box::new(crate::websocket::WebSocket::new(socket));
 
-        Ok(format!("{}:{}", pieces[2], pieces[4]).parse()?)
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            ..self
+        })
     }
 }
 
+/// Writes out queued messages until the connection is cancelled. Messages queued together, e.g.
+/// a difficulty followed by a job, are written and flushed at once, up to `max_batch` bytes.
 async fn write_message(
     cancel_token: CancellationToken,
-    mut rx: UnboundedReceiver<SendInformation>,
-    mut writer: OwnedWriteHalf,
+    queue: &SendQueue,
+    mut codec: StratumCodec,
+    mut writer: Writer,
+    max_batch: usize,
+    #[cfg(feature = "v2")] mut encryptor: Option<Encryptor>,
 ) -> Result<()> {
+    let mut buffer = BytesMut::with_capacity(BUFFER_SIZE);
+
     //@todo move cancel_token.cancelled() into the select loop oh wait it is, weird I guess this
     //works just review again?
     while !cancel_token.is_cancelled() {
         tokio::select! {
-            Some(msg) = rx.recv() => {
-                match msg {
-                    SendInformation::Json(json) => {
-                        writer.write_all(json.as_bytes()).await?;
-                        writer.write_all(b"\n").await?;
-                    }
-                    SendInformation::Text(text) => {
-                        writer.write_all(text.as_bytes()).await?;
-                    }
-                    SendInformation::Raw(buffer) => {
-                        writer.write_all(&buffer).await?;
-                    }
+            Some(msg) = queue.pop() => {
+                encode_batch(
+                    msg,
+                    queue,
+                    &mut codec,
+                    &mut buffer,
+                    max_batch,
+                    #[cfg(feature = "v2")]
+                    encryptor.as_mut(),
+                )?;
+
+                if buffer.is_empty() {
+                    continue;
                 }
+
+                writer.write_all(&buffer).await?;
+                buffer.clear();
+
+                // A no-op for plain TCP, but TLS streams hold on to writes until they are flushed.
+                writer.flush().await?;
             }
             () = cancel_token.cancelled() => {
                 //@todo reword this
                 trace!("write loop hit cancellation token.");
-
-                //Return Err
-                    return Ok(());
+                break;
             }
             else => {
             //Return Err
@@ -131,45 +266,298 @@ async fn write_message(
         }
     }
 
+    // Whatever was queued before the connection was cancelled still goes out, e.g. the error
+    // response that got the miner disconnected, but no more than one batch and only briefly.
+    queue.close();
+
+    if let Some(msg) = queue.try_pop() {
+        encode_batch(
+            msg,
+            queue,
+            &mut codec,
+            &mut buffer,
+            max_batch,
+            #[cfg(feature = "v2")]
+            encryptor.as_mut(),
+        )?;
+
+        let flush = async {
+            writer.write_all(&buffer).await?;
+            writer.flush().await
+        };
+
+        if let Ok(result) = tokio::time::timeout(FINAL_FLUSH_TIMEOUT, flush).await {
+            result?;
+        } else {
+            trace!("Timed out writing the last queued messages");
+        }
+    }
+
     Ok(())
 }
 
-//@todo inhouse a buffer here, but for now this works I suppose.
 pub struct ConnectionReader {
-    reader: BufReader<OwnedReadHalf>,
+    reader: Reader,
+    /// Bytes read but not yet decoded, reused across frames.
+    buffer: BytesMut,
+    codec: StratumCodec,
+    /// How long a frame may take to arrive once its first byte has.
+    frame_timeout: Duration,
+    #[cfg(feature = "v2")]
+    decryptor: Option<Decryptor>,
 }
 
 impl ConnectionReader {
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
-        loop {
-            let mut buf = String::new();
-            if 0 == self.reader.read_line(&mut buf).await? {
-                if self.reader.buffer().is_empty() {
-                    return Ok(None);
-                }
-                return Err(Error::PeerResetConnection);
-            }
+        #[cfg(feature = "v2")]
+        if let Some(decryptor) = &mut self.decryptor {
+            return read_v2_frame(
+                &mut self.reader,
+                decryptor,
+                self.codec.max_frame_size(),
+                self.frame_timeout,
+            )
+            .await;
+        }
 
-            if !buf.is_empty() {
-                //@smells
-                buf = buf.trim().to_owned();
+        let mut deadline = None;
 
-                //@todo when revamping logging, put connection id into here.
-                trace!("Received Message: {}", &buf);
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buffer)? {
+                return Ok(Some(frame));
+            }
 
-                if buf.is_empty() {
-                    continue;
-                }
+            // A frame has to arrive within `frame_timeout` of its first byte, so that a peer can't
+            // hold the connection open by trickling bytes.
+            if !self.buffer.is_empty() {
+                deadline.get_or_insert_with(|| Instant::now() + self.frame_timeout);
+            }
 
-                //@todo I think we may want to log the buf here if it fails on trace - Right now we
-                //can't see what these connections are sending.
-                let msg: Request = serde_json::from_str(&buf)?;
+            let read = match deadline {
+                Some(deadline) => timeout_at(deadline, self.reader.read_buf(&mut self.buffer))
+                    .await
+                    .map_err(|_| Error::FrameTimeout)??,
+                None => self.reader.read_buf(&mut self.buffer).await?,
+            };
 
-                return Ok(Some(Frame::V1(msg)));
+            if read == 0 {
+                return self.codec.decode_eof(&mut self.buffer);
             }
         }
     }
 }
 
+#[cfg(feature = "v2")]
+pub(crate) async fn read_v2_frame(
+    reader: &mut Reader,
+    decryptor: &mut Decryptor,
+    max_frame_size: usize,
+    frame_timeout: Duration,
+) -> Result<Option<Frame>> {
+    if reader.fill_buf().await?.is_empty() {
+        return Ok(None);
+    }
+
+    // As with V1, the frame has to arrive within `frame_timeout` of its first byte.
+    let deadline = Instant::now() + frame_timeout;
+
+    let mut header = [0; noise::ENCRYPTED_HEADER_SIZE];
+    timeout_at(deadline, reader.read_exact(&mut header))
+        .await
+        .map_err(|_| Error::FrameTimeout)??;
+    let header = decryptor.decrypt_header(header)?;
+
+    // The length comes from the peer, so it is checked before anything is allocated for it.
+    if header.msg_length as usize > max_frame_size {
+        return Err(Error::FrameTooLarge(max_frame_size));
+    }
+
+    let mut payload = vec![0; noise::encrypted_len(header.msg_length as usize)];
+    timeout_at(deadline, reader.read_exact(&mut payload))
+        .await
+        .map_err(|_| Error::FrameTimeout)??;
+    let payload = decryptor.decrypt_payload(&payload)?;
+
+    trace!("Received SV2 frame: {header:?}");
+
+    Ok(Some(Frame::V2(crate::v2::Frame {
+        header,
+        payload: payload.into(),
+    })))
+}
+
 //@todo RUN tests here with a bunch of different scenarios, including bad messages, not using proxy
 //protocol, etc.
+
+/// Appends `first` and whatever else is queued to `buffer`, until it holds `max_batch` bytes.
+fn encode_batch(
+    first: SendInformation,
+    queue: &SendQueue,
+    codec: &mut StratumCodec,
+    buffer: &mut BytesMut,
+    max_batch: usize,
+    #[cfg(feature = "v2")] mut encryptor: Option<&mut Encryptor>,
+) -> Result<()> {
+    let mut next = Some(first);
+    while let Some(msg) = next {
+        encode(
+            msg,
+            codec,
+            buffer,
+            #[cfg(feature = "v2")]
+            encryptor.as_deref_mut(),
+        )?;
+
+        next = if buffer.len() < max_batch {
+            queue.try_pop()
+        } else {
+            None
+        };
+    }
+
+    Ok(())
+}
+
+/// Appends `msg` to `buffer`, encrypted on an SV2 connection.
+fn encode(
+    msg: SendInformation,
+    codec: &mut StratumCodec,
+    buffer: &mut BytesMut,
+    #[cfg(feature = "v2")] encryptor: Option<&mut Encryptor>,
+) -> Result<()> {
+    #[cfg(feature = "v2")]
+    if let Some(encryptor) = encryptor {
+        if let SendInformation::V2(frame) = msg {
+            buffer.extend_from_slice(&encryptor.encrypt_frame(&frame)?);
+        } else {
+            trace!("Dropping V1 message on an SV2 connection");
+        }
+
+        return Ok(());
+    }
+
+    codec.encode(msg, buffer)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, ConnectionConfig};
+    use bytes::Bytes;
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+    use tokio::io::{DuplexStream, ReadBuf};
+
+    /// Counts the writes made to the stream it wraps.
+    struct CountWrites {
+        inner: DuplexStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountWrites {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountWrites {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Queues `messages` before the write loop gets to run, then returns what the miner received
+    /// and how many writes it took.
+    async fn write_burst(write_batch_size: usize, messages: &[&str]) -> (String, usize) {
+        let (stream, mut miner) = tokio::io::duplex(64 * 1024);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let stream = CountWrites {
+            inner: stream,
+            writes: writes.clone(),
+        };
+
+        let config_manager = ConfigManager::new(Config {
+            connection: ConnectionConfig {
+                write_batch_size,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let cancel_token = CancellationToken::new();
+        let connection = Connection::new(
+            ConnectionID::new(),
+            Box::new(stream),
+            "127.0.0.1:3333".parse().unwrap(),
+            cancel_token.clone(),
+        );
+        let (_reader, queue, handle) = connection.init(&config_manager);
+
+        for message in messages {
+            queue
+                .push(
+                    SendInformation::Json(Bytes::from(format!("{message}\n"))),
+                    false,
+                )
+                .unwrap();
+        }
+
+        let expected: usize = messages.iter().map(|message| message.len() + 1).sum();
+        let mut received = vec![0; expected];
+        miner.read_exact(&mut received).await.unwrap();
+
+        cancel_token.cancel();
+        handle.await.unwrap().unwrap();
+
+        (
+            String::from_utf8(received).unwrap(),
+            writes.load(Ordering::Relaxed),
+        )
+    }
+
+    #[tokio::test]
+    async fn writes_queued_messages_at_once() {
+        let (received, writes) =
+            write_burst(1024, &["{\"id\":1}", "{\"id\":2}", "{\"id\":3}"]).await;
+
+        assert_eq!(received, "{\"id\":1}\n{\"id\":2}\n{\"id\":3}\n");
+        assert_eq!(writes, 1);
+    }
+
+    #[tokio::test]
+    async fn batches_up_to_the_batch_size() {
+        let (received, writes) = write_burst(10, &["{\"id\":1}", "{\"id\":2}", "{\"id\":3}"]).await;
+        assert_eq!(received, "{\"id\":1}\n{\"id\":2}\n{\"id\":3}\n");
+        assert_eq!(writes, 2);
+
+        let (_, writes) = write_burst(0, &["{\"id\":1}", "{\"id\":2}", "{\"id\":3}"]).await;
+        assert_eq!(writes, 3);
+    }
+}
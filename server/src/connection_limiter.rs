@@ -0,0 +1,283 @@
+use crate::{config::ConnectionConfig, BanManager, ConfigManager, Error, Result};
+use dashmap::DashMap;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::time::Instant;
+use tracing::warn;
+
+/// How many connections are checked between sweeps of the addresses that went idle.
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Limits how many connections one IP address, or subnet, may have open at once and how quickly
+/// it may open new ones. Connections are checked as they are accepted, before anything is read
+/// from them, or once their PROXY header is read when behind a load balancer, and addresses that
+/// keep hitting the limits are handed to the `BanManager`.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    entries: DashMap<IpAddr, Entry>,
+    ban_manager: BanManager,
+    checked: AtomicU64,
+}
+
+struct Entry {
+    connections: usize,
+    /// New connections left in the token bucket, as of `refilled`.
+    tokens: f64,
+    refilled: Instant,
+    /// Connections refused since the address was last banned or idle.
+    violations: u32,
+}
+
+impl Entry {
+    fn new(config: &ConnectionConfig, now: Instant) -> Self {
+        Entry {
+            connections: 0,
+            tokens: config.connection_burst as f64,
+            refilled: now,
+            violations: 0,
+        }
+    }
+
+    fn refill(&mut self, config: &ConnectionConfig, now: Instant) {
+        if let Some(rate) = config.connection_rate {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(config.connection_burst as f64);
+        }
+        self.refilled = now;
+    }
+
+    /// Whether forgetting the entry changes nothing, i.e. it holds no connections and its bucket
+    /// has filled back up.
+    fn is_idle(&mut self, config: &ConnectionConfig, now: Instant) -> bool {
+        self.refill(config, now);
+        self.connections == 0
+            && (config.connection_rate.is_none() || self.tokens >= config.connection_burst as f64)
+    }
+}
+
+/// Holds one of an address's connection slots, until it is dropped when the connection ends.
+pub(crate) struct ConnectionPermit {
+    inner: Arc<Inner>,
+    key: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.inner.entries.get_mut(&self.key) {
+            entry.connections = entry.connections.saturating_sub(1);
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(ban_manager: BanManager) -> Self {
+        ConnectionLimiter {
+            inner: Arc::new(Inner {
+                entries: DashMap::new(),
+                ban_manager,
+                checked: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Takes a connection slot for `ip`, or returns why it may not connect. `None` is returned
+    /// when no per IP limit is configured.
+    pub(crate) fn acquire(
+        &self,
+        ip: IpAddr,
+        config_manager: &ConfigManager,
+    ) -> Result<Option<ConnectionPermit>> {
+        let config = config_manager.connection_config();
+        if config.max_connections_per_ip.is_none() && config.connection_rate.is_none() {
+            return Ok(None);
+        }
+
+        if config_manager.ban_manager_enabled() {
+            self.inner.ban_manager.check_banned(ip)?;
+        }
+
+        let now = Instant::now();
+        if self.inner.checked.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == 0 {
+            self.inner
+                .entries
+                .retain(|_, entry| !entry.is_idle(config, now));
+        }
+
+        let key = subnet(ip, config);
+        let mut entry = self
+            .inner
+            .entries
+            .entry(key)
+            .or_insert_with(|| Entry::new(config, now));
+        entry.refill(config, now);
+
+        let refused = if config
+            .max_connections_per_ip
+            .map_or(false, |max| entry.connections >= max)
+        {
+            Error::TooManyConnections(key)
+        } else if config.connection_rate.is_some() && entry.tokens < 1.0 {
+            Error::ConnectionRateLimited(key)
+        } else {
+            if config.connection_rate.is_some() {
+                entry.tokens -= 1.0;
+            }
+            entry.connections += 1;
+
+            return Ok(Some(ConnectionPermit {
+                inner: self.inner.clone(),
+                key,
+            }));
+        };
+
+        entry.violations += 1;
+        let ban = config
+            .limit_violation_ban
+            .map_or(false, |violations| entry.violations >= violations)
+            && config_manager.ban_manager_enabled();
+        if ban {
+            entry.violations = 0;
+        }
+        drop(entry);
+
+        if ban {
+            warn!(
+                ip = ip.to_string(),
+                "Banning for repeatedly exceeding the connection limits"
+            );
+            self.inner.ban_manager.add_ban(ip);
+        }
+
+        Err(refused)
+    }
+}
+
+/// The network `ip` is in, for the prefix lengths configured.
+fn subnet(ip: IpAddr, config: &ConnectionConfig) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(config.ipv4_prefix))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(config.ipv6_prefix))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    fn limiter(connection: ConnectionConfig) -> (ConnectionLimiter, ConfigManager) {
+        let mut config = Config {
+            connection,
+            ..Default::default()
+        };
+        config.bans.enabled = true;
+        let config_manager = ConfigManager::new(config);
+        let ban_manager = BanManager::new(config_manager.clone(), CancellationToken::new());
+
+        (ConnectionLimiter::new(ban_manager), config_manager)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_connections_per_subnet() {
+        let (limiter, config_manager) = limiter(ConnectionConfig {
+            max_connections_per_ip: Some(2),
+            ipv4_prefix: 24,
+            ..Default::default()
+        });
+
+        let first = limiter.acquire(ip("10.0.0.1"), &config_manager).unwrap();
+        let _second = limiter.acquire(ip("10.0.0.2"), &config_manager).unwrap();
+        assert!(matches!(
+            limiter.acquire(ip("10.0.0.3"), &config_manager),
+            Err(Error::TooManyConnections(subnet)) if subnet == ip("10.0.0.0")
+        ));
+        assert!(limiter.acquire(ip("10.0.1.1"), &config_manager).is_ok());
+
+        drop(first);
+        assert!(limiter.acquire(ip("10.0.0.3"), &config_manager).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_the_connection_rate() {
+        let (limiter, config_manager) = limiter(ConnectionConfig {
+            connection_rate: Some(2.0),
+            connection_burst: 2,
+            ..Default::default()
+        });
+
+        for _ in 0..2 {
+            limiter.acquire(ip("10.0.0.1"), &config_manager).unwrap();
+        }
+        assert!(matches!(
+            limiter.acquire(ip("10.0.0.1"), &config_manager),
+            Err(Error::ConnectionRateLimited(_))
+        ));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.acquire(ip("10.0.0.1"), &config_manager).is_ok());
+        assert!(limiter.acquire(ip("10.0.0.1"), &config_manager).is_err());
+    }
+
+    #[tokio::test]
+    async fn bans_repeat_offenders() {
+        let (limiter, config_manager) = limiter(ConnectionConfig {
+            max_connections_per_ip: Some(1),
+            limit_violation_ban: Some(2),
+            ..Default::default()
+        });
+
+        let _permit = limiter.acquire(ip("10.0.0.1"), &config_manager).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                limiter.acquire(ip("10.0.0.1"), &config_manager),
+                Err(Error::TooManyConnections(_))
+            ));
+        }
+
+        assert!(matches!(
+            limiter.acquire(ip("10.0.0.1"), &config_manager),
+            Err(Error::ConnectionBanned(_))
+        ));
+    }
+
+    #[test]
+    fn groups_addresses_by_prefix() {
+        let config = ConnectionConfig {
+            ipv4_prefix: 16,
+            ipv6_prefix: 64,
+            ..Default::default()
+        };
+
+        assert_eq!(subnet(ip("10.1.2.3"), &config), ip("10.1.0.0"));
+        assert_eq!(subnet(ip("2001:db8::1"), &config), ip("2001:db8::"));
+        assert_eq!(
+            subnet(ip("10.1.2.3"), &ConnectionConfig::default()),
+            ip("10.1.2.3")
+        );
+    }
+}
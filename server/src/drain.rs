@@ -0,0 +1,266 @@
+use crate::SessionList;
+#[cfg(feature = "v1")]
+use crate::{v1::Reconnect, Session};
+use serde::Serialize;
+#[cfg(feature = "v1")]
+use std::net::IpAddr;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+use tracing::{info, warn};
+
+/// How often progress is logged while the redirects go out.
+#[cfg(feature = "v1")]
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "v1")]
+type Redirect<CState> = dyn Fn(&Session<CState>) -> Option<Reconnect> + Send + Sync;
+
+/// Where each miner is sent when the server drains, see `StratumServerBuilder::with_drain`.
+#[cfg(feature = "v1")]
+pub struct DrainConfig<CState> {
+    pub(crate) redirect: Arc<Redirect<CState>>,
+    pub(crate) window: Duration,
+}
+
+#[cfg(feature = "v1")]
+impl<CState> Clone for DrainConfig<CState> {
+    fn clone(&self) -> Self {
+        DrainConfig {
+            redirect: self.redirect.clone(),
+            window: self.window,
+        }
+    }
+}
+
+#[cfg(feature = "v1")]
+impl<CState: Clone + 'static> DrainConfig<CState> {
+    /// Sends each session the `client.reconnect` `redirect` returns for it. Sessions it returns
+    /// `None` for are left to disconnect by themselves, or are closed at the shutdown timeout.
+    pub fn new(
+        redirect: impl Fn(&Session<CState>) -> Option<Reconnect> + Send + Sync + 'static,
+    ) -> Self {
+        DrainConfig {
+            redirect: Arc::new(redirect),
+            window: Duration::ZERO,
+        }
+    }
+
+    /// Spreads the miners across `servers` by a hash of their IP address, so that a miner is
+    /// always sent to the same sibling, by every server and release that has the same list.
+    pub fn siblings(servers: impl IntoIterator<Item = (impl Into<String>, u16)>) -> Self {
+        let servers: Vec<(String, u16)> = servers
+            .into_iter()
+            .map(|(host, port)| (host.into(), port))
+            .collect();
+
+        Self::new(move |session| {
+            let (host, port) = pick(&servers, session.ip().ip())?;
+            Some(Reconnect {
+                host: Some(host.clone()),
+                port: Some(*port),
+                wait_time: None,
+            })
+        })
+    }
+
+    /// Spreads the redirects evenly over `window`, so that the miners don't all arrive at their
+    /// new server at once. Defaults to sending them all straight away.
+    #[must_use]
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+}
+
+#[cfg(feature = "v1")]
+fn pick<T>(servers: &[T], ip: IpAddr) -> Option<&T> {
+    if servers.is_empty() {
+        return None;
+    }
+
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    #[allow(clippy::cast_possible_truncation)]
+    servers.get((fnv1a(&octets) % servers.len() as u64) as usize)
+}
+
+/// 64 bit FNV-1a, which unlike the std hashers is the same in every process and release.
+#[cfg(feature = "v1")]
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// How far along a drain is, as served by the API's `/drain`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DrainProgress {
+    pub draining: bool,
+    /// Sessions connected when the drain started.
+    pub sessions: usize,
+    /// Sessions sent a `client.reconnect` so far.
+    pub redirected: usize,
+    /// Sessions still connected, as of the last check.
+    pub remaining: usize,
+}
+
+/// Triggers a drain: the server is marked not ready, stops accepting and redirects its sessions
+/// elsewhere with `client.reconnect`, then shuts down once they are gone.
+#[derive(Clone, Default)]
+pub struct Drain {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: Notify,
+    draining: AtomicBool,
+    sessions: AtomicUsize,
+    redirected: AtomicUsize,
+    remaining: AtomicUsize,
+}
+
+impl Drain {
+    /// Requests a drain. A `POST` to the API's `/drain` does the same.
+    pub fn trigger(&self) {
+        self.inner.requested.notify_one();
+    }
+
+    pub(crate) async fn requested(&self) {
+        self.inner.requested.notified().await;
+    }
+
+    #[must_use]
+    pub fn progress(&self) -> DrainProgress {
+        DrainProgress {
+            draining: self.inner.draining.load(Ordering::Relaxed),
+            sessions: self.inner.sessions.load(Ordering::Relaxed),
+            redirected: self.inner.redirected.load(Ordering::Relaxed),
+            remaining: self.inner.remaining.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn start<CState: Clone>(&self, session_list: &SessionList<CState>) {
+        let sessions = session_list.len();
+        self.inner.sessions.store(sessions, Ordering::Relaxed);
+        self.inner.remaining.store(sessions, Ordering::Relaxed);
+        self.inner.draining.store(true, Ordering::Relaxed);
+
+        info!("Draining {sessions} sessions");
+    }
+
+    /// Sends every session its `client.reconnect`, spread over the configured window.
+    #[cfg(feature = "v1")]
+    pub(crate) async fn redirect<CState: Clone>(
+        &self,
+        session_list: &SessionList<CState>,
+        config: &DrainConfig<CState>,
+    ) {
+        let sessions = session_list.get_all_miners();
+        let start = Instant::now();
+        let mut logged = start;
+
+        for (i, session) in sessions.iter().enumerate() {
+            let at = start + config.window.mul_f64(i as f64 / sessions.len() as f64);
+            tokio::time::sleep_until(at).await;
+
+            if session.is_disconnected() {
+                continue;
+            }
+
+            if let Some(reconnect) = (config.redirect)(session) {
+                if let Err(e) = session.send_notification(&reconnect) {
+                    warn!(connection_id = %session.id(), cause = %e, "Failed to send redirect");
+                    continue;
+                }
+                self.inner.redirected.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if logged.elapsed() >= PROGRESS_INTERVAL {
+                self.log(session_list);
+                logged = Instant::now();
+            }
+        }
+
+        self.log(session_list);
+    }
+
+    /// Waits up to `timeout` for the sessions to disconnect, then closes those that are left.
+    pub(crate) async fn wait<CState: Clone>(
+        &self,
+        session_list: &SessionList<CState>,
+        timeout: Duration,
+    ) {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            let remaining = session_list.len();
+            self.inner.remaining.store(remaining, Ordering::Relaxed);
+            if remaining == 0 {
+                break;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("{remaining} remaining, force shutting down now");
+                session_list.shutdown();
+                break;
+            }
+
+            info!("Waiting for all miners to disconnect, {remaining} remaining");
+            tokio::time::sleep(backoff.min(deadline - now)).await;
+
+            backoff *= 2;
+        }
+    }
+
+    #[cfg(feature = "v1")]
+    fn log<CState: Clone>(&self, session_list: &SessionList<CState>) {
+        let remaining = session_list.len();
+        self.inner.remaining.store(remaining, Ordering::Relaxed);
+
+        let progress = self.progress();
+        info!(
+            "Drain redirected {}/{} sessions, {} remaining",
+            progress.redirected, progress.sessions, progress.remaining
+        );
+    }
+}
+
+#[cfg(all(test, feature = "v1"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_same_sibling_for_an_address() {
+        let servers = ["a", "b", "c"];
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(pick(&servers, ip), pick(&servers, ip));
+        assert!(pick::<&str>(&[], ip).is_none());
+
+        let picked: std::collections::HashSet<_> = (0..=255u8)
+            .map(|i| pick(&servers, IpAddr::from([10, 0, 0, i])).unwrap())
+            .collect();
+        assert_eq!(picked.len(), servers.len());
+    }
+
+    #[test]
+    fn picks_siblings_independently_of_the_process() {
+        let servers = ["a", "b", "c"];
+
+        for (ip, server) in [("10.0.0.1", "a"), ("10.0.0.3", "c"), ("192.168.1.20", "b")] {
+            assert_eq!(pick(&servers, ip.parse().unwrap()), Some(&server));
+        }
+    }
+}
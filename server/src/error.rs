@@ -5,6 +5,12 @@ use futures::channel::mpsc::SendError;
 pub enum Error {
     #[error("Banned connection attempted to connect: {0}")]
     ConnectionBanned(ban_manager::Key),
+    #[error("Too many connections from {0}")]
+    TooManyConnections(std::net::IpAddr),
+    #[error("Connecting too quickly from {0}")]
+    ConnectionRateLimited(std::net::IpAddr),
+    #[error("More than one listener is named {0}")]
+    DuplicateListener(String),
     #[error("Session IDs Exhausted")]
     SessionIDsExhausted,
     //This is the result of a non-graceful shutdown from someone connecting.
@@ -12,6 +18,14 @@ pub enum Error {
     PeerResetConnection,
     #[error(transparent)]
     Sender(#[from] tokio::sync::mpsc::error::SendError<SendInformation>),
+    #[error("Frame exceeded the maximum size of {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("Frame was not completed in time")]
+    FrameTimeout,
+    #[error("Send queue is full")]
+    SendQueueFull,
+    #[error("Send queue is closed")]
+    SendQueueClosed,
     #[error(transparent)]
     Json(#[from] serde_json::error::Error),
     #[error(transparent)]
@@ -20,9 +34,40 @@ pub enum Error {
     MesssageSend(#[from] SendError),
     #[error(transparent)]
     AddrParseError(#[from] std::net::AddrParseError),
+    #[error(transparent)]
+    Stratum(#[from] crate::StratumError),
+    #[error(transparent)]
+    ProxyProtocol(#[from] crate::proxy_protocol::Error),
     #[cfg(feature = "api")]
     #[error(transparent)]
     API(#[from] crate::api::Error),
+    #[cfg(feature = "v2")]
+    #[error(transparent)]
+    V2(#[from] crate::v2::Error),
+    #[cfg(feature = "upstream")]
+    #[error(transparent)]
+    Upstream(#[from] crate::upstream::Error),
+    #[cfg(feature = "tls")]
+    #[error(transparent)]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[cfg(feature = "tls")]
+    #[error("Unable to load TLS file {}: {1}", .0.display())]
+    InvalidTlsFile(std::path::PathBuf, String),
+    #[cfg(feature = "tls")]
+    #[error("TLS is not enabled on this server")]
+    TlsNotEnabled,
+    #[cfg(feature = "tls")]
+    #[error("Timed out during the TLS handshake")]
+    TlsHandshakeTimeout,
+    #[cfg(unix)]
+    #[error("Handoff is not enabled on this server")]
+    HandoffNotEnabled,
+    #[cfg(feature = "websocket")]
+    #[error(transparent)]
+    WebSocket(#[from] Box<tokio_tungstenite::tungstenite::Error>),
+    #[cfg(feature = "websocket")]
+    #[error("Timed out during the WebSocket handshake")]
+    WebSocketHandshakeTimeout,
 
     //Non-updated Errors
     #[error("Stratum User not authorized")]
@@ -35,6 +80,10 @@ pub enum Error {
     MethodDoesntExist,
     #[error("Can't break ExMessage header - Not complete")]
     BrokenExHeader,
+    #[error("Unknown ExMessage command: {0:#04x}")]
+    UnknownExMessage(u8),
+    #[error("Invalid ExMessage: {0}")]
+    InvalidExMessage(&'static str),
     //@todo double cehck this covers it, and doesn't just feature gate the tranpsarent part.
     //@todo shutdown error.
     // #[error("Timeout Error: {0}")]
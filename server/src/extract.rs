@@ -0,0 +1,263 @@
+//! Extractors for endpoint arguments.
+//!
+//! Any endpoint whose arguments all implement [`FromStratumRequest`] can be registered on the
+//! server, e.g. `async fn submit(Params(submit): Params<v1::Submit>, _: Authorized, Sess(session):
+//! Sess<CState>)`. If an extractor fails the miner is answered with the returned error and the
+//! endpoint is never called.
+
+use crate::{types::GlobalVars, Session, StratumError, StratumRequest, ID};
+use serde::de::DeserializeOwned;
+
+pub trait FromStratumRequest<State, CState>: Sized {
+    fn from_request(
+        req: &StratumRequest<State>,
+        session: &Session<CState>,
+    ) -> Result<Self, StratumError>;
+}
+
+/// The request's params deserialized into `T`.
+#[derive(Debug, Clone)]
+pub struct Params<T>(pub T);
+
+impl<State, CState, T: DeserializeOwned> FromStratumRequest<State, CState> for Params<T> {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.params()
+            .map(Params)
+            .map_err(|e| StratumError::other(format!("Invalid params: {e}")))
+    }
+}
+
+/// The id of the request.
+#[derive(Debug, Clone)]
+pub struct Id(pub ID);
+
+impl<State, CState> FromStratumRequest<State, CState> for Id {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.get_id()
+            .map(Id)
+            .map_err(|e| StratumError::other(format!("Invalid id: {e}")))
+    }
+}
+
+/// Application scoped state.
+#[derive(Debug, Clone)]
+pub struct State<S>(pub S);
+
+impl<S: Clone, CState> FromStratumRequest<S, CState> for State<S> {
+    fn from_request(
+        req: &StratumRequest<S>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        Ok(State(req.state().clone()))
+    }
+}
+
+/// Server wide variables such as the server id.
+#[derive(Debug, Clone)]
+pub struct Global(pub GlobalVars);
+
+impl<State, CState> FromStratumRequest<State, CState> for Global {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        Ok(Global(req.global_vars().clone()))
+    }
+}
+
+/// The payload of an SV2 frame decoded as `M`.
+#[cfg(feature = "v2")]
+#[derive(Debug, Clone)]
+pub struct V2Message<M>(pub M);
+
+#[cfg(feature = "v2")]
+impl<State, CState, M: crate::v2::Message> FromStratumRequest<State, CState> for V2Message<M> {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.message()
+            .map(V2Message)
+            .map_err(|e| StratumError::other(format!("Invalid message: {e}")))
+    }
+}
+
+/// The BTC Agent ex-message the request carries.
+#[cfg(feature = "btcagent")]
+#[derive(Debug, Clone)]
+pub struct AgentMessage(pub crate::btcagent::ExMessage);
+
+#[cfg(feature = "btcagent")]
+impl<State, CState> FromStratumRequest<State, CState> for AgentMessage {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.ex_message()
+            .cloned()
+            .map(AgentMessage)
+            .ok_or_else(|| StratumError::other("Request is not an ex-message"))
+    }
+}
+
+/// The `SessionID` of the agent's worker that an ex-message concerns.
+#[cfg(feature = "btcagent")]
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerSession(pub crate::SessionID);
+
+#[cfg(feature = "btcagent")]
+impl<State, CState> FromStratumRequest<State, CState> for WorkerSession {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.worker_session_id()
+            .map(WorkerSession)
+            .ok_or_else(|| StratumError::other("Request has no worker session id"))
+    }
+}
+
+/// The server wide connection to a parent pool. Rejects the request if none was configured.
+#[cfg(feature = "upstream")]
+#[derive(Debug, Clone)]
+pub struct Pool(pub crate::upstream::Upstream);
+
+#[cfg(feature = "upstream")]
+impl<State, CState> FromStratumRequest<State, CState> for Pool {
+    fn from_request(
+        req: &StratumRequest<State>,
+        _session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        req.global_vars()
+            .upstream
+            .clone()
+            .map(Pool)
+            .ok_or_else(|| StratumError::other("No upstream pool configured"))
+    }
+}
+
+/// The Session that sent the request.
+#[derive(Clone)]
+pub struct Sess<CState>(pub Session<CState>);
+
+impl<State, CState: Clone> FromStratumRequest<State, CState> for Sess<CState> {
+    fn from_request(
+        _req: &StratumRequest<State>,
+        session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        Ok(Sess(session.clone()))
+    }
+}
+
+/// Rejects the request with an `Unauthorized` error unless the Session has been authorized.
+#[derive(Debug, Clone, Copy)]
+pub struct Authorized;
+
+impl<State, CState: Clone> FromStratumRequest<State, CState> for Authorized {
+    fn from_request(
+        _req: &StratumRequest<State>,
+        session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        if session.authorized() {
+            Ok(Authorized)
+        } else {
+            Err(StratumError::unauthorized())
+        }
+    }
+}
+
+/// Rejects the request with a `NotSubscribed` error unless the Session has subscribed.
+#[derive(Debug, Clone, Copy)]
+pub struct Subscribed;
+
+impl<State, CState: Clone> FromStratumRequest<State, CState> for Subscribed {
+    fn from_request(
+        _req: &StratumRequest<State>,
+        session: &Session<CState>,
+    ) -> Result<Self, StratumError> {
+        if session.subscribed() {
+            Ok(Subscribed)
+        } else {
+            Err(StratumError::not_subscribed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frame::Request, handler::Handler, ErrorCode, Frame};
+    use serde_json::json;
+
+    fn request(params: serde_json::Value) -> StratumRequest<u32> {
+        StratumRequest {
+            state: 7,
+            values: Frame::V1(Request {
+                id: ID::Num(1),
+                method: String::from("mining.submit"),
+                params,
+            }),
+            global_vars: GlobalVars::new(3),
+        }
+    }
+
+    #[test]
+    fn params_extraction() {
+        let session = Session::mock(());
+
+        let Params((username, job_id)) =
+            Params::<(String, u64)>::from_request(&request(json!(["user.worker", 191])), &session)
+                .unwrap();
+        assert_eq!(username, "user.worker");
+        assert_eq!(job_id, 191);
+
+        let error =
+            Params::<(String, u64)>::from_request(&request(json!(["user.worker"])), &session)
+                .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Other);
+    }
+
+    #[test]
+    fn authorized_guard() {
+        let session = Session::mock(());
+        let req = request(json!([]));
+
+        let error = Authorized::from_request(&req, &session).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Unauthorized);
+
+        session.authorize();
+        assert!(Authorized::from_request(&req, &session).is_ok());
+    }
+
+    #[tokio::test]
+    async fn handler_with_extractors() {
+        async fn submit(
+            Params((_, job_id)): Params<(String, String)>,
+            State(state): State<u32>,
+            Global(global): Global,
+            _: Authorized,
+        ) -> Result<String, std::io::Error> {
+            Ok(format!("{job_id}:{state}:{}", global.server_id))
+        }
+
+        let endpoint = Handler::<u32, (), _>::into_endpoint(submit);
+        let session = Session::mock(());
+        let params = json!(["user.worker", "bf"]);
+
+        let error = endpoint
+            .call(request(params.clone()), session.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Unauthorized);
+
+        session.authorize();
+        let result = endpoint.call(request(params), session).await.unwrap();
+        assert_eq!(result, json!("bf:7:3"));
+    }
+}
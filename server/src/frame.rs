@@ -1,22 +1,43 @@
-use crate::ID;
+use crate::{StratumError, ID};
 #[cfg(feature = "v1")]
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub enum Frame {
-    // #[cfg(feature = "btcagent")]
-    // ExMessage(ExMessage),
+    #[cfg(feature = "btcagent")]
+    ExMessage(crate::btcagent::ExMessage),
 
     // #[cfg(feature = "v1")]
     // V1(serde_json::map::Map<String, serde_json::Value>),
     #[cfg(feature = "v1")]
     V1(Request),
+    #[cfg(feature = "v2")]
+    V2(crate::v2::Frame),
 }
 
+/// SV2 frames and ex-messages carry no JSON-RPC id.
+#[cfg(any(feature = "v2", feature = "btcagent"))]
+static NULL_ID: ID = ID::Null(serde_json::Value::Null);
+
 impl Frame {
     pub(crate) fn method(&self) -> &str {
         match self {
             #[cfg(feature = "v1")]
             Frame::V1(req) => &req.method,
+            #[cfg(feature = "v2")]
+            Frame::V2(frame) => frame.name(),
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(msg) => msg.method(),
+        }
+    }
+
+    pub(crate) fn id(&self) -> &ID {
+        match self {
+            #[cfg(feature = "v1")]
+            Frame::V1(req) => &req.id,
+            #[cfg(feature = "v2")]
+            Frame::V2(_) => &NULL_ID,
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(_) => &NULL_ID,
         }
     }
 }
@@ -28,90 +49,11 @@ pub struct Request {
     pub params: serde_json::Value,
 }
 
-//@todo move all these to seperate crate, but for now it works.
-
-// ex-message: BTC Agent Messages
-//   magic_number	uint8_t		magic number for Ex-Message, always 0x7F
-//   type/cmd		uint8_t		message type
-//   length			uint16_t	message length (include header self)
-//   message_body	uint8_t[]	message body
-// #[derive(Clone, Debug)]
-// pub struct ExMessageGeneric {
-//     pub magic_number: u8,
-//     pub cmd: MessageTypes,
-//     pub length: u16,
-//     pub body: Buffer,
-// }
-//
-// impl ExMessageGeneric {
-//     //@todo maybe switch this to bytes.
-//     pub fn from_buffer(buffer: &mut Buffer) -> Result<Self> {
-//         let magic_number = buffer.read_u8().map_err(|_| Error::BrokenExHeader)?;
-//         let cmd = buffer.read_u8().map_err(|_| Error::BrokenExHeader)?;
-//         let length = buffer.read_u16().map_err(|_| Error::BrokenExHeader)?;
-//
-//         let body = buffer.clone();
-//
-//         let cmd = MessageTypes::from_u8(cmd);
-//
-//         if length as usize != body.len() {
-//             return Err(Error::BrokenExHeader);
-//         }
-//
-//         if let MessageTypes::Unknown(_) = cmd {
-//             return Err(Error::BrokenExHeader);
-//         }
-//
-//         Ok(ExMessageGeneric {
-//             magic_number,
-//             cmd,
-//             length,
-//             body,
-//         })
-//     }
-// }
-
-// #[derive(Clone, Eq, PartialEq, Debug)]
-// pub enum MessageTypes {
-//     RegisterWorker,
-//     SubmitShare,
-//     SubmitShareWithTime,
-//     SubmitShareWithVersion,
-//     SubmitShareWithTimeAndVersion,
-//     UnregisterWorker,
-//     MiningSetDiff,
-//
-//     Unknown(u8),
-// }
-//
-// impl MessageTypes {
-//     pub fn from_u8(cmd: u8) -> Self {
-//         match cmd {
-//             0x01 => MessageTypes::RegisterWorker,
-//             0x02 => MessageTypes::SubmitShare,
-//             0x03 => MessageTypes::SubmitShareWithTime,
-//             0x04 => MessageTypes::UnregisterWorker,
-//             0x05 => MessageTypes::MiningSetDiff,
-//             //@note not sure why these are so far after the originals. Makes me think there are
-//             //other messages we are missing here, but can figure that out later.
-//             0x12 => MessageTypes::SubmitShareWithVersion,
-//             0x13 => MessageTypes::SubmitShareWithTimeAndVersion,
-//             _ => MessageTypes::Unknown(cmd),
-//         }
-//     }
-// }
-//
-// impl fmt::Display for MessageTypes {
-//     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-//         match *self {
-//             MessageTypes::RegisterWorker => write!(f, "exMessageRegisterWorker"),
-//             MessageTypes::SubmitShare => write!(f, "exMessageSubmitShare"),
-//             MessageTypes::SubmitShareWithTime => write!(f, "exMessageSubmitShare"),
-//             MessageTypes::UnregisterWorker => write!(f, "exMessageUnregisterWorker"),
-//             MessageTypes::MiningSetDiff => write!(f, "exMessageMiningSetDiff"),
-//             MessageTypes::SubmitShareWithVersion => write!(f, "exMessageSubmitShare"),
-//             MessageTypes::SubmitShareWithTimeAndVersion => write!(f, "exMessageSubmitShare"),
-//             _ => write!(f, ""),
-//         }
-//     }
-// }
+/// A Stratum V1 response to a miner's request. The `id` is always copied from the request that it
+/// answers, and `error` is serialized as `null` on success.
+#[derive(Clone, Serialize, Debug)]
+pub struct Response {
+    pub id: ID,
+    pub result: serde_json::Value,
+    pub error: Option<StratumError>,
+}
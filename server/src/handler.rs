@@ -0,0 +1,93 @@
+use crate::{
+    extract::FromStratumRequest,
+    route::{into_result, Endpoint},
+    Session, StratumError, StratumRequest,
+};
+use async_trait::async_trait;
+use futures::Future;
+use std::marker::PhantomData;
+
+/// Anything that can be registered as a route.
+///
+/// This is implemented for plain `Fn(StratumRequest<State>, Session<CState>)` endpoints, as well
+/// as for async functions taking up to 8 arguments that all implement [`FromStratumRequest`]. `T`
+/// only exists to tell those implementations apart and is inferred at the call site.
+pub trait Handler<State: Clone, CState: Clone, T>: Send + Sync + 'static {
+    #[doc(hidden)]
+    fn into_endpoint(self) -> Box<dyn Endpoint<State, CState>>;
+}
+
+#[doc(hidden)]
+pub struct ViaEndpoint;
+
+impl<State, CState, E> Handler<State, CState, ViaEndpoint> for E
+where
+    State: Clone,
+    CState: Clone,
+    E: Endpoint<State, CState>,
+{
+    fn into_endpoint(self) -> Box<dyn Endpoint<State, CState>> {
+        Box::new(self)
+    }
+}
+
+/// Runs the extractors for each argument before calling the wrapped function.
+struct ExtractorEndpoint<F, T> {
+    f: F,
+    _args: PhantomData<fn() -> T>,
+}
+
+macro_rules! impl_handler {
+    ($($ty:ident),+) => {
+        impl<State, CState, F, Fut, Res, E, $($ty,)+> Handler<State, CState, ($($ty,)+)> for F
+        where
+            State: Clone + Send + Sync + 'static,
+            CState: Clone + Send + Sync + 'static,
+            F: Send + Sync + 'static + Fn($($ty,)+) -> Fut,
+            Fut: Future<Output = std::result::Result<Res, E>> + Send + 'static,
+            E: std::error::Error + 'static + std::marker::Send,
+            Res: Into<serde_json::Value> + 'static + std::marker::Send,
+            $($ty: FromStratumRequest<State, CState> + Send + 'static,)+
+        {
+            fn into_endpoint(self) -> Box<dyn Endpoint<State, CState>> {
+                Box::new(ExtractorEndpoint {
+                    f: self,
+                    _args: PhantomData,
+                })
+            }
+        }
+
+        #[async_trait]
+        impl<State, CState, F, Fut, Res, E, $($ty,)+> Endpoint<State, CState>
+            for ExtractorEndpoint<F, ($($ty,)+)>
+        where
+            State: Clone + Send + Sync + 'static,
+            CState: Clone + Send + Sync + 'static,
+            F: Send + Sync + 'static + Fn($($ty,)+) -> Fut,
+            Fut: Future<Output = std::result::Result<Res, E>> + Send + 'static,
+            E: std::error::Error + 'static + std::marker::Send,
+            Res: Into<serde_json::Value> + 'static + std::marker::Send,
+            $($ty: FromStratumRequest<State, CState> + Send + 'static,)+
+        {
+            #[allow(non_snake_case)]
+            async fn call(
+                &self,
+                req: StratumRequest<State>,
+                connection: Session<CState>,
+            ) -> std::result::Result<serde_json::Value, StratumError> {
+                $(let $ty = $ty::from_request(&req, &connection)?;)+
+
+                into_result((self.f)($($ty,)+).await, &connection)
+            }
+        }
+    };
+}
+
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+impl_handler!(T1, T2, T3, T4, T5);
+impl_handler!(T1, T2, T3, T4, T5, T6);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
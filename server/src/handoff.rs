@@ -0,0 +1,393 @@
+#[cfg(unix)]
+use std::{
+    ffi::OsString,
+    io,
+    mem::ManuallyDrop,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+#[cfg(unix)]
+use tokio::sync::Notify;
+#[cfg(unix)]
+use tracing::{info, warn};
+
+use crate::Result;
+
+/// The first descriptor passed under `LISTEN_FDS`, after stdin, stdout and stderr.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed to this process under `LISTEN_FDS`, by systemd socket activation or
+/// by a server handing off to it. A listener bound to the address of one of them takes it over
+/// instead of binding a new socket, so connections waiting to be accepted are not lost. See
+/// `StratumServerBuilder::with_inherited_sockets`.
+#[derive(Debug, Default)]
+pub struct InheritedSockets {
+    #[cfg(unix)]
+    pub(crate) tcp: Vec<(std::net::SocketAddr, std::net::TcpListener)>,
+    #[cfg(unix)]
+    pub(crate) unix: Vec<(PathBuf, std::os::unix::net::UnixListener)>,
+}
+
+impl InheritedSockets {
+    /// Takes the listening sockets passed to this process under `LISTEN_FDS`, and clears the
+    /// `LISTEN_*` variables so that nothing else takes them again. Changing the environment isn't
+    /// thread safe, so this is meant to be called first thing in `main`, before the runtime or
+    /// any other thread is started. Descriptors that aren't listening sockets are logged and left
+    /// open.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn take() -> Self {
+        let mut sockets = InheritedSockets::default();
+
+        // Sockets meant for another process, e.g. a parent started by systemd, are left alone. A
+        // server handing off can't know the pid of its replacement, so it leaves this unset.
+        if let Ok(pid) = std::env::var("LISTEN_PID") {
+            if pid.parse() != Ok(std::process::id()) {
+                return sockets;
+            }
+        }
+
+        let Some(count) = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+        else {
+            return sockets;
+        };
+
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            if let Err(e) = sockets.adopt(fd) {
+                warn!(fd, cause = %e, "Skipping inherited descriptor");
+            }
+        }
+
+        info!(
+            "Inherited {} TCP and {} Unix listening sockets",
+            sockets.tcp.len(),
+            sockets.unix.len()
+        );
+
+        sockets
+    }
+
+    #[cfg(not(unix))]
+    #[must_use]
+    pub fn take() -> Self {
+        InheritedSockets::default()
+    }
+
+    /// Takes ownership of `fd` if it is a listening socket. It is left open otherwise, as it
+    /// isn't known what else it may belong to.
+    #[cfg(unix)]
+    fn adopt(&mut self, fd: RawFd) -> io::Result<()> {
+        if !is_listening_socket(fd)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a listening socket",
+            ));
+        }
+
+        // So that it isn't passed on to anything this process starts in turn.
+        set_cloexec(fd)?;
+
+        // SAFETY: `fd` is an open socket handed to this process under `LISTEN_FDS`, and nothing
+        // else in it takes ownership of it. It is only dropped once it is known to be usable.
+        let socket = ManuallyDrop::new(unsafe { socket2::Socket::from_raw_fd(fd) });
+        socket.set_nonblocking(true)?;
+        let address = socket.local_addr()?;
+
+        if let Some(address) = address.as_socket() {
+            self.tcp
+                .push((address, ManuallyDrop::into_inner(socket).into()));
+        } else if let Some(path) = address.as_pathname() {
+            self.unix
+                .push((path.to_path_buf(), ManuallyDrop::into_inner(socket).into()));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "neither a TCP nor a Unix socket bound to a path",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Takes an inherited socket bound to an address `host:port` resolves to.
+    #[cfg_attr(not(unix), allow(clippy::unused_async, unused_variables))]
+    pub(crate) async fn take_tcp(
+        &mut self,
+        host: &str,
+        port: u16,
+    ) -> Result<Option<tokio::net::TcpListener>> {
+        #[cfg(unix)]
+        {
+            if self.tcp.is_empty() {
+                return Ok(None);
+            }
+
+            for address in tokio::net::lookup_host(format!("{host}:{port}")).await? {
+                if let Some(index) = self.tcp.iter().position(|(bound, _)| *bound == address) {
+                    let (_, listener) = self.tcp.swap_remove(index);
+                    return Ok(Some(tokio::net::TcpListener::from_std(listener)?));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Takes an inherited Unix socket bound to `path`.
+    #[cfg(unix)]
+    pub(crate) fn take_unix(&mut self, path: &Path) -> Result<Option<tokio::net::UnixListener>> {
+        let Some(index) = self.unix.iter().position(|(bound, _)| bound == path) else {
+            return Ok(None);
+        };
+
+        let (_, listener) = self.unix.swap_remove(index);
+        Ok(Some(tokio::net::UnixListener::from_std(listener)?))
+    }
+}
+
+/// The process that takes over from this one on a handoff, see
+/// `StratumServerBuilder::with_handoff`.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct HandoffConfig {
+    pub(crate) program: PathBuf,
+    pub(crate) args: Vec<OsString>,
+}
+
+#[cfg(unix)]
+impl HandoffConfig {
+    /// Usually `std::env::current_exe()` with this process's arguments, after the binary has been
+    /// replaced on disk.
+    pub fn new(
+        program: impl Into<PathBuf>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Self {
+        HandoffConfig {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Triggers a handoff: a replacement process is started with every listening socket passed to
+/// it under `LISTEN_FDS`, after which this server stops accepting and drains its sessions.
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct Handoff {
+    inner: Arc<Inner>,
+}
+
+#[cfg(unix)]
+struct Inner {
+    config: HandoffConfig,
+    /// Duplicates of the listening sockets, along with names for `LISTEN_FDNAMES`.
+    sockets: Vec<(String, OwnedFd)>,
+    requested: Notify,
+}
+
+#[cfg(unix)]
+impl Handoff {
+    pub(crate) fn new(config: HandoffConfig, sockets: Vec<(String, OwnedFd)>) -> Self {
+        Handoff {
+            inner: Arc::new(Inner {
+                config,
+                sockets,
+                requested: Notify::new(),
+            }),
+        }
+    }
+
+    /// Requests a handoff. `SIGUSR2` and a `POST` to the API's `/handoff` do the same.
+    pub fn trigger(&self) {
+        self.inner.requested.notify_one();
+    }
+
+    pub(crate) async fn requested(&self) {
+        self.inner.requested.notified().await;
+    }
+
+    /// Starts the replacement process with the listening sockets.
+    pub(crate) fn spawn(&self) -> io::Result<std::process::Child> {
+        let sockets = &self.inner.sockets;
+        let fds: Vec<RawFd> = sockets.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+        let names: Vec<&str> = sockets.iter().map(|(name, _)| name.as_str()).collect();
+
+        let mut command = std::process::Command::new(&self.inner.config.program);
+        command
+            .args(&self.inner.config.args)
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env("LISTEN_FDNAMES", names.join(":"))
+            .env_remove("LISTEN_PID");
+
+        let mut moved = Vec::with_capacity(fds.len());
+        // SAFETY: `pass_fds` only makes async-signal-safe calls, and `moved` never grows past
+        // its capacity, so nothing allocates between fork and exec.
+        unsafe {
+            std::os::unix::process::CommandExt::pre_exec(&mut command, move || {
+                pass_fds(&fds, &mut moved)
+            });
+        }
+
+        let child = command.spawn()?;
+        info!(
+            pid = child.id(),
+            "Handed {} listening sockets off to {}",
+            sockets.len(),
+            self.inner.config.program.display()
+        );
+
+        Ok(child)
+    }
+}
+
+/// Moves `fds` to 3 and up in a forked child, where `LISTEN_FDS` expects them, without the
+/// close-on-exec flag.
+#[cfg(unix)]
+fn pass_fds(fds: &[RawFd], moved: &mut Vec<RawFd>) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    let above = LISTEN_FDS_START + fds.len() as RawFd;
+
+    // Out of the way first, in case one of them is where another has to go.
+    moved.clear();
+    for fd in fds {
+        // SAFETY: `fd` is open, it is owned by the `Handoff` the command was built from.
+        let fd = unsafe { libc::fcntl(*fd, libc::F_DUPFD, above) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        moved.push(fd);
+    }
+
+    for (target, fd) in (LISTEN_FDS_START..).zip(moved.iter()) {
+        // SAFETY: both descriptors are valid, and `fd` was only opened for this.
+        if unsafe { libc::dup2(*fd, target) } < 0 || unsafe { libc::close(*fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `fd` is a socket that has been set listening.
+#[cfg(unix)]
+fn is_listening_socket(fd: RawFd) -> io::Result<bool> {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: `stat` is only read once `fstat` has filled it in.
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fstat` succeeded.
+    if unsafe { stat.assume_init() }.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Ok(false);
+    }
+
+    let mut listening: libc::c_int = 0;
+    #[allow(clippy::cast_possible_truncation)]
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `listening` and `len` describe a buffer large enough for the option.
+    if unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            std::ptr::addr_of_mut!(listening).cast(),
+            &mut len,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listening != 0)
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: `fd` is open, and only its descriptor flags are changed.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn listeners_take_sockets_bound_to_their_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut sockets = InheritedSockets {
+            tcp: vec![(address, listener)],
+            unix: Vec::new(),
+        };
+
+        assert!(sockets
+            .take_tcp("127.0.0.1", address.port() + 1)
+            .await
+            .unwrap()
+            .is_none());
+
+        let taken = sockets.take_tcp("127.0.0.1", address.port()).await.unwrap();
+        assert_eq!(taken.unwrap().local_addr().unwrap(), address);
+        assert!(sockets
+            .take_tcp("127.0.0.1", address.port())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn passes_sockets_from_three() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let handoff = Handoff::new(
+            HandoffConfig::new(
+                "/bin/sh",
+                [
+                    "-c",
+                    concat!(
+                        r#"[ "$LISTEN_FDS" = 1 ] && [ "$LISTEN_FDNAMES" = stratum ] "#,
+                        "&& [ -S /dev/fd/3 ]",
+                    ),
+                ],
+            ),
+            vec![("stratum".to_string(), OwnedFd::from(listener))],
+        );
+
+        assert!(handoff.spawn().unwrap().wait().unwrap().success());
+    }
+
+    #[test]
+    fn only_listening_sockets_are_adopted() {
+        let mut sockets = InheritedSockets::default();
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let bound = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for fd in [file.as_raw_fd(), bound.as_raw_fd()] {
+            assert!(sockets.adopt(fd).is_err());
+            // Still open, for whatever owns it.
+            // SAFETY: only the descriptor flags are read.
+            assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        sockets
+            .adopt(std::os::fd::IntoRawFd::into_raw_fd(listener))
+            .unwrap();
+        assert_eq!(sockets.tcp.len(), 1);
+        assert_eq!(sockets.tcp[0].0, address);
+    }
+}
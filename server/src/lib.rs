@@ -14,42 +14,93 @@
 
 mod ban_manager;
 mod builder;
+mod codec;
 mod config;
 mod connection;
+mod connection_limiter;
+mod drain;
 mod error;
 mod frame;
 mod global;
+mod handler;
+mod handoff;
 mod id_manager;
+mod message_limiter;
 mod miner;
 mod miner_list;
 mod request;
 mod route;
 mod router;
+mod send_queue;
 mod server;
 mod session;
 mod session_list;
+mod socket;
+mod stratum_error;
 mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
 mod types;
 mod utils;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 #[cfg(feature = "api")]
 mod api;
 
+pub mod extract;
+pub mod middleware;
+pub mod proxy_protocol;
+
+#[cfg(feature = "btcagent")]
+pub mod btcagent;
+
+#[cfg(feature = "upstream")]
+pub mod upstream;
+
+#[cfg(feature = "v1")]
+pub mod v1;
+
+#[cfg(feature = "v2")]
+pub mod v2;
+
 pub(crate) use crate::{
     ban_manager::BanManager, connection::Connection, frame::Frame, miner_list::MinerList,
 };
 
 pub use crate::{
     builder::StratumServerBuilder,
-    config::{Config, ConfigManager, ConnectionConfig, DifficultyConfig},
+    config::{
+        Config, ConfigManager, ConnectionConfig, DifficultyConfig, ListenerConfig,
+        MessageRateLimit, OverflowPolicy, RateLimitAction,
+    },
+    drain::{Drain, DrainProgress},
     error::Error,
     global::Global,
+    handler::Handler,
     miner::Miner,
     request::StratumRequest,
+    router::Router,
+    send_queue::QueueStats,
     server::StratumServer,
     session::Session,
-    session_list::SessionList,
-    types::{Difficulty, ReadyIndicator, SessionID, EX_MAGIC_NUMBER, ID},
+    session_list::{ConnectionStats, SessionList},
+    socket::SocketOptions,
+    stratum_error::{ErrorCode, ErrorPolicy, StratumError},
+    types::{Difficulty, GlobalVars, ReadyIndicator, SessionID, EX_MAGIC_NUMBER, ID},
 };
 
+#[cfg(feature = "v1")]
+pub use crate::drain::DrainConfig;
+
+#[cfg(feature = "upstream")]
+pub use crate::config::UpstreamConfig;
+
+#[cfg(feature = "tls")]
+pub use crate::tls::TlsConfig;
+
+pub use crate::handoff::InheritedSockets;
+#[cfg(unix)]
+pub use crate::handoff::{Handoff, HandoffConfig};
+
 pub type Result<T> = std::result::Result<T, Error>;
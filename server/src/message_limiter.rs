@@ -0,0 +1,252 @@
+use crate::{
+    config::{Budget, MessageRateLimit, RateLimitAction},
+    ConfigManager, Frame, Session,
+};
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tracing::{trace, warn};
+
+/// Limits how quickly one session may send messages, checked in its handler loop before a
+/// message reaches the router.
+pub(crate) struct MessageLimiter {
+    config: MessageRateLimit,
+    session: Bucket,
+    /// Buckets of the methods with their own budget, added as the session first sends them.
+    methods: HashMap<String, Bucket>,
+    /// Messages refused since the session's budgets were last full.
+    violations: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    Allow,
+    Refuse,
+    Ban,
+}
+
+/// A token bucket holding up to a budget's burst, refilled at its rate.
+pub(crate) struct Bucket {
+    /// Messages left, as of `refilled`.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn new(budget: Option<Budget>, now: Instant) -> Self {
+        Bucket {
+            tokens: budget.map_or(0.0, |budget| budget.burst as f64),
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst as f64);
+        self.refilled = now;
+    }
+
+    /// Refills the bucket, then takes a message from it if one is left.
+    pub(crate) fn take(&mut self, budget: Budget, now: Instant) -> bool {
+        self.refill(budget, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+impl MessageLimiter {
+    /// A limiter for a new session, which allows everything unless a `MessageRateLimit` is
+    /// configured.
+    pub(crate) fn new(config_manager: &ConfigManager) -> Self {
+        let config = config_manager
+            .connection_config()
+            .message_rate_limit
+            .clone()
+            .unwrap_or_default();
+
+        MessageLimiter::with_config(config)
+    }
+
+    fn with_config(config: MessageRateLimit) -> Self {
+        MessageLimiter {
+            session: Bucket::new(config.session, Instant::now()),
+            methods: HashMap::new(),
+            violations: 0,
+            config,
+        }
+    }
+
+    /// Whether `frame` is over the limit, in which case it has been answered or dropped and must
+    /// not be handled, nor push back the session's timeout. Sessions that keep going over it are
+    /// banned.
+    pub(crate) fn limit<CState: Clone>(
+        &mut self,
+        frame: &Frame,
+        session: &Session<CState>,
+    ) -> bool {
+        if self.config.session.is_none() && self.config.methods.is_empty() {
+            return false;
+        }
+
+        match self.check(frame.method(), Instant::now()) {
+            Verdict::Allow => false,
+            Verdict::Refuse => {
+                trace!(
+                    connection_id = %session.id(),
+                    "Message {} over the rate limit",
+                    frame.method()
+                );
+
+                #[cfg(feature = "v1")]
+                if self.config.action == RateLimitAction::Respond && !frame.id().is_notification() {
+                    let response = crate::frame::Response {
+                        id: frame.id().clone(),
+                        result: serde_json::Value::Null,
+                        error: Some(crate::StratumError::other("Rate limit exceeded")),
+                    };
+
+                    if let Err(e) = session.send(response) {
+                        warn!(connection_id = %session.id(), cause = %e, "Failed to send response");
+                    }
+                }
+
+                true
+            }
+            Verdict::Ban => {
+                warn!(
+                    connection_id = %session.id(),
+                    ip = session.ip().to_string(),
+                    "Banning for repeatedly exceeding the message rate limit"
+                );
+                session.ban_ip();
+
+                true
+            }
+        }
+    }
+
+    fn check(&mut self, method: &str, now: Instant) -> Verdict {
+        let method_budget = self.config.methods.get(method).copied();
+        if method_budget.is_some() && !self.methods.contains_key(method) {
+            self.methods
+                .insert(method.to_string(), Bucket::new(method_budget, now));
+        }
+
+        let mut buckets = [
+            self.config
+                .session
+                .map(|budget| (&mut self.session, budget)),
+            method_budget.and_then(|budget| Some((self.methods.get_mut(method)?, budget))),
+        ];
+
+        let mut rested = true;
+        let mut allowed = true;
+        for (bucket, budget) in buckets.iter_mut().flatten() {
+            bucket.refill(*budget, now);
+            rested &= bucket.tokens >= budget.burst as f64;
+            allowed &= bucket.tokens >= 1.0;
+        }
+
+        if rested {
+            self.violations = 0;
+        }
+
+        if allowed {
+            for (bucket, _) in buckets.iter_mut().flatten() {
+                bucket.tokens -= 1.0;
+            }
+
+            return Verdict::Allow;
+        }
+
+        self.violations += 1;
+        if self
+            .config
+            .ban_violations
+            .map_or(false, |violations| self.violations >= violations)
+        {
+            return Verdict::Ban;
+        }
+
+        Verdict::Refuse
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_the_session_rate() {
+        let mut limiter = MessageLimiter::with_config(MessageRateLimit::new().with_rate(2.0, 2));
+
+        for _ in 0..2 {
+            assert_eq!(
+                limiter.check("mining.submit", Instant::now()),
+                Verdict::Allow
+            );
+        }
+        assert_eq!(
+            limiter.check("mining.submit", Instant::now()),
+            Verdict::Refuse
+        );
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(
+            limiter.check("mining.submit", Instant::now()),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.check("mining.submit", Instant::now()),
+            Verdict::Refuse
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_methods_on_their_own() {
+        let mut limiter = MessageLimiter::with_config(
+            MessageRateLimit::new()
+                .with_rate(100.0, 100)
+                .with_method("mining.subscribe", 1.0, 1),
+        );
+
+        assert_eq!(
+            limiter.check("mining.subscribe", Instant::now()),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.check("mining.subscribe", Instant::now()),
+            Verdict::Refuse
+        );
+        assert_eq!(
+            limiter.check("mining.submit", Instant::now()),
+            Verdict::Allow
+        );
+
+        // Refused messages don't use up the session wide budget.
+        assert!((limiter.session.tokens - 98.0).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_sustained_abuse() {
+        let mut limiter =
+            MessageLimiter::with_config(MessageRateLimit::new().with_rate(1.0, 1).with_ban(3));
+
+        assert_eq!(limiter.check("junk", Instant::now()), Verdict::Allow);
+        for _ in 0..2 {
+            assert_eq!(limiter.check("junk", Instant::now()), Verdict::Refuse);
+        }
+
+        // Slowing down until the budget is full forgives earlier violations.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.check("junk", Instant::now()), Verdict::Allow);
+        for _ in 0..2 {
+            assert_eq!(limiter.check("junk", Instant::now()), Verdict::Refuse);
+        }
+        assert_eq!(limiter.check("junk", Instant::now()), Verdict::Ban);
+    }
+}
@@ -0,0 +1,397 @@
+//! Middleware that runs around endpoints.
+//!
+//! Middleware registered with `StratumServer::layer` wraps every route, middleware registered with
+//! `StratumServer::layer_for` only wraps the listed methods. Server wide middleware always runs
+//! first, in the order it was registered, followed by the method specific middleware.
+
+use crate::{config::Budget, route::DynEndpoint, Session, StratumError, StratumRequest};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, trace};
+
+pub(crate) type DynMiddleware<State, CState> = Arc<dyn Middleware<State, CState>>;
+
+#[async_trait]
+pub trait Middleware<State: Clone, CState: Clone>: Send + Sync + 'static {
+    /// Handles the request, calling `next.run` to pass it on to the rest of the chain. Returning
+    /// without calling `next` short circuits the request, the returned value is sent to the miner
+    /// as if the endpoint had produced it.
+    async fn handle(
+        &self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+        next: Next<'_, State, CState>,
+    ) -> Result<serde_json::Value, StratumError>;
+}
+
+/// The remainder of the middleware chain, ending with the endpoint.
+pub struct Next<'a, State: Clone, CState: Clone> {
+    pub(crate) endpoint: &'a DynEndpoint<State, CState>,
+    pub(crate) global: &'a [DynMiddleware<State, CState>],
+    pub(crate) method: &'a [DynMiddleware<State, CState>],
+}
+
+impl<State, CState> Next<'_, State, CState>
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    pub async fn run(
+        mut self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+    ) -> Result<serde_json::Value, StratumError> {
+        if let Some((current, rest)) = self.global.split_first() {
+            self.global = rest;
+            current.handle(req, session, self).await
+        } else if let Some((current, rest)) = self.method.split_first() {
+            self.method = rest;
+            current.handle(req, session, self).await
+        } else {
+            self.endpoint.call(req, session).await
+        }
+    }
+}
+
+/// Logs every request along with how long it took to handle.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Logger;
+
+#[async_trait]
+impl<State, CState> Middleware<State, CState> for Logger
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    async fn handle(
+        &self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+        next: Next<'_, State, CState>,
+    ) -> Result<serde_json::Value, StratumError> {
+        let method = req.method().to_owned();
+        let connection_id = session.id().to_string();
+        let start = Instant::now();
+
+        let result = next.run(req, session).await;
+
+        match &result {
+            Ok(_) => debug!(
+                connection_id,
+                method,
+                elapsed = ?start.elapsed(),
+                "Request handled"
+            ),
+            Err(e) => debug!(
+                connection_id,
+                method,
+                elapsed = ?start.elapsed(),
+                error.code = e.code().as_i32(),
+                error.message = %e,
+                "Request failed"
+            ),
+        }
+
+        result
+    }
+}
+
+/// Rejects requests from Sessions that have not been authorized yet with an `Unauthorized`
+/// error, e.g. `server.layer_for(&["mining.submit"], RequireAuthorized)`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequireAuthorized;
+
+#[async_trait]
+impl<State, CState> Middleware<State, CState> for RequireAuthorized
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    async fn handle(
+        &self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+        next: Next<'_, State, CState>,
+    ) -> Result<serde_json::Value, StratumError> {
+        if !session.authorized() {
+            return Err(StratumError::unauthorized());
+        }
+
+        next.run(req, session).await
+    }
+}
+
+/// Limits how often each Session may call the methods it wraps, answering requests over the limit
+/// with a "Rate limit exceeded" error, e.g.
+/// `server.layer_for(&["mining.subscribe"], RateLimit::new(5, Duration::from_secs(60)))`.
+///
+/// Each method is counted on its own, in a token bucket kept on the Session. To limit everything a
+/// Session sends before it reaches the Router, see `ConnectionConfig::with_message_rate_limit`
+/// instead.
+pub struct RateLimit {
+    /// Tells the buckets of this limiter apart from those of others on the same Session.
+    id: usize,
+    budget: Budget,
+}
+
+impl RateLimit {
+    /// Allows a burst of `max` requests for each method per Session, refilled at `max` every
+    /// `per`.
+    #[must_use]
+    pub fn new(max: u32, per: Duration) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        RateLimit {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            budget: Budget {
+                per_second: f64::from(max) / per.as_secs_f64(),
+                burst: max,
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<State, CState> Middleware<State, CState> for RateLimit
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    async fn handle(
+        &self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+        next: Next<'_, State, CState>,
+    ) -> Result<serde_json::Value, StratumError> {
+        if !session.take_rate_limit(self.id, req.method(), self.budget) {
+            trace!(
+                connection_id = %session.id(),
+                "Request {} over the rate limit",
+                req.method()
+            );
+            return Err(StratumError::other("Rate limit exceeded"));
+        }
+
+        next.run(req, session).await
+    }
+}
+
+/// Counts the requests, errors and time spent handling each method, e.g. to export them from a
+/// metrics endpoint. Clones share their counts, so keep one to read them back with `snapshot`.
+#[derive(Clone, Default)]
+pub struct RequestMetrics {
+    methods: Arc<Mutex<HashMap<String, MethodMetrics>>>,
+}
+
+/// What `RequestMetrics` has recorded for one method.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MethodMetrics {
+    pub requests: u64,
+    /// Requests answered with an error.
+    pub errors: u64,
+    /// Total time spent handling the requests.
+    pub elapsed: Duration,
+}
+
+impl RequestMetrics {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The metrics recorded so far, by method.
+    #[must_use]
+    pub fn snapshot(&self) -> HashMap<String, MethodMetrics> {
+        self.methods.lock().clone()
+    }
+}
+
+#[async_trait]
+impl<State, CState> Middleware<State, CState> for RequestMetrics
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    async fn handle(
+        &self,
+        req: StratumRequest<State>,
+        session: Session<CState>,
+        next: Next<'_, State, CState>,
+    ) -> Result<serde_json::Value, StratumError> {
+        let method = req.method().to_owned();
+        let start = Instant::now();
+
+        let result = next.run(req, session).await;
+
+        let mut methods = self.methods.lock();
+        let metrics = methods.entry(method).or_default();
+        metrics.requests += 1;
+        metrics.errors += u64::from(result.is_err());
+        metrics.elapsed += start.elapsed();
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frame::Request, types::GlobalVars, ErrorCode, Frame, ID};
+
+    struct Record(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    #[async_trait]
+    impl Middleware<(), ()> for Record {
+        async fn handle(
+            &self,
+            req: StratumRequest<()>,
+            session: Session<()>,
+            next: Next<'_, (), ()>,
+        ) -> Result<serde_json::Value, StratumError> {
+            self.1.lock().push(self.0);
+            next.run(req, session).await
+        }
+    }
+
+    fn request() -> StratumRequest<()> {
+        StratumRequest {
+            state: (),
+            values: Frame::V1(Request {
+                id: ID::Num(1),
+                method: String::from("mining.submit"),
+                params: serde_json::Value::Null,
+            }),
+            global_vars: GlobalVars::new(1),
+        }
+    }
+
+    async fn submit(_req: StratumRequest<()>, _session: Session<()>) -> crate::Result<bool> {
+        Ok(true)
+    }
+
+    #[tokio::test]
+    async fn middleware_runs_in_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let endpoint: Box<DynEndpoint<(), ()>> = Box::new(submit);
+        let global: Vec<DynMiddleware<(), ()>> = vec![
+            Arc::new(Record("first", calls.clone())),
+            Arc::new(Record("second", calls.clone())),
+        ];
+        let method: Vec<DynMiddleware<(), ()>> = vec![Arc::new(Record("method", calls.clone()))];
+
+        let next = Next {
+            endpoint: endpoint.as_ref(),
+            global: &global,
+            method: &method,
+        };
+
+        let result = next.run(request(), Session::mock(())).await.unwrap();
+
+        assert_eq!(result, serde_json::Value::Bool(true));
+        assert_eq!(*calls.lock(), vec!["first", "second", "method"]);
+    }
+
+    #[tokio::test]
+    async fn require_authorized_short_circuits() {
+        let endpoint: Box<DynEndpoint<(), ()>> = Box::new(submit);
+        let method: Vec<DynMiddleware<(), ()>> = vec![Arc::new(RequireAuthorized)];
+        let session = Session::mock(());
+
+        let next = Next {
+            endpoint: endpoint.as_ref(),
+            global: &[],
+            method: &method,
+        };
+        let error = next.run(request(), session.clone()).await.unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Unauthorized);
+
+        session.authorize();
+
+        let next = Next {
+            endpoint: endpoint.as_ref(),
+            global: &[],
+            method: &method,
+        };
+        assert!(next.run(request(), session).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_counts_each_session() {
+        let endpoint: Box<DynEndpoint<(), ()>> = Box::new(submit);
+        let method: Vec<DynMiddleware<(), ()>> =
+            vec![Arc::new(RateLimit::new(2, Duration::from_secs(60)))];
+        let run = |session: Session<()>| {
+            let next = Next {
+                endpoint: endpoint.as_ref(),
+                global: &[],
+                method: &method,
+            };
+            next.run(request(), session)
+        };
+
+        let session = Session::mock(());
+        assert!(run(session.clone()).await.is_ok());
+        assert!(run(session.clone()).await.is_ok());
+        let error = run(session).await.unwrap_err();
+        assert_eq!(error.message(), "Rate limit exceeded");
+
+        assert!(run(Session::mock(())).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_refills_over_time() {
+        let endpoint: Box<DynEndpoint<(), ()>> = Box::new(submit);
+        let method: Vec<DynMiddleware<(), ()>> =
+            vec![Arc::new(RateLimit::new(2, Duration::from_secs(2)))];
+        let session = Session::mock(());
+        let run = || {
+            let next = Next {
+                endpoint: endpoint.as_ref(),
+                global: &[],
+                method: &method,
+            };
+            next.run(request(), session.clone())
+        };
+
+        assert!(run().await.is_ok());
+        assert!(run().await.is_ok());
+        assert!(run().await.is_err());
+
+        // One request a second comes back, there is no window edge to burst across.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(run().await.is_ok());
+        assert!(run().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn request_metrics_count_by_method() {
+        let metrics = RequestMetrics::new();
+        let endpoint: Box<DynEndpoint<(), ()>> = Box::new(submit);
+        let global: Vec<DynMiddleware<(), ()>> =
+            vec![Arc::new(metrics.clone()), Arc::new(RequireAuthorized)];
+        let session = Session::mock(());
+
+        for _ in 0..2 {
+            let next = Next {
+                endpoint: endpoint.as_ref(),
+                global: &global,
+                method: &[],
+            };
+            let _ = next.run(request(), session.clone()).await;
+            session.authorize();
+        }
+
+        let snapshot = metrics.snapshot();
+        let submit = snapshot["mining.submit"];
+        assert_eq!(submit.requests, 2);
+        assert_eq!(submit.errors, 1);
+    }
+}
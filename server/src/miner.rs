@@ -90,6 +90,7 @@ impl Miner {
         }
     }
 
+    #[allow(clippy::unused_self)]
     pub(crate) fn ban(&self) {
         //@todo I now set needs ban in consider ban so that I don't have to drop the Mutex. In
         //here, we just really want to either contact the session, or disconnect the miner
@@ -111,6 +112,7 @@ impl Miner {
         //   on a connection? Or we just send this individual miner a ban signal (tbd)
     }
 
+    #[must_use]
     pub fn needs_ban(&self) -> bool {
         self.shared.ban_stats.lock().needs_ban
     }
@@ -0,0 +1,400 @@
+//! Parsing of the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+//! header that load balancers prefix connections with, in both the text (v1) and binary (v2)
+//! formats.
+//!
+//! The header is read when the server is built with `with_proxy(true)`. The miner's address in it
+//! replaces the connection's own address, and the full header is available to handlers through
+//! `Session::proxy_header`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// The longest a v1 header can be, including the CRLF.
+pub const V1_MAX_LENGTH: u64 = 107;
+
+/// The 12 bytes every v2 header starts with.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_HEADER_SIZE: usize = 16;
+
+// v2 TLV types, see section 2.2 of the specification.
+pub const TLV_ALPN: u8 = 0x01;
+pub const TLV_AUTHORITY: u8 = 0x02;
+pub const TLV_CRC32C: u8 = 0x03;
+pub const TLV_NOOP: u8 = 0x04;
+pub const TLV_UNIQUE_ID: u8 = 0x05;
+pub const TLV_SSL: u8 = 0x20;
+pub const TLV_NETNS: u8 = 0x30;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Connection did not start with a PROXY protocol header")]
+    MissingHeader,
+    #[error("Invalid PROXY protocol v1 header: {0}")]
+    InvalidV1(&'static str),
+    #[error("Invalid PROXY protocol v2 header: {0}")]
+    InvalidV2(&'static str),
+    #[error("Unsupported PROXY protocol version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unsupported PROXY protocol v2 address family: {0:#04x}")]
+    UnsupportedFamily(u8),
+    #[error("PROXY protocol header sent by untrusted source {0}")]
+    UntrustedSource(IpAddr),
+    #[error("Timed out waiting for the PROXY protocol header")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A type-length-value field from a v2 header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The miner's address. It is `None` for v1 `UNKNOWN` and v2 `LOCAL` headers, such as load
+    /// balancer health checks, in which case the connection's own address is used.
+    pub source: Option<SocketAddr>,
+    /// The address the miner connected to on the load balancer.
+    pub destination: Option<SocketAddr>,
+    /// Always empty for v1 headers.
+    pub tlvs: Vec<Tlv>,
+}
+
+impl ProxyHeader {
+    /// The value of the first TLV of type `kind`, e.g. [`TLV_AUTHORITY`].
+    #[must_use]
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|tlv| tlv.kind == kind)
+            .map(|tlv| tlv.value.as_slice())
+    }
+}
+
+/// Reads a v1 or v2 header, whichever the connection starts with. Nothing past the header is
+/// consumed.
+pub async fn read_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<ProxyHeader> {
+    match reader.fill_buf().await?.first() {
+        Some(b'P') => {
+            let mut line = Vec::new();
+            (&mut *reader)
+                .take(V1_MAX_LENGTH)
+                .read_until(b'\n', &mut line)
+                .await?;
+
+            parse_v1(&line)
+        }
+        Some(b'\r') => {
+            let mut header = [0; V2_HEADER_SIZE];
+            reader.read_exact(&mut header).await?;
+
+            let length = u16::from_be_bytes([header[14], header[15]]);
+            let mut payload = vec![0; length as usize];
+            reader.read_exact(&mut payload).await?;
+
+            parse_v2(header, &payload)
+        }
+        _ => Err(Error::MissingHeader),
+    }
+}
+
+/// Parses a v1 header, e.g. `PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n`.
+pub fn parse_v1(line: &[u8]) -> Result<ProxyHeader> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or(Error::InvalidV1("missing CRLF"))?;
+    let line = std::str::from_utf8(line).map_err(|_| Error::InvalidV1("not ASCII"))?;
+
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(Error::InvalidV1("missing PROXY prefix"));
+    }
+
+    let parse_ip = match fields.next() {
+        Some("UNKNOWN") => return Ok(ProxyHeader::default()),
+        Some("TCP4") => |ip: &str| ip.parse::<Ipv4Addr>().map(IpAddr::V4),
+        Some("TCP6") => |ip: &str| ip.parse::<Ipv6Addr>().map(IpAddr::V6),
+        _ => return Err(Error::InvalidV1("unknown protocol")),
+    };
+
+    let (Some(source), Some(destination), Some(source_port), Some(destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(Error::InvalidV1("wrong number of fields"));
+    };
+
+    let address = |ip: &str, port: &str| -> Result<SocketAddr> {
+        let ip = parse_ip(ip).map_err(|_| Error::InvalidV1("invalid address"))?;
+        let port = port.parse().map_err(|_| Error::InvalidV1("invalid port"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+
+    Ok(ProxyHeader {
+        source: Some(address(source, source_port)?),
+        destination: Some(address(destination, destination_port)?),
+        tlvs: Vec::new(),
+    })
+}
+
+/// Parses a v2 header, given its fixed 16 byte start and the payload that follows it.
+pub fn parse_v2(header: [u8; 16], payload: &[u8]) -> Result<ProxyHeader> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(Error::InvalidV2("invalid signature"));
+    }
+
+    let version = header[12] >> 4;
+    if version != 2 {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let local = match header[12] & 0x0F {
+        0x0 => true,
+        0x1 => false,
+        _ => return Err(Error::InvalidV2("unknown command")),
+    };
+
+    let family = header[13];
+    let (addresses, tlvs) = match family {
+        // TCP over IPv4
+        0x11 => {
+            let (addresses, tlvs) = split(payload, 12)?;
+            let ip = |at: usize| {
+                IpAddr::from(<[u8; 4]>::try_from(&addresses[at..at + 4]).expect("4 bytes"))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            (
+                Some((
+                    SocketAddr::new(ip(0), port(8)),
+                    SocketAddr::new(ip(4), port(10)),
+                )),
+                tlvs,
+            )
+        }
+        // TCP over IPv6
+        0x21 => {
+            let (addresses, tlvs) = split(payload, 36)?;
+            let ip = |at: usize| {
+                IpAddr::from(<[u8; 16]>::try_from(&addresses[at..at + 16]).expect("16 bytes"))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            (
+                Some((
+                    SocketAddr::new(ip(0), port(32)),
+                    SocketAddr::new(ip(16), port(34)),
+                )),
+                tlvs,
+            )
+        }
+        // Unspecified, which is only expected with LOCAL.
+        0x00 => (None, payload),
+        // Unix sockets, which carry no IP to take.
+        0x31 => (None, split(payload, 216)?.1),
+        family => return Err(Error::UnsupportedFamily(family)),
+    };
+
+    let (source, destination) = match addresses {
+        Some((source, destination)) if !local => (Some(source), Some(destination)),
+        _ => (None, None),
+    };
+
+    Ok(ProxyHeader {
+        source,
+        destination,
+        tlvs: parse_tlvs(tlvs)?,
+    })
+}
+
+fn split(payload: &[u8], at: usize) -> Result<(&[u8], &[u8])> {
+    if payload.len() < at {
+        return Err(Error::InvalidV2("addresses truncated"));
+    }
+
+    Ok(payload.split_at(at))
+}
+
+fn parse_tlvs(mut data: &[u8]) -> Result<Vec<Tlv>> {
+    let mut tlvs = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(Error::InvalidV2("TLV truncated"));
+        }
+
+        let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let value = data
+            .get(3..3 + length)
+            .ok_or(Error::InvalidV2("TLV truncated"))?;
+
+        tlvs.push(Tlv {
+            kind: data[0],
+            value: value.to_vec(),
+        });
+        data = &data[3 + length..];
+    }
+
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    fn tcp4_payload() -> Vec<u8> {
+        let mut payload = vec![92, 118, 161, 17, 172, 20, 42, 228];
+        payload.extend_from_slice(&55867u16.to_be_bytes());
+        payload.extend_from_slice(&8080u16.to_be_bytes());
+        payload
+    }
+
+    #[test]
+    fn v1_headers() {
+        let header = parse_v1(b"PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n").unwrap();
+        assert_eq!(header.source, Some("92.118.161.17:55867".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("172.20.42.228:8080".parse().unwrap())
+        );
+
+        let header = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 55867 8080\r\n").unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:55867".parse().unwrap()));
+
+        assert_eq!(
+            parse_v1(b"PROXY UNKNOWN\r\n").unwrap(),
+            ProxyHeader::default()
+        );
+        assert_eq!(
+            parse_v1(b"PROXY UNKNOWN ffff:f...f:ffff 1 2\r\n").unwrap(),
+            ProxyHeader::default()
+        );
+    }
+
+    #[test]
+    fn invalid_v1_headers() {
+        for line in [
+            &b"PROXY TCP4 92.118.161.17\r\n"[..],
+            b"PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080 1\r\n",
+            b"PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\n",
+            b"PROXY TCP4 2001:db8::1 172.20.42.228 55867 8080\r\n",
+            b"PROXY TCP4 92.118.161.17 172.20.42.228 65536 8080\r\n",
+            b"PROXY UDP4 92.118.161.17 172.20.42.228 55867 8080\r\n",
+            b"{\"id\":1}\r\n",
+            b"",
+        ] {
+            assert!(
+                matches!(parse_v1(line), Err(Error::InvalidV1(_))),
+                "{}",
+                String::from_utf8_lossy(line)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let mut payload = tcp4_payload();
+        payload.extend_from_slice(&[TLV_AUTHORITY, 0, 4]);
+        payload.extend_from_slice(b"pool");
+        let mut stream = v2(1, 0x11, &payload);
+        stream.extend_from_slice(b"{\"id\":1}\n");
+
+        let mut reader = BufReader::new(stream.as_slice());
+        let header = read_header(&mut reader).await.unwrap();
+        assert_eq!(header.source, Some("92.118.161.17:55867".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("172.20.42.228:8080".parse().unwrap())
+        );
+        assert_eq!(header.tlv(TLV_AUTHORITY), Some(&b"pool"[..]));
+
+        // Nothing past the header is consumed.
+        let mut rest = String::new();
+        reader.read_line(&mut rest).await.unwrap();
+        assert_eq!(rest, "{\"id\":1}\n");
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&[0x20, 0x01, 0xd, 0xb8]);
+        payload.extend_from_slice(&[0; 11]);
+        payload.push(1);
+        payload.extend_from_slice(&[0; 16]);
+        payload.extend_from_slice(&55867u16.to_be_bytes());
+        payload.extend_from_slice(&8080u16.to_be_bytes());
+        let header = read_header(&mut v2(1, 0x21, &payload).as_slice())
+            .await
+            .unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:55867".parse().unwrap()));
+
+        // Health checks from the load balancer itself.
+        let header = read_header(&mut v2(0, 0x11, &tcp4_payload()).as_slice())
+            .await
+            .unwrap();
+        assert_eq!(header.source, None);
+        let header = read_header(&mut v2(0, 0x00, &[]).as_slice()).await.unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[tokio::test]
+    async fn invalid_v2_headers() {
+        let payload = tcp4_payload();
+
+        let mut header = v2(1, 0x11, &payload);
+        header[12] = 0x11;
+        assert!(matches!(
+            read_header(&mut header.as_slice()).await,
+            Err(Error::UnsupportedVersion(1))
+        ));
+
+        assert!(matches!(
+            read_header(&mut v2(2, 0x11, &payload).as_slice()).await,
+            Err(Error::InvalidV2(_))
+        ));
+        assert!(matches!(
+            read_header(&mut v2(1, 0x12, &payload).as_slice()).await,
+            Err(Error::UnsupportedFamily(0x12))
+        ));
+        assert!(matches!(
+            read_header(&mut v2(1, 0x11, &payload[..8]).as_slice()).await,
+            Err(Error::InvalidV2(_))
+        ));
+
+        let mut truncated_tlv = payload.clone();
+        truncated_tlv.extend_from_slice(&[TLV_AUTHORITY, 0, 4, b'p']);
+        assert!(matches!(
+            read_header(&mut v2(1, 0x11, &truncated_tlv).as_slice()).await,
+            Err(Error::InvalidV2(_))
+        ));
+
+        // The length claims more than was sent.
+        let mut short = v2(1, 0x11, &payload);
+        short.truncate(20);
+        assert!(matches!(
+            read_header(&mut short.as_slice()).await,
+            Err(Error::Io(_))
+        ));
+
+        assert!(matches!(
+            read_header(&mut &b"{\"id\":1}\n"[..]).await,
+            Err(Error::MissingHeader)
+        ));
+    }
+}
@@ -19,31 +19,132 @@ impl<State> StratumRequest<State> {
         &self.global_vars
     }
 
-    // pub fn get_ex_message(&self) -> Option<ExMessageGeneric> {
-    //     match &self.values {
-    //         MessageValue::StratumV1(_) => None,
-    //         MessageValue::ExMessage(msg) => Some(msg.clone()),
-    //     }
-    // }
+    pub fn method(&self) -> &str {
+        self.values.method()
+    }
+
+    /// The BTC Agent ex-message this request carries, if any.
+    #[cfg(feature = "btcagent")]
+    pub fn ex_message(&self) -> Option<&crate::btcagent::ExMessage> {
+        match &self.values {
+            Frame::ExMessage(msg) => Some(msg),
+            #[cfg(feature = "v1")]
+            Frame::V1(_) => None,
+            #[cfg(feature = "v2")]
+            Frame::V2(_) => None,
+        }
+    }
+
+    /// The agent's session id for the worker an ex-message concerns. `None` for any other request.
+    #[cfg(feature = "btcagent")]
+    pub fn worker_session_id(&self) -> Option<crate::SessionID> {
+        self.ex_message()
+            .and_then(crate::btcagent::ExMessage::session_id)
+    }
 
+    /// Deserializes the request's params, e.g. `req.params::<v1::Submit>()`.
+    pub fn params<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        match &self.values {
+            Frame::V1(request) => T::deserialize(&request.params),
+            #[cfg(feature = "v2")]
+            Frame::V2(_) => Err(serde::de::Error::custom(
+                "SV2 frames have no params, use `message` instead",
+            )),
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(_) => Err(serde::de::Error::custom(
+                "Ex-messages have no params, use `ex_message` instead",
+            )),
+        }
+    }
+
+    /// Deserializes the request's params, `name` is ignored. Kept for existing callers, use
+    /// `params` for the whole params or `param` for a single field.
     pub fn get_json<T: serde::de::DeserializeOwned>(
         &self,
         _name: &str,
+    ) -> Result<T, serde_json::Error> {
+        self.params()
+    }
+
+    /// Deserializes a single field of params that were sent as an object, e.g.
+    /// `req.param::<String>("worker")`.
+    pub fn param<T: serde::de::DeserializeOwned>(
+        &self,
+        name: &str,
     ) -> Result<T, serde_json::Error> {
         match &self.values {
-            Frame::V1(request) => Ok(serde_json::from_value(request.params.clone())?),
-            // MessageValue::ExMessage(_) => Err(serde::de::Error::custom(
-            //     "wrong message type request".to_string(),
-            // )),
+            Frame::V1(request) => match request.params.get(name) {
+                Some(value) => T::deserialize(value),
+                None => Err(serde::de::Error::custom(format!(
+                    "missing field `{name}` in params"
+                ))),
+            },
+            #[cfg(feature = "v2")]
+            Frame::V2(_) => Err(serde::de::Error::custom(
+                "SV2 frames have no params, use `message` instead",
+            )),
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(_) => Err(serde::de::Error::custom(
+                "Ex-messages have no params, use `ex_message` instead",
+            )),
         }
     }
 
     pub fn get_id(&self) -> Result<ID, serde_json::Error> {
         match &self.values {
             Frame::V1(request) => Ok(request.id.clone()),
-            // MessageValue::ExMessage(_) => Err(serde::de::Error::custom(
-            //     "wrong message type request".to_string(),
-            // )),
+            #[cfg(feature = "v2")]
+            Frame::V2(_) => Ok(ID::null()),
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(_) => Ok(ID::null()),
         }
     }
+
+    /// Decodes the payload of an SV2 frame, e.g. `req.message::<v2::SubmitSharesStandard>()`.
+    #[cfg(feature = "v2")]
+    pub fn message<M: crate::v2::Message>(&self) -> Result<M, crate::v2::Error> {
+        match &self.values {
+            Frame::V2(frame) => frame.message(),
+            #[cfg(feature = "v1")]
+            Frame::V1(_) => Err(crate::v2::Error::NotV2Frame),
+            #[cfg(feature = "btcagent")]
+            Frame::ExMessage(_) => Err(crate::v2::Error::NotV2Frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Request;
+    use serde_json::json;
+
+    fn request(params: serde_json::Value) -> StratumRequest<()> {
+        StratumRequest {
+            state: (),
+            values: Frame::V1(Request {
+                id: ID::Num(1),
+                method: String::from("mining.authorize"),
+                params,
+            }),
+            global_vars: GlobalVars::new(1),
+        }
+    }
+
+    #[test]
+    fn get_json_returns_the_whole_params() {
+        let req = request(json!(["user.worker", "x"]));
+
+        let params: Vec<String> = req.get_json("username").unwrap();
+        assert_eq!(params, ["user.worker", "x"]);
+        assert!(req.param::<String>("username").is_err());
+    }
+
+    #[test]
+    fn param_looks_up_a_field() {
+        let req = request(json!({"username": "user.worker"}));
+
+        assert_eq!(req.param::<String>("username").unwrap(), "user.worker");
+        assert!(req.param::<String>("password").is_err());
+    }
 }
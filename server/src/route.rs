@@ -1,17 +1,24 @@
-use crate::{Session, StratumRequest};
+use crate::{ErrorCode, ErrorPolicy, Session, StratumError, StratumRequest};
 use async_trait::async_trait;
 use futures::Future;
-use tracing::error;
+use tracing::{debug, error};
 
 pub(crate) type DynEndpoint<State, CState> = dyn Endpoint<State, CState>;
 
+/// A registered endpoint along with how the Router should treat its return value.
+pub(crate) struct Route<State, CState> {
+    pub(crate) endpoint: Box<DynEndpoint<State, CState>>,
+    /// Whether the value returned by the endpoint is sent back to the miner as a response.
+    pub(crate) respond: bool,
+}
+
 #[async_trait]
 pub trait Endpoint<State: Clone, CState: Clone>: Send + Sync + 'static {
     async fn call(
         &self,
         req: StratumRequest<State>,
         connection: Session<CState>,
-    ) -> serde_json::Value;
+    ) -> std::result::Result<serde_json::Value, StratumError>;
 }
 
 #[async_trait]
@@ -28,23 +35,44 @@ where
         &self,
         req: StratumRequest<State>,
         connection: Session<CState>,
-    ) -> serde_json::Value {
+    ) -> std::result::Result<serde_json::Value, StratumError> {
         let fut = (self)(req, connection.clone());
 
-        match fut.await {
-            Ok(response) => response.into(),
-            Err(e) => {
+        into_result(fut.await, &connection)
+    }
+}
+
+/// Converts the value returned by an endpoint into the result the Router answers with.
+pub(crate) fn into_result<CState, Res, E>(
+    result: std::result::Result<Res, E>,
+    connection: &Session<CState>,
+) -> std::result::Result<serde_json::Value, StratumError>
+where
+    CState: Clone,
+    E: std::error::Error + 'static,
+    Res: Into<serde_json::Value>,
+{
+    match result {
+        Ok(response) => Ok(response.into()),
+        Err(e) => {
+            let error = StratumError::from_handler_error(&e);
+
+            if error.code() == ErrorCode::Other && error.policy() == ErrorPolicy::Disconnect {
                 error!(
                     connection_id = connection.id().to_string(),
                     error.cause_chain = ?e,
                     error.message = %e,
                     "Request failed disconnecting miner"
                 );
-
-                //@todo better response values here if we can.
-                connection.disconnect();
-                serde_json::Value::Null
+            } else {
+                debug!(
+                    connection_id = connection.id().to_string(),
+                    error.message = %e,
+                    "Request failed"
+                );
             }
+
+            Err(error)
         }
     }
 }
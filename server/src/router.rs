@@ -1,26 +1,100 @@
 use crate::{
-    route::{DynEndpoint, Endpoint},
+    frame::Response,
+    handler::Handler,
+    middleware::{DynMiddleware, Middleware, Next},
+    route::{Endpoint, Route},
     types::GlobalVars,
-    Frame, Session, StratumRequest,
+    ErrorPolicy, Frame, Session, StratumError, StratumRequest,
 };
-use std::collections::HashMap;
-use tracing::warn;
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, warn};
 
 pub struct Router<State, CState> {
-    routes: HashMap<String, Box<DynEndpoint<State, CState>>>,
+    routes: HashMap<String, Route<State, CState>>,
+    fallback: Route<State, CState>,
+    middleware: Vec<DynMiddleware<State, CState>>,
+    method_middleware: HashMap<String, Vec<DynMiddleware<State, CState>>>,
+}
+
+impl<State: Clone + Send + Sync + 'static, CState: Clone + Send + Sync + 'static> Default
+    for Router<State, CState>
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<State: Clone + Send + Sync + 'static, CState: Clone + Send + Sync + 'static>
     Router<State, CState>
 {
+    #[must_use]
     pub fn new() -> Router<State, CState> {
         Router {
             routes: HashMap::new(),
+            fallback: Route {
+                endpoint: Box::new(MethodNotFound),
+                respond: true,
+            },
+            middleware: Vec::new(),
+            method_middleware: HashMap::new(),
         }
     }
 
-    pub fn add(&mut self, method: &str, ep: impl Endpoint<State, CState>) {
-        self.routes.insert(method.to_owned(), Box::new(ep));
+    /// Adds a route whose return value is sent back to the miner as a response.
+    ///
+    /// **Note:** earlier releases discarded the return value. Handlers that still answer through
+    /// `Session::send` themselves must either stop doing so, or be registered with
+    /// `add_without_response`, otherwise the miner is answered twice.
+    pub fn add<T>(&mut self, method: &str, handler: impl Handler<State, CState, T>) {
+        self.insert(method, handler, true);
+    }
+
+    /// Adds a route whose return value is discarded. Use this for methods that must never be
+    /// answered, or for handlers that write their own responses through the Session.
+    pub fn add_without_response<T>(
+        &mut self,
+        method: &str,
+        handler: impl Handler<State, CState, T>,
+    ) {
+        self.insert(method, handler, false);
+    }
+
+    /// Sets the endpoint called for methods that have no route. By default the miner is answered
+    /// with a "Method not found" error and the Session's ban score is increased.
+    pub fn fallback<T>(&mut self, handler: impl Handler<State, CState, T>) {
+        self.fallback = Route {
+            endpoint: handler.into_endpoint(),
+            respond: true,
+        };
+    }
+
+    /// Wraps every route in `middleware`.
+    pub fn layer(&mut self, middleware: impl Middleware<State, CState>) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// Wraps only the routes for `methods` in `middleware`. The methods don't need to have been
+    /// added yet.
+    pub fn layer_for(&mut self, methods: &[&str], middleware: impl Middleware<State, CState>) {
+        let middleware: DynMiddleware<State, CState> = Arc::new(middleware);
+
+        for method in methods {
+            self.method_middleware
+                .entry((*method).to_owned())
+                .or_default()
+                .push(middleware.clone());
+        }
+    }
+
+    fn insert<T>(&mut self, method: &str, handler: impl Handler<State, CState, T>, respond: bool) {
+        self.routes.insert(
+            method.to_owned(),
+            Route {
+                endpoint: handler.into_endpoint(),
+                respond,
+            },
+        );
     }
 
     pub async fn call(
@@ -30,10 +104,7 @@ impl<State: Clone + Send + Sync + 'static, CState: Clone + Send + Sync + 'static
         connection: Session<CState>,
         global_vars: GlobalVars,
     ) {
-        let Some(endpoint) = self.routes.get(value.method()) else {
-            warn!("Method {} was not found", value.method());
-            return;
-        };
+        let route = self.routes.get(value.method()).unwrap_or(&self.fallback);
 
         // if log::log_enabled!(log::Level::Trace) {
         //@todo I think there is something really good we have going here, but needs to be
@@ -57,12 +128,73 @@ impl<State: Clone + Send + Sync + 'static, CState: Clone + Send + Sync + 'static
         // }
         // }
 
+        // Notifications (null or otherwise invalid id) are never answered, per JSON-RPC.
+        let id = (route.respond && !value.id().is_notification()).then(|| value.id().clone());
+
+        let method_middleware = self
+            .method_middleware
+            .get(value.method())
+            .map_or(&[][..], Vec::as_slice);
+
         let request = StratumRequest {
             state,
             values: value,
             global_vars,
         };
 
-        endpoint.call(request, connection).await;
+        let next = Next {
+            endpoint: route.endpoint.as_ref(),
+            global: &self.middleware,
+            method: method_middleware,
+        };
+
+        let (result, error) = match next.run(request, connection.clone()).await {
+            Ok(result) => (result, None),
+            Err(error) => (serde_json::Value::Null, Some(error)),
+        };
+
+        let policy = error.as_ref().map(StratumError::policy);
+
+        // The endpoint dropped the miner itself, nothing to answer.
+        if let Some(id) = id.filter(|_| !connection.is_disconnected()) {
+            let response = Response { id, result, error };
+
+            if let Err(e) = connection.send(response) {
+                error!(connection_id = %connection.id(), cause = %e, "Failed to send response");
+            }
+        }
+
+        match policy {
+            None | Some(ErrorPolicy::Keep) => {}
+            Some(ErrorPolicy::Disconnect) => connection.disconnect(),
+            Some(ErrorPolicy::Ban) => connection.ban(),
+            Some(ErrorPolicy::BanScore(score)) => connection.add_ban_score(score),
+        }
+    }
+}
+
+/// The default fallback, answers with a "Method not found" error.
+struct MethodNotFound;
+
+#[async_trait]
+impl<State, CState> Endpoint<State, CState> for MethodNotFound
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Clone + Send + Sync + 'static,
+{
+    async fn call(
+        &self,
+        req: StratumRequest<State>,
+        connection: Session<CState>,
+    ) -> std::result::Result<serde_json::Value, StratumError> {
+        warn!(
+            connection_id = %connection.id(),
+            "Method {} was not found",
+            req.method()
+        );
+
+        let score = connection.config_manager().unknown_method_ban_score();
+
+        Err(StratumError::method_not_found().with_policy(ErrorPolicy::BanScore(score)))
     }
 }
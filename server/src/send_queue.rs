@@ -0,0 +1,292 @@
+use crate::{config::OverflowPolicy, session::SendInformation, Error, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+
+/// The messages waiting to be written to a miner. It holds at most `capacity` messages, and what
+/// happens beyond that is decided by the `OverflowPolicy`, so that a miner that stops reading
+/// can't make the server buffer job notifications without bound.
+#[derive(Clone)]
+pub struct SendQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    /// Wakes the write loop when a message is queued or the queue is closed.
+    queued: Notify,
+    /// Wakes senders waiting for room when the write loop takes a message.
+    taken: Notify,
+    dropped: AtomicU64,
+}
+
+struct State {
+    messages: VecDeque<Queued>,
+    closed: bool,
+}
+
+struct Queued {
+    message: SendInformation,
+    /// Whether the message can be dropped under `OverflowPolicy::DropOldest`, i.e. it is a job
+    /// notification that a later one supersedes.
+    droppable: bool,
+}
+
+/// Send queue metrics over all sessions, see `SessionList::queue_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages waiting to be written, over all sessions.
+    pub queued: usize,
+    /// The most messages waiting for any one session.
+    pub deepest: usize,
+    /// Messages dropped under `OverflowPolicy::DropOldest` since the server started.
+    pub dropped: u64,
+}
+
+impl SendQueue {
+    pub(crate) fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        SendQueue {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    messages: VecDeque::new(),
+                    closed: false,
+                }),
+                capacity,
+                overflow,
+                queued: Notify::new(),
+                taken: Notify::new(),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub(crate) fn overflow(&self) -> OverflowPolicy {
+        self.inner.overflow
+    }
+
+    /// Queues `message`, or returns `Error::SendQueueFull` if there is no room for it under the
+    /// overflow policy. With `DropOldest` the oldest droppable message makes room, or `message`
+    /// itself is dropped if it is droppable and nothing queued is.
+    pub(crate) fn push(&self, message: SendInformation, droppable: bool) -> Result<()> {
+        let mut state = self.inner.state.lock();
+
+        if state.closed {
+            return Err(Error::SendQueueClosed);
+        }
+
+        if state.messages.len() >= self.inner.capacity {
+            if self.inner.overflow != OverflowPolicy::DropOldest {
+                return Err(Error::SendQueueFull);
+            }
+
+            match state.messages.iter().position(|queued| queued.droppable) {
+                Some(oldest) => {
+                    state.messages.remove(oldest);
+                }
+                None if droppable => {
+                    self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                None => return Err(Error::SendQueueFull),
+            }
+
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        state.messages.push_back(Queued { message, droppable });
+        drop(state);
+
+        self.inner.queued.notify_one();
+
+        Ok(())
+    }
+
+    /// Takes the next message to write, waiting for one if the queue is empty. Returns `None` once
+    /// the queue is closed and empty.
+    pub(crate) async fn pop(&self) -> Option<SendInformation> {
+        loop {
+            let queued = self.inner.queued.notified();
+
+            {
+                let mut state = self.inner.state.lock();
+                if let Some(queued) = state.messages.pop_front() {
+                    drop(state);
+                    self.inner.taken.notify_waiters();
+                    return Some(queued.message);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            queued.await;
+        }
+    }
+
+    /// Takes the next message if one is queued, without waiting.
+    pub(crate) fn try_pop(&self) -> Option<SendInformation> {
+        let queued = self.inner.state.lock().messages.pop_front()?;
+        self.inner.taken.notify_waiters();
+        Some(queued.message)
+    }
+
+    /// Waits until there is room for another message, or the queue is closed.
+    pub(crate) async fn writable(&self) {
+        loop {
+            let taken = self.inner.taken.notified();
+            tokio::pin!(taken);
+            taken.as_mut().enable();
+
+            {
+                let state = self.inner.state.lock();
+                if state.closed || state.messages.len() < self.inner.capacity {
+                    return;
+                }
+            }
+
+            taken.await;
+        }
+    }
+
+    /// Stops accepting messages. Anything already queued can still be taken.
+    pub(crate) fn close(&self) {
+        self.inner.state.lock().closed = true;
+        self.inner.queued.notify_one();
+        self.inner.taken.notify_waiters();
+    }
+
+    /// Drops everything queued.
+    #[cfg(feature = "upstream")]
+    pub(crate) fn clear(&self) {
+        self.inner.state.lock().messages.clear();
+        self.inner.taken.notify_waiters();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.state.lock().messages.len()
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(queue: &SendQueue, text: &str, droppable: bool) -> Result<()> {
+        queue.push(SendInformation::Text(text.to_string()), droppable)
+    }
+
+    async fn drain(queue: &SendQueue) -> Vec<String> {
+        queue.close();
+
+        let mut messages = Vec::new();
+        while let Some(message) = queue.pop().await {
+            messages.push(message.to_string());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_drops_notifications_first() {
+        let queue = SendQueue::new(2, OverflowPolicy::DropOldest);
+
+        text(&queue, "response", false).unwrap();
+        text(&queue, "notify 1", true).unwrap();
+        text(&queue, "notify 2", true).unwrap();
+        assert_eq!(queue.dropped(), 1);
+
+        // Nothing droppable is left, so a new notification is dropped instead.
+        let queue_of_responses = SendQueue::new(1, OverflowPolicy::DropOldest);
+        text(&queue_of_responses, "response", false).unwrap();
+        text(&queue_of_responses, "notify", true).unwrap();
+        assert!(matches!(
+            text(&queue_of_responses, "response", false),
+            Err(Error::SendQueueFull)
+        ));
+        assert_eq!(queue_of_responses.dropped(), 1);
+
+        assert_eq!(drain(&queue).await, ["response", "notify 2"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_and_backpressure_refuse_messages() {
+        for overflow in [OverflowPolicy::Disconnect, OverflowPolicy::Backpressure] {
+            let queue = SendQueue::new(1, overflow);
+
+            text(&queue, "notify 1", true).unwrap();
+            assert!(matches!(
+                text(&queue, "notify 2", true),
+                Err(Error::SendQueueFull)
+            ));
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue.dropped(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn writable_waits_for_room() {
+        let queue = SendQueue::new(1, OverflowPolicy::Backpressure);
+        text(&queue, "notify 1", true).unwrap();
+
+        let writer = queue.clone();
+        let sender = tokio::spawn(async move {
+            writer.writable().await;
+            text(&writer, "notify 2", true)
+        });
+
+        tokio::task::yield_now().await;
+        assert!(!sender.is_finished());
+
+        assert_eq!(queue.pop().await.unwrap().to_string(), "notify 1");
+        sender.await.unwrap().unwrap();
+        assert_eq!(drain(&queue).await, ["notify 2"]);
+    }
+
+    #[test]
+    fn sessions_disconnect_slow_miners() {
+        for (overflow, disconnected) in [
+            (OverflowPolicy::Disconnect, true),
+            (OverflowPolicy::Backpressure, false),
+        ] {
+            let session = crate::Session::new(
+                crate::types::ConnectionID::new(),
+                crate::SessionID::from(0),
+                std::net::SocketAddr::from(([127, 0, 0, 1], 0)),
+                SendQueue::new(1, overflow),
+                crate::ConfigManager::default(),
+                tokio_util::sync::CancellationToken::new(),
+                (),
+            )
+            .unwrap();
+
+            session.send("first").unwrap();
+            assert!(matches!(session.send("second"), Err(Error::SendQueueFull)));
+            assert_eq!(session.queue_depth(), 1);
+            assert_eq!(session.is_disconnected(), disconnected);
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_queues_refuse_messages() {
+        let queue = SendQueue::new(1, OverflowPolicy::DropOldest);
+        queue.close();
+
+        assert!(matches!(
+            text(&queue, "notify", true),
+            Err(Error::SendQueueClosed)
+        ));
+        assert!(queue.pop().await.is_none());
+    }
+}
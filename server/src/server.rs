@@ -1,24 +1,77 @@
 use crate::{
+    connection::{self, Stream, Transport},
+    connection_limiter::ConnectionLimiter,
     global::Global,
     id_manager::IDManager,
-    route::Endpoint,
     router::Router,
     tcp::Handler,
     types::{ConnectionID, GlobalVars, ReadyIndicator},
-    BanManager, ConfigManager, Connection, Result, SessionList, StratumServerBuilder,
+    BanManager, ConfigManager, Connection, Error, Result, SessionList, StratumServerBuilder,
 };
 use extended_primitives::Buffer;
-use futures::StreamExt;
+use futures::{stream::BoxStream, StreamExt};
 use rlimit::Resource;
-use std::{
-    net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+#[cfg(feature = "v1")]
+use std::time::Duration;
+use std::{fmt::Display, net::SocketAddr, sync::Arc, time::Instant};
+#[cfg(feature = "v1")]
+use tokio::io::AsyncWriteExt;
 use tokio::task::JoinSet;
-use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace};
+
+/// Streams accepted by a listener, along with the peer's address.
+pub(crate) type Incoming = BoxStream<'static, std::io::Result<(Box<dyn Stream>, SocketAddr)>>;
+
+/// A bound listener, along with the transport it serves and the settings of its sessions.
+pub(crate) struct Listener {
+    pub(crate) address: ListenAddress,
+    pub(crate) transport: Transport,
+    pub(crate) config_manager: ConfigManager,
+    /// Taken once the server starts accepting.
+    pub(crate) incoming: Option<Incoming>,
+    /// A duplicate of the listening socket, which stays open to be handed off.
+    #[cfg(unix)]
+    pub(crate) socket: std::os::fd::OwnedFd,
+}
+
+impl Listener {
+    /// The listener's name, or its transport's for the built in ones.
+    #[cfg(unix)]
+    pub(crate) fn name(&self) -> String {
+        match self.config_manager.listener() {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", self.transport).to_lowercase(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum ListenAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl ListenAddress {
+    fn socket(&self) -> Option<SocketAddr> {
+        match self {
+            ListenAddress::Tcp(address) => Some(*address),
+            #[cfg(unix)]
+            ListenAddress::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "{address}"),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
 
 pub struct StratumServer<State, CState>
 where
@@ -26,20 +79,34 @@ where
     CState: Default + Clone,
 {
     pub(crate) id: u8,
-    pub(crate) listen_address: SocketAddr,
-    pub(crate) listener: TcpListenerStream,
+    /// Every bound listener, starting with the main one.
+    pub(crate) listeners: Vec<Listener>,
     pub(crate) state: State,
     pub(crate) session_list: SessionList<CState>,
     pub(crate) ban_manager: BanManager,
-    pub(crate) config_manager: ConfigManager,
+    pub(crate) connection_limiter: ConnectionLimiter,
     pub(crate) router: Arc<Router<State, CState>>,
+    #[cfg(feature = "upstream")]
+    pub(crate) upstream: Option<crate::upstream::Upstream>,
+    #[cfg(feature = "upstream")]
+    pub(crate) upstream_router: Arc<Router<State, CState>>,
     pub(crate) session_id_manager: IDManager,
     pub(crate) cancel_token: CancellationToken,
+    /// Cancelled to stop accepting connections while the sessions drain, e.g. after a handoff.
+    pub(crate) stop_accepting: CancellationToken,
+    #[cfg(unix)]
+    pub(crate) handoff: Option<crate::Handoff>,
+    pub(crate) drain: crate::Drain,
+    #[cfg(feature = "v1")]
+    pub(crate) drain_config: Option<crate::DrainConfig<CState>>,
     pub(crate) global_thread_list: JoinSet<()>,
     pub(crate) ready_indicator: ReadyIndicator,
     pub(crate) shutdown_message: Option<Buffer>,
     #[cfg(feature = "api")]
     pub(crate) api: crate::api::Api,
+    /// Stops the API, on shutdown or once a handoff has passed its socket on.
+    #[cfg(feature = "api")]
+    pub(crate) api_cancel_token: CancellationToken,
 }
 
 impl<State, CState> StratumServer<State, CState>
@@ -51,10 +118,58 @@ where
         StratumServerBuilder::new(state, server_id)
     }
 
-    pub fn add(&mut self, method: &str, ep: impl Endpoint<State, CState>) {
+    /// Adds a route whose return value is sent back to the miner as a response, see
+    /// `Router::add` for handlers that still answer through `Session::send` themselves.
+    pub fn add<T>(&mut self, method: &str, handler: impl crate::Handler<State, CState, T>) {
+        let router = Arc::get_mut(&mut self.router)
+            .expect("Registering routes is not possible after the Server has started");
+        router.add(method, handler);
+    }
+
+    /// Adds a route whose return value is discarded.
+    pub fn add_without_response<T>(
+        &mut self,
+        method: &str,
+        handler: impl crate::Handler<State, CState, T>,
+    ) {
         let router = Arc::get_mut(&mut self.router)
             .expect("Registering routes is not possible after the Server has started");
-        router.add(method, ep);
+        router.add_without_response(method, handler);
+    }
+
+    pub fn fallback<T>(&mut self, handler: impl crate::Handler<State, CState, T>) {
+        let router = Arc::get_mut(&mut self.router)
+            .expect("Registering routes is not possible after the Server has started");
+        router.fallback(handler);
+    }
+
+    /// Adds a route for messages sent by the upstream pool, see
+    /// `StratumServerBuilder::with_upstream`.
+    #[cfg(feature = "upstream")]
+    pub fn add_upstream<T>(
+        &mut self,
+        method: &str,
+        handler: impl crate::Handler<State, CState, T>,
+    ) {
+        let router = Arc::get_mut(&mut self.upstream_router)
+            .expect("Registering routes is not possible after the Server has started");
+        router.add(method, handler);
+    }
+
+    pub fn layer(&mut self, middleware: impl crate::middleware::Middleware<State, CState>) {
+        let router = Arc::get_mut(&mut self.router)
+            .expect("Registering middleware is not possible after the Server has started");
+        router.layer(middleware);
+    }
+
+    pub fn layer_for(
+        &mut self,
+        methods: &[&str],
+        middleware: impl crate::middleware::Middleware<State, CState>,
+    ) {
+        let router = Arc::get_mut(&mut self.router)
+            .expect("Registering middleware is not possible after the Server has started");
+        router.layer_for(methods, middleware);
     }
 
     pub fn global(&mut self, global_name: &str, ep: impl Global<State, CState>) {
@@ -80,100 +195,208 @@ where
     }
 
     async fn handle_incoming(&mut self) -> Result<()> {
-        info!("Listening on {}", &self.listen_address);
+        for listener in &self.listeners {
+            if let Some(name) = listener.config_manager.listener() {
+                info!("Listening for {} on {}", name, listener.address);
+            } else {
+                info!(
+                    "Listening for {:?} on {}",
+                    listener.transport, listener.address
+                );
+            }
+        }
 
-        while let Some(stream) = self.listener.next().await {
-            let stream = match stream {
-                Ok(stream) => stream,
-                Err(e) => {
-                    error!(cause = ?e, "Unable to access stream");
-                    continue;
-                }
-            };
+        let acceptor = Acceptor {
+            ban_manager: self.ban_manager.clone(),
+            connection_limiter: self.connection_limiter.clone(),
+            id_manager: self.session_id_manager.clone(),
+            session_list: self.session_list.clone(),
+            router: self.router.clone(),
+            state: self.state.clone(),
+            global_vars: self.global_vars(),
+            cancel_token: self.cancel_token.clone(),
+        };
+
+        // Each listener is accepted on its own task, so that acceptors sharing a port can run on
+        // separate cores.
+        let mut accept_loops = JoinSet::new();
+        for listener in &mut self.listeners {
+            if let Some(incoming) = listener.incoming.take() {
+                accept_loops.spawn(acceptor.clone().run(
+                    incoming,
+                    listener.transport,
+                    listener.config_manager.clone(),
+                ));
+            }
+        }
 
-            let id = ConnectionID::new();
-            let child_token = self.get_cancel_token();
+        while let Some(result) = accept_loops.join_next().await {
+            if let Err(e) = result {
+                error!(cause = ?e, "Accept loop failed");
+            }
+        }
 
-            trace!(
-                id = ?id,
-                ip = &stream.peer_addr()?.to_string(),
-                "Connection initialized",
-            );
+        Ok(())
+    }
 
-            let connection = match Connection::new(id.clone(), stream, child_token.clone()) {
-                Ok(connection) => connection,
-                Err(e) => {
-                    error!(id = ?id, cause = ?e, "Failed while constructing Connection");
-                    continue;
-                }
-            };
+    fn global_vars(&self) -> GlobalVars {
+        #[allow(unused_mut)]
+        let mut global_vars = GlobalVars::new(self.id);
 
-            let handler = Handler {
-                id: id.clone(),
-                ban_manager: self.ban_manager.clone(),
-                id_manager: self.session_id_manager.clone(),
-                session_list: self.session_list.clone(),
-                router: self.router.clone(),
-                state: self.state.clone(),
-                connection_state: CState::default(),
-                config_manager: self.config_manager.clone(),
-                cancel_token: child_token,
-                global_vars: GlobalVars::new(self.id),
-                connection,
-            };
+        #[cfg(feature = "upstream")]
+        {
+            global_vars.upstream.clone_from(&self.upstream);
+        }
 
-            tokio::spawn(async move {
-                if let Err(err) = handler.run().await {
-                    error!(id =?id, cause = ?err, "connection error");
+        global_vars
+    }
+
+    /// Hands off on `SIGUSR2` or `Handoff::trigger`, then stops accepting.
+    #[cfg(unix)]
+    fn watch_handoff(&mut self) -> Result<()> {
+        let Some(handoff) = self.handoff.clone() else {
+            return Ok(());
+        };
+
+        let cancel_token = self.cancel_token.clone();
+        let stop_accepting = self.stop_accepting.clone();
+        #[cfg(feature = "api")]
+        let api_cancel_token = self.api_cancel_token.clone();
+        let mut signal =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())?;
+
+        self.global_thread_list.spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(()) = signal.recv() => {}
+                    () = handoff.requested() => {}
+                    () = cancel_token.cancelled() => break,
                 }
-            });
-        }
+
+                // Nothing is handed over until the replacement has started, so a failed handoff
+                // leaves this server as it was.
+                match handoff.spawn() {
+                    Ok(_) => {
+                        stop_accepting.cancel();
+                        // The replacement serves the API on the socket it was handed.
+                        #[cfg(feature = "api")]
+                        api_cancel_token.cancel();
+                        break;
+                    }
+                    Err(e) => error!(cause = %e, "Unable to hand off the listening sockets."),
+                }
+            }
+        });
 
         Ok(())
     }
 
+    /// Redirects each session on a drain with `with_drain`, or sends them the shutdown message.
+    #[cfg_attr(not(feature = "v1"), allow(unused_variables, clippy::unused_async))]
+    async fn send_off_sessions(&mut self, draining: bool) -> Result<()> {
+        #[cfg(feature = "v1")]
+        if let Some(config) = self.drain_config.as_ref().filter(|_| draining) {
+            self.drain.redirect(&self.session_list, config).await;
+            return Ok(());
+        }
+
+        self.session_list
+            .shutdown_msg(self.shutdown_message.clone())
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         init()?;
 
         let cancel_token = self.cancel_token.clone();
 
+        #[cfg(feature = "upstream")]
+        if let Some(upstream) = self.upstream.clone() {
+            let router = self.upstream_router.clone();
+            let state = self.state.clone();
+            let global_vars = self.global_vars();
+
+            self.global_thread_list.spawn(async move {
+                if let Err(e) = upstream
+                    .run(router, state, CState::default(), global_vars)
+                    .await
+                {
+                    error!(cause = ?e, "Upstream connection failed.");
+                }
+            });
+        }
+
+        #[cfg(all(feature = "tls", unix))]
+        if let Some(tls) = self.listeners[0].config_manager.tls().cloned() {
+            let cancel_token = cancel_token.clone();
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+            self.global_thread_list.spawn(async move {
+                loop {
+                    tokio::select! {
+                        Some(()) = hangup.recv() => {
+                            if let Err(e) = tls.reload() {
+                                error!(cause = %e, "Unable to reload the TLS certificate.");
+                            }
+                        }
+                        () = cancel_token.cancelled() => break,
+                    }
+                }
+            });
+        }
+
+        #[cfg(unix)]
+        self.watch_handoff()?;
+
         #[cfg(feature = "api")]
-        let api_handle = self.api.run(cancel_token.clone())?;
+        let api_handle = self.api.run(self.api_cancel_token.clone())?;
 
-        tokio::select! {
+        let stop_accepting = self.stop_accepting.clone();
+        let drain = self.drain.clone();
+        let draining = tokio::select! {
             res = self.handle_incoming() => {
                 if let Err(err) = res {
                     error!(cause = %err, "failed to accept");
-                };
+                }
+                false
             },
-            () = cancel_token.cancelled() => {}
-        }
+            () = cancel_token.cancelled() => false,
+            () = stop_accepting.cancelled() => {
+                info!("Stopped accepting connections, draining sessions");
+                false
+            }
+            () = drain.requested() => true,
+        };
 
         let start = Instant::now();
 
         //Session Shutdowns
         {
-            self.session_list
-                .shutdown_msg(self.shutdown_message.clone())?;
+            if draining {
+                // Accepting already stopped with the select above, the token is for anything else
+                // waiting on it.
+                self.session_list.not_ready();
+                self.stop_accepting.cancel();
+                self.drain.start(&self.session_list);
+            }
 
-            let mut backoff = 1;
-            loop {
-                let connected_miners = self.session_list.len();
-                if connected_miners == 0 {
-                    break;
-                }
+            self.send_off_sessions(draining).await?;
 
-                if backoff > 64 {
-                    warn!("{connected_miners} remaining, force shutting down now");
-                    self.session_list.shutdown();
-                    break;
-                }
+            let timeout = self.listeners[0]
+                .config_manager
+                .connection_config()
+                .shutdown_timeout;
+            self.drain.wait(&self.session_list, timeout).await;
+        }
 
-                info!("Waiting for all miners to disconnect, {connected_miners} remaining");
-                tokio::time::sleep(Duration::from_secs(backoff)).await;
+        // Stops the global threads and the API, which is not done yet when the server only
+        // stopped accepting for a handoff.
+        cancel_token.cancel();
 
-                backoff *= 2;
-            }
+        // The upstream stays connected until the miners are gone, as they may still be using it.
+        #[cfg(feature = "upstream")]
+        if let Some(upstream) = &self.upstream {
+            upstream.shutdown();
         }
 
         info!("Awaiting for all current globals to complete");
@@ -196,6 +419,11 @@ where
         Ok(())
     }
 
+    /// Triggers a drain, and reports its progress.
+    pub fn get_drain(&self) -> crate::Drain {
+        self.drain.clone()
+    }
+
     pub fn get_ready_indicator(&self) -> ReadyIndicator {
         self.ready_indicator.create_new()
     }
@@ -210,7 +438,80 @@ where
     }
 
     pub fn get_address(&self) -> SocketAddr {
-        self.listen_address
+        self.listeners[0]
+            .address
+            .socket()
+            .expect("The main listener is always bound to TCP")
+    }
+
+    /// The address of the SV2 listener, if one was configured with `with_v2`.
+    #[cfg(feature = "v2")]
+    pub fn get_v2_address(&self) -> Option<SocketAddr> {
+        self.transport_address(Transport::V2)
+    }
+
+    /// The address of the TLS listener, if one was configured with `with_tls`.
+    #[cfg(feature = "tls")]
+    pub fn get_tls_address(&self) -> Option<SocketAddr> {
+        self.transport_address(Transport::Tls)
+    }
+
+    /// The address of the WebSocket listener, if one was configured with `with_websocket`.
+    #[cfg(feature = "websocket")]
+    pub fn get_websocket_address(&self) -> Option<SocketAddr> {
+        self.transport_address(Transport::WebSocket)
+    }
+
+    #[cfg(any(feature = "v2", feature = "tls", feature = "websocket"))]
+    fn transport_address(&self, transport: Transport) -> Option<SocketAddr> {
+        self.listeners
+            .iter()
+            .find(|listener| listener.transport == transport)
+            .and_then(|listener| listener.address.socket())
+    }
+
+    /// The address of the listener added with `with_listener` under `name`.
+    pub fn get_listener_address(&self, name: &str) -> Option<SocketAddr> {
+        self.named_listener(name)
+            .and_then(|listener| listener.address.socket())
+    }
+
+    /// The path of the Unix socket listener added with `with_listener` under `name`.
+    #[cfg(unix)]
+    pub fn get_listener_path(&self, name: &str) -> Option<std::path::PathBuf> {
+        match &self.named_listener(name)?.address {
+            ListenAddress::Unix(path) => Some(path.clone()),
+            ListenAddress::Tcp(_) => None,
+        }
+    }
+
+    fn named_listener(&self, name: &str) -> Option<&Listener> {
+        self.listeners
+            .iter()
+            .find(|listener| listener.config_manager.listener() == Some(name))
+    }
+
+    /// Reloads the TLS certificate and key from disk. New connections use them straight away,
+    /// while existing ones are unaffected.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self) -> Result<()> {
+        self.listeners[0]
+            .config_manager
+            .tls()
+            .ok_or(crate::Error::TlsNotEnabled)?
+            .reload()
+    }
+
+    /// The server wide connection to a parent pool, if one was configured with `with_upstream`.
+    #[cfg(feature = "upstream")]
+    pub fn get_upstream(&self) -> Option<crate::upstream::Upstream> {
+        self.upstream.clone()
+    }
+
+    /// Triggers handoffs to the replacement configured with `with_handoff`.
+    #[cfg(unix)]
+    pub fn get_handoff(&self) -> Option<crate::Handoff> {
+        self.handoff.clone()
     }
 
     pub fn get_ban_manager(&self) -> BanManager {
@@ -223,6 +524,107 @@ where
     }
 }
 
+/// What an accept loop needs to hand connections to `Handler`s, so it can run on its own task.
+#[derive(Clone)]
+struct Acceptor<State, CState>
+where
+    CState: Clone,
+{
+    ban_manager: BanManager,
+    connection_limiter: ConnectionLimiter,
+    id_manager: IDManager,
+    session_list: SessionList<CState>,
+    router: Arc<Router<State, CState>>,
+    state: State,
+    global_vars: GlobalVars,
+    cancel_token: CancellationToken,
+}
+
+impl<State, CState> Acceptor<State, CState>
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Default + Clone + Send + Sync + 'static,
+{
+    async fn run(
+        self,
+        mut incoming: Incoming,
+        transport: Transport,
+        config_manager: ConfigManager,
+    ) {
+        while let Some(stream) = incoming.next().await {
+            let (stream, address) = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!(cause = ?e, "Unable to access stream");
+                    continue;
+                }
+            };
+
+            let Some(slot) = self.session_list.accept() else {
+                reject(stream, address, transport, &config_manager);
+                continue;
+            };
+
+            // Behind a load balancer every connection comes from it, so only the PROXY header
+            // says who the miner is, and the handler limits it once that is read. Unix socket
+            // peers have no address to limit without one.
+            let permit = if config_manager.proxy_protocol() || connection::is_unix_peer(address) {
+                None
+            } else {
+                match self
+                    .connection_limiter
+                    .acquire(address.ip(), &config_manager)
+                {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        debug!(ip = address.ip().to_string(), "Refusing connection: {}", e);
+                        continue;
+                    }
+                }
+            };
+
+            let id = ConnectionID::new();
+            let child_token = self.cancel_token.child_token();
+
+            trace!(
+                id = ?id,
+                ip = &address.to_string(),
+                "Connection initialized",
+            );
+
+            let connection = Connection::new(id.clone(), stream, address, child_token.clone());
+
+            let handler = Handler {
+                id: id.clone(),
+                ban_manager: self.ban_manager.clone(),
+                id_manager: self.id_manager.clone(),
+                session_list: self.session_list.clone(),
+                router: self.router.clone(),
+                state: self.state.clone(),
+                connection_state: CState::default(),
+                config_manager: config_manager.clone(),
+                connection_limiter: self.connection_limiter.clone(),
+                cancel_token: child_token,
+                global_vars: self.global_vars.clone(),
+                connection,
+                transport,
+            };
+
+            tokio::spawn(async move {
+                match handler.run().await {
+                    Err(err @ (Error::TooManyConnections(_) | Error::ConnectionRateLimited(_))) => {
+                        debug!(id = ?id, cause = %err, "Refusing connection");
+                    }
+                    Err(err) => error!(id =?id, cause = ?err, "connection error"),
+                    Ok(()) => {}
+                }
+                drop(permit);
+                drop(slot);
+            });
+        }
+    }
+}
+
 fn init() -> Result<()> {
     info!("Initializing...");
 
@@ -237,6 +639,48 @@ fn init() -> Result<()> {
 }
 
 //@todo
+/// Turns away a connection made while the server is full, first pointing plain Stratum miners at
+/// the overflow host if one is set.
+#[cfg_attr(not(feature = "v1"), allow(unused_mut, unused_variables))]
+fn reject(
+    mut stream: Box<dyn Stream>,
+    address: SocketAddr,
+    transport: Transport,
+    config_manager: &ConfigManager,
+) {
+    debug!(
+        ip = &address.to_string(),
+        "Server is full, rejecting connection"
+    );
+
+    #[cfg(feature = "v1")]
+    if let (Transport::Stratum, Some(reconnect)) = (
+        transport,
+        &config_manager.connection_config().overflow_reconnect,
+    ) {
+        // Behind a load balancer the stream starts with a PROXY header, which is not read here.
+        let mut message = match serde_json::to_vec(&crate::v1::Notification::new(reconnect)) {
+            Ok(message) => message,
+            Err(e) => {
+                error!(cause = ?e, "Unable to encode overflow reconnect");
+                return;
+            }
+        };
+        message.push(b'\n');
+
+        tokio::spawn(async move {
+            let write = tokio::time::timeout(Duration::from_secs(1), async {
+                stream.write_all(&message).await?;
+                stream.shutdown().await
+            });
+
+            if let Ok(Err(e)) = write.await {
+                trace!(ip = &address.to_string(), cause = ?e, "Unable to send overflow reconnect");
+            }
+        });
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //
@@ -1,20 +1,25 @@
 use crate::{
-    config::ConfigManager,
+    ban_manager::Key,
+    config::{Budget, ConfigManager, OverflowPolicy},
+    message_limiter::Bucket,
+    proxy_protocol::ProxyHeader,
+    send_queue::SendQueue,
     types::{ConnectionID, Difficulties, Difficulty, DifficultySettings},
-    Miner, MinerList, Result, SessionID,
+    Error, Miner, MinerList, Result, SessionID,
 };
+use bytes::{BufMut, Bytes, BytesMut};
 use extended_primitives::Buffer;
 use parking_lot::{Mutex, RwLock};
 use serde::Serialize;
 use std::{
+    collections::HashMap,
     fmt::Display,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant, SystemTime},
 };
-use tokio::sync::mpsc::UnboundedSender;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
 //@todo remove this excessive_bools
@@ -58,22 +63,45 @@ pub enum SessionState {
 
 #[derive(Debug)]
 pub enum SendInformation {
-    Json(String),
+    /// An encoded JSON message, newline included.
+    Json(Bytes),
     Text(String),
     Raw(Buffer),
+    /// A plaintext SV2 frame, encrypted by the write loop before it is sent.
+    #[cfg(feature = "v2")]
+    V2(Vec<u8>),
+}
+
+impl SendInformation {
+    /// Serializes `message` straight into the buffer that is queued, rather than going through a
+    /// `String` first.
+    pub(crate) fn json<T: Serialize>(message: &T) -> Result<Self> {
+        let mut writer = BytesMut::new().writer();
+        serde_json::to_writer(&mut writer, message)?;
+
+        let mut json = writer.into_inner();
+        json.put_u8(b'\n');
+
+        Ok(SendInformation::Json(json.freeze()))
+    }
 }
 
 impl Display for SendInformation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SendInformation::Json(s) => {
-                write!(f, "{}", s)
+            SendInformation::Json(json) => {
+                let json = json.strip_suffix(b"\n").unwrap_or(json);
+                write!(f, "{}", String::from_utf8_lossy(json))
             }
             SendInformation::Text(s) => {
-                write!(f, "{}", s)
+                write!(f, "{s}")
             }
             SendInformation::Raw(b) => {
-                write!(f, "{}", b)
+                write!(f, "{b}")
+            }
+            #[cfg(feature = "v2")]
+            SendInformation::V2(frame) => {
+                write!(f, "SV2 frame of {} bytes", frame.len())
             }
         }
     }
@@ -107,12 +135,16 @@ struct Inner<State> {
 pub(crate) struct Shared {
     //@todo possibly turn this into an Atomic
     status: SessionState,
-    //@todo change this (But Later)
-    sender: UnboundedSender<SendInformation>,
+    sender: SendQueue,
     needs_ban: bool,
+    ban_ip: bool,
+    ban_score: u64,
     last_active: Instant,
     //@todo wrap this in a RwLock I believe
     info: SessionInfo,
+    proxy_header: Option<ProxyHeader>,
+    /// Buckets of the `RateLimit` middleware, by limiter and method.
+    rate_limits: HashMap<(usize, String), Bucket>,
 }
 
 impl<State: Clone> Session<State> {
@@ -120,7 +152,7 @@ impl<State: Clone> Session<State> {
         id: ConnectionID,
         session_id: SessionID,
         ip: SocketAddr,
-        sender: UnboundedSender<SendInformation>,
+        sender: SendQueue,
         config_manager: ConfigManager,
         cancel_token: CancellationToken,
         state: State,
@@ -131,8 +163,12 @@ impl<State: Clone> Session<State> {
             status: SessionState::Connected,
             last_active: Instant::now(),
             needs_ban: false,
+            ban_ip: false,
+            ban_score: 0,
             sender,
             info: SessionInfo::new(),
+            proxy_header: None,
+            rate_limits: HashMap::new(),
         };
 
         let inner = Inner {
@@ -161,6 +197,12 @@ impl<State: Clone> Session<State> {
     }
 
     pub fn send<T: Serialize>(&self, message: T) -> Result<()> {
+        self.send_json(message, false)
+    }
+
+    /// Sends `message`, which may be dropped if the miner falls behind and
+    /// `OverflowPolicy::DropOldest` is in use.
+    fn send_json<T: Serialize>(&self, message: T, droppable: bool) -> Result<()> {
         let shared = self.shared.lock();
 
         if shared.last_active.elapsed()
@@ -183,24 +225,58 @@ impl<State: Clone> Session<State> {
             return Ok(());
         }
 
-        let msg = SendInformation::Json(serde_json::to_string(&message)?);
+        let msg = SendInformation::json(&message)?;
 
         debug!("Sending message: {}", msg);
 
-        //@todo it may make sense to keep the sender inside of session here - not sure why it's in
-        //connection like the way it is.
-        //@todo this feels inefficient, maybe we do send bytes here.
-        shared.sender.send(msg)?;
+        let sender = shared.sender.clone();
+        drop(shared);
 
-        Ok(())
+        self.queue(&sender, msg, droppable)
     }
 
     pub fn send_raw(&self, message: Buffer) -> Result<()> {
-        let shared = self.shared.lock();
+        let sender = self.shared.lock().sender.clone();
+
+        self.queue(&sender, SendInformation::Raw(message), false)
+    }
 
-        shared.sender.send(SendInformation::Raw(message))?;
+    /// Queues `message`, disconnecting the miner if it has fallen too far behind, unless the
+    /// overflow policy leaves that to the sender.
+    fn queue(&self, sender: &SendQueue, message: SendInformation, droppable: bool) -> Result<()> {
+        let result = sender.push(message, droppable);
+
+        if matches!(result, Err(Error::SendQueueFull))
+            && sender.overflow() != OverflowPolicy::Backpressure
+        {
+            warn!(
+                id = ?self.inner.id,
+                ip = &self.inner.ip.to_string(),
+                "Send queue full, disconnecting slow miner",
+            );
+            self.shutdown();
+        }
+
+        result
+    }
+
+    /// Waits until the miner's send queue has room for another message. With
+    /// `OverflowPolicy::Backpressure`, call this after a send fails with `Error::SendQueueFull`.
+    pub async fn writable(&self) {
+        let sender = self.shared.lock().sender.clone();
+        sender.writable().await;
+    }
+
+    /// The number of messages waiting to be written to the miner.
+    #[must_use]
+    pub fn queue_depth(&self) -> usize {
+        self.shared.lock().sender.len()
+    }
 
-        Ok(())
+    /// The number of messages dropped because the miner fell behind.
+    #[must_use]
+    pub fn dropped_messages(&self) -> u64 {
+        self.shared.lock().sender.dropped()
     }
 
     pub fn shutdown(&self) {
@@ -220,11 +296,71 @@ impl<State: Clone> Session<State> {
         self.shutdown();
     }
 
+    /// Bans the Session's IP rather than its address, so the miner can't come straight back from
+    /// another port.
+    pub fn ban_ip(&self) {
+        self.shared.lock().ban_ip = true;
+        self.ban();
+    }
+
     #[must_use]
     pub fn needs_ban(&self) -> bool {
         self.shared.lock().needs_ban
     }
 
+    /// Takes a request for `method` from this Session's bucket of the `RateLimit` middleware
+    /// `limiter`, returning whether one was left.
+    pub(crate) fn take_rate_limit(&self, limiter: usize, method: &str, budget: Budget) -> bool {
+        let now = tokio::time::Instant::now();
+        let mut shared = self.shared.lock();
+
+        if let Some(bucket) = shared.rate_limits.get_mut(&(limiter, method.to_owned())) {
+            return bucket.take(budget, now);
+        }
+
+        let mut bucket = Bucket::new(Some(budget), now);
+        let allowed = bucket.take(budget, now);
+        shared
+            .rate_limits
+            .insert((limiter, method.to_owned()), bucket);
+
+        allowed
+    }
+
+    /// What to ban once the Session has closed, if it needs a ban.
+    pub(crate) fn ban_key(&self) -> Option<Key> {
+        let shared = self.shared.lock();
+        if !shared.needs_ban {
+            return None;
+        }
+
+        Some(if shared.ban_ip {
+            Key::IP(self.inner.ip.ip())
+        } else {
+            Key::Socket(self.inner.ip)
+        })
+    }
+
+    /// Adds to this Session's ban score, banning it once the configured allowance is reached.
+    pub fn add_ban_score(&self, score: u64) {
+        let mut shared = self.shared.lock();
+        shared.ban_score = shared.ban_score.saturating_add(score);
+
+        if shared.ban_score >= self.config_manager.ban_score_allowed() {
+            drop(shared);
+            self.ban();
+        }
+    }
+
+    #[must_use]
+    pub fn ban_score(&self) -> u64 {
+        self.shared.lock().ban_score
+    }
+
+    pub(crate) fn config_manager(&self) -> &ConfigManager {
+        &self.config_manager
+    }
+
     #[must_use]
     pub fn id(&self) -> &ConnectionID {
         &self.inner.id
@@ -295,6 +431,23 @@ impl<State: Clone> Session<State> {
         shared.info.is_long_timeout = long_timeout;
     }
 
+    pub(crate) fn set_proxy_header(&self, header: ProxyHeader) {
+        self.shared.lock().proxy_header = Some(header);
+    }
+
+    /// The PROXY protocol header the connection started with, if the server expects one.
+    #[must_use]
+    pub fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.shared.lock().proxy_header.clone()
+    }
+
+    /// The name of the listener added with `with_listener` that accepted this session, or `None`
+    /// for the server's own listeners.
+    #[must_use]
+    pub fn listener(&self) -> Option<&str> {
+        self.config_manager.listener()
+    }
+
     #[must_use]
     pub fn get_connection_info(&self) -> SessionInfo {
         self.shared.lock().info.clone()
@@ -408,7 +561,77 @@ impl<State: Clone> Session<State> {
     // }
 }
 
-#[cfg(feature = "test-utils")]
+#[cfg(feature = "v1")]
 impl<State: Clone> Session<State> {
-    pub fn mock(state: State) -> Session<State> {}
+    /// Sends a server initiated Stratum V1 message, e.g. `mining.notify`.
+    pub fn send_notification<T: crate::v1::Method + Serialize>(&self, params: &T) -> Result<()> {
+        self.send(crate::v1::Notification::new(params))
+    }
+
+    /// Sends a job. Unlike other messages, queued jobs are dropped first when the miner falls
+    /// behind, see `OverflowPolicy::DropOldest`.
+    pub fn send_notify(&self, notify: &crate::v1::Notify) -> Result<()> {
+        self.send_json(crate::v1::Notification::new(notify), true)
+    }
+
+    /// Sends `mining.set_difficulty`. Takes an `f64` as miners accept fractional difficulties,
+    /// e.g. `0.5`, which a `Difficulty` can't hold.
+    pub fn send_set_difficulty(&self, difficulty: f64) -> Result<()> {
+        self.send_notification(&crate::v1::SetDifficulty { difficulty })
+    }
+
+    pub fn send_set_extranonce(&self, extranonce1: &str, extranonce2_size: usize) -> Result<()> {
+        self.send_notification(&crate::v1::SetExtranonce {
+            extranonce1: extranonce1.to_owned(),
+            extranonce2_size,
+        })
+    }
+
+    pub fn send_reconnect(&self, reconnect: &crate::v1::Reconnect) -> Result<()> {
+        self.send_notification(reconnect)
+    }
+
+    pub fn send_show_message(&self, message: &str) -> Result<()> {
+        self.send_notification(&crate::v1::ShowMessage {
+            message: message.to_owned(),
+        })
+    }
+}
+
+#[cfg(feature = "v2")]
+impl<State: Clone> Session<State> {
+    /// Sends an SV2 message. Only SV2 connections deliver these, V1 connections drop them.
+    pub fn send_v2<M: crate::v2::Message>(&self, message: &M) -> Result<()> {
+        let frame = crate::v2::Frame::from_message(message)?;
+        let sender = self.shared.lock().sender.clone();
+
+        self.queue(&sender, SendInformation::V2(frame.encode()), false)
+    }
+}
+
+#[cfg(feature = "btcagent")]
+impl<State: Clone> Session<State> {
+    /// Sends a BTC Agent ex-message, e.g. a `MiningSetDiff` for some of the agent's workers.
+    pub fn send_ex_message(&self, message: &crate::btcagent::ExMessage) -> Result<()> {
+        self.send_raw(Buffer::from(message.encode()?))
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl<State: Clone> Session<State> {
+    pub fn mock(state: State) -> Session<State> {
+        let config = crate::ConnectionConfig::default();
+        let sender = SendQueue::new(config.send_queue_capacity, config.send_queue_overflow);
+
+        Session::new(
+            ConnectionID::new(),
+            SessionID::from(0),
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            sender,
+            ConfigManager::default(),
+            CancellationToken::new(),
+            state,
+        )
+        .expect("Mock session construction is infallible")
+    }
 }
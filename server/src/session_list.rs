@@ -1,7 +1,14 @@
-use crate::{session::Session, ConfigManager, Result};
+use crate::{session::Session, ConfigManager, QueueStats, ReadyIndicator, Result};
 use dashmap::DashMap;
 use extended_primitives::Buffer;
-use std::{net::SocketAddr, sync::Arc};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tracing::{info, warn};
 
 //@todo performance test using a Sephamore for this similar to how Tokio does it in mini-redis
@@ -16,11 +23,58 @@ use tracing::{info, warn};
 pub struct SessionList<CState: Clone> {
     inner: Arc<Inner<CState>>,
     pub(crate) config_manager: ConfigManager,
+    /// Flipped to not-ready while the server is near `max_connections`.
+    ready_indicator: ReadyIndicator,
+}
+
+/// Connection counts, as served by the API's `/connections`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    /// Connections accepted and not yet closed, including those still in their handshakes.
+    pub open: usize,
+    /// Connections turned away because the server was at `max_connections`.
+    pub rejected: u64,
+}
+
+/// Counts connections from the moment they are accepted, so that those still in their
+/// handshakes are held against `max_connections` too.
+#[derive(Default, Debug)]
+pub(crate) struct ConnectionCounter {
+    open: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+impl ConnectionCounter {
+    fn open(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            open: self.open(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An accepted connection, counted until its handler exits and this is dropped.
+pub(crate) struct ConnectionSlot<CState: Clone>(SessionList<CState>);
+
+impl<CState: Clone> Drop for ConnectionSlot<CState> {
+    fn drop(&mut self) {
+        self.0.inner.connections.open.fetch_sub(1, Ordering::AcqRel);
+        self.0.update_readiness();
+    }
 }
 
 #[derive(Default)]
 struct Inner<CState> {
     state: DashMap<SocketAddr, Session<CState>>,
+    /// Messages dropped by sessions that have since been removed.
+    dropped: AtomicU64,
+    connections: Arc<ConnectionCounter>,
+    /// Whether the ready indicator was flipped to not-ready by `update_readiness`.
+    near_capacity: AtomicBool,
 }
 
 impl<CState: Clone> SessionList<CState> {
@@ -29,11 +83,21 @@ impl<CState: Clone> SessionList<CState> {
         SessionList {
             inner: Arc::new(Inner {
                 state: DashMap::new(),
+                dropped: AtomicU64::new(0),
+                connections: Arc::default(),
+                near_capacity: AtomicBool::new(false),
             }),
             config_manager,
+            ready_indicator: ReadyIndicator::default(),
         }
     }
 
+    #[must_use]
+    pub(crate) fn with_ready_indicator(mut self, ready_indicator: ReadyIndicator) -> Self {
+        self.ready_indicator = ready_indicator;
+        self
+    }
+
     pub fn add_miner(&self, addr: SocketAddr, miner: Session<CState>) {
         self.inner.state.insert(addr, miner);
         // gauge!(
@@ -43,7 +107,11 @@ impl<CState: Clone> SessionList<CState> {
     }
 
     pub fn remove_miner(&self, addr: SocketAddr) {
-        self.inner.state.remove(&addr);
+        if let Some((_, session)) = self.inner.state.remove(&addr) {
+            self.inner
+                .dropped
+                .fetch_add(session.dropped_messages(), Ordering::Relaxed);
+        }
         // gauge!(
         //     "stratum.num_connections",
         //     self.miners.read().await.len() as f64
@@ -55,6 +123,25 @@ impl<CState: Clone> SessionList<CState> {
         self.inner.state.iter().map(|x| x.value().clone()).collect()
     }
 
+    /// The send queue depths of every session, along with the messages dropped since the server
+    /// started, including by sessions that are gone.
+    #[must_use]
+    pub fn queue_stats(&self) -> QueueStats {
+        let mut stats = QueueStats {
+            dropped: self.inner.dropped.load(Ordering::Relaxed),
+            ..QueueStats::default()
+        };
+
+        for session in &self.inner.state {
+            let depth = session.queue_depth();
+            stats.queued += depth;
+            stats.deepest = stats.deepest.max(depth);
+            stats.dropped += session.dropped_messages();
+        }
+
+        stats
+    }
+
     #[must_use]
     pub fn len(&self) -> usize {
         self.inner.state.len()
@@ -65,20 +152,92 @@ impl<CState: Clone> SessionList<CState> {
         self.inner.state.is_empty()
     }
 
+    /// Whether the server is at `max_connections`. Connections still in their handshakes count.
     #[must_use]
     pub fn is_full(&self) -> bool {
-        if let Some(max) = self
-            .config_manager
+        self.max_connections()
+            .map_or(false, |max| self.inner.connections.open() >= max)
+    }
+
+    /// The number of connections turned away because the server was at `max_connections`.
+    #[must_use]
+    pub fn rejected_connections(&self) -> u64 {
+        self.inner.connections.rejected.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.inner.connections.stats()
+    }
+
+    #[cfg(feature = "api")]
+    pub(crate) fn connection_counter(&self) -> Arc<ConnectionCounter> {
+        self.inner.connections.clone()
+    }
+
+    /// Counts a newly accepted connection against `max_connections` until the returned slot is
+    /// dropped, or counts it as rejected if the server is full.
+    pub(crate) fn accept(&self) -> Option<ConnectionSlot<CState>> {
+        let max = self.max_connections().unwrap_or(usize::MAX);
+        let counter = &self.inner.connections;
+
+        if counter
+            .open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .is_err()
+        {
+            counter.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        self.update_readiness();
+
+        Some(ConnectionSlot(self.clone()))
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        self.config_manager
             .current_config()
             .connection
             .max_connections
+    }
+
+    /// Marks the server not ready once it fills up past the not-ready watermark, so that load
+    /// balancers send miners elsewhere, and ready again once it drains below the ready watermark.
+    /// Connections still in their handshakes count. Only a not-ready set here is undone here.
+    fn update_readiness(&self) {
+        let config = self.config_manager.current_config();
+        let Some(max) = config.connection.max_connections else {
+            return;
+        };
+
+        let open = self.inner.connections.open();
+        let percent = open as f64 / max.max(1) as f64 * 100.0;
+
+        if percent >= config.connection.not_ready_percent {
+            if self.ready_indicator.status()
+                && !self.inner.near_capacity.swap(true, Ordering::Relaxed)
+            {
+                warn!("Session List is near capacity ({open}/{max}), marking not ready");
+                self.ready_indicator.not_ready();
+            }
+        } else if percent <= config.connection.ready_percent
+            && self.inner.near_capacity.swap(false, Ordering::Relaxed)
         {
-            self.len() >= max
-        } else {
-            false
+            info!("Session List has capacity again ({open}/{max}), marking ready");
+            self.ready_indicator.ready();
         }
     }
 
+    /// Marks the server not ready for good, e.g. while it drains, so that sessions leaving don't
+    /// mark it ready again.
+    pub(crate) fn not_ready(&self) {
+        self.inner.near_capacity.store(false, Ordering::Relaxed);
+        self.ready_indicator.not_ready();
+    }
+
     //@todo we need to revamp this as it needs to be variable.
     pub fn shutdown_msg(&self, msg: Option<Buffer>) -> Result<()> {
         // @todo use this for deluge
@@ -0,0 +1,214 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+
+/// Options for the TCP sockets miners connect over. Buffer sizes and the backlog are set on each
+/// listener, which its streams inherit, while `TCP_NODELAY` and keepalive are set on every stream
+/// as it is accepted. Anything not set keeps the OS default.
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    pub(crate) nodelay: bool,
+    pub(crate) keepalive: Option<TcpKeepalive>,
+    pub(crate) send_buffer_size: Option<u32>,
+    pub(crate) recv_buffer_size: Option<u32>,
+    pub(crate) backlog: u32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            // The same as `TcpListener::bind`.
+            backlog: 1024,
+        }
+    }
+}
+
+impl SocketOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        SocketOptions::default()
+    }
+
+    /// Sends responses straight away rather than waiting to coalesce them with later writes.
+    #[must_use]
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Probes connections that have been idle for `time`, every `interval`, so that miners lost
+    /// behind a NAT are disconnected long before the active timeout. `interval` is ignored where
+    /// the OS doesn't support setting it.
+    #[must_use]
+    #[cfg_attr(
+        not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            windows
+        )),
+        allow(unused_variables)
+    )]
+    pub fn with_keepalive(mut self, time: Duration, interval: Duration) -> Self {
+        let keepalive = TcpKeepalive::new().with_time(time);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            windows
+        ))]
+        let keepalive = keepalive.with_interval(interval);
+
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// `SO_SNDBUF`, in bytes.
+    #[must_use]
+    pub fn with_send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// `SO_RCVBUF`, in bytes.
+    #[must_use]
+    pub fn with_recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// The most connections waiting to be accepted. Defaults to 1024.
+    #[must_use]
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Binds a listener to the first address `host:port` resolves to that it can. With
+    /// `reuse_port`, other listeners can bind to the same port and the OS spreads connections
+    /// between them.
+    pub(crate) async fn bind(
+        &self,
+        host: &str,
+        port: u16,
+        reuse_port: bool,
+    ) -> io::Result<TcpListener> {
+        let mut error = None;
+
+        for address in lookup_host(format!("{host}:{port}")).await? {
+            match self.bind_address(address, reuse_port) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        Err(error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        }))
+    }
+
+    fn bind_address(&self, address: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+        let socket = if address.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        // As `TcpListener::bind` does, so that a restarted server can bind while the old one's
+        // connections linger.
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+
+        if reuse_port {
+            set_reuse_port(&socket)?;
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        socket.bind(address)?;
+        socket.listen(self.backlog)
+    }
+
+    /// Sets the options that aren't inherited from the listener on an accepted stream.
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+
+        if let Some(keepalive) = &self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(keepalive)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn applies_options_to_listeners_and_streams() {
+        let options = SocketOptions::new()
+            .with_nodelay(true)
+            .with_keepalive(Duration::from_secs(60), Duration::from_secs(10))
+            .with_recv_buffer_size(64 * 1024);
+
+        let listener = options.bind("127.0.0.1", 0, false).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(SockRef::from(&listener).recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let _client = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        options.apply(&stream).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reuse_port_shares_the_port() {
+        let options = SocketOptions::new();
+
+        let first = options.bind("127.0.0.1", 0, true).await.unwrap();
+        let port = first.local_addr().unwrap().port();
+
+        assert!(options.bind("127.0.0.1", port, true).await.is_ok());
+        assert!(options.bind("127.0.0.1", port, false).await.is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::Error;
+use serde::{ser::SerializeTuple, Serialize, Serializer};
+use std::fmt::Display;
+
+/// The standard Stratum V1 error codes, plus the JSON-RPC codes the server answers with itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    MethodNotFound = -32601,
+    Other = 20,
+    JobNotFound = 21,
+    DuplicateShare = 22,
+    LowDifficulty = 23,
+    Unauthorized = 24,
+    NotSubscribed = 25,
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub fn as_i32(self) -> i32 {
+        self as i32
+    }
+
+    #[must_use]
+    pub fn default_message(self) -> &'static str {
+        match self {
+            ErrorCode::MethodNotFound => "Method not found",
+            ErrorCode::Other => "Other/Unknown",
+            ErrorCode::JobNotFound => "Job not found",
+            ErrorCode::DuplicateShare => "Duplicate share",
+            ErrorCode::LowDifficulty => "Low difficulty share",
+            ErrorCode::Unauthorized => "Unauthorized worker",
+            ErrorCode::NotSubscribed => "Not subscribed",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_i32())
+    }
+}
+
+/// What happens to the Session after an error has been sent to the miner.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ErrorPolicy {
+    /// Answer the miner and keep the connection open.
+    #[default]
+    Keep,
+    /// Answer the miner and then disconnect it.
+    Disconnect,
+    /// Answer the miner, then disconnect and ban it.
+    Ban,
+    /// Answer the miner and add to the Session's ban score. The Session is banned once the score
+    /// reaches the configured allowance.
+    BanScore(u64),
+}
+
+/// An error that is sent back to the miner as a JSON-RPC `error` array, e.g.
+/// `[21, "Job not found", null]`.
+#[derive(thiserror::Error, Clone, Debug)]
+#[error("Stratum error {code}: {message}")]
+pub struct StratumError {
+    code: ErrorCode,
+    message: String,
+    policy: ErrorPolicy,
+}
+
+impl StratumError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        StratumError {
+            code,
+            message: message.into(),
+            policy: ErrorPolicy::default(),
+        }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        StratumError::new(ErrorCode::Other, message)
+    }
+
+    #[must_use]
+    pub fn job_not_found() -> Self {
+        StratumError::from(ErrorCode::JobNotFound)
+    }
+
+    #[must_use]
+    pub fn duplicate_share() -> Self {
+        StratumError::from(ErrorCode::DuplicateShare)
+    }
+
+    #[must_use]
+    pub fn low_difficulty() -> Self {
+        StratumError::from(ErrorCode::LowDifficulty)
+    }
+
+    #[must_use]
+    pub fn unauthorized() -> Self {
+        StratumError::from(ErrorCode::Unauthorized)
+    }
+
+    #[must_use]
+    pub fn not_subscribed() -> Self {
+        StratumError::from(ErrorCode::NotSubscribed)
+    }
+
+    #[must_use]
+    pub fn method_not_found() -> Self {
+        StratumError::from(ErrorCode::MethodNotFound)
+    }
+
+    #[must_use]
+    pub fn with_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    #[must_use]
+    pub fn policy(&self) -> ErrorPolicy {
+        self.policy
+    }
+
+    /// Converts any error returned from an endpoint. Typed Stratum errors are passed through
+    /// untouched, everything else becomes an `Other` error that disconnects the miner. Its text
+    /// may describe server internals, so the miner is only told "Internal error".
+    pub(crate) fn from_handler_error(e: &(dyn std::error::Error + 'static)) -> Self {
+        if let Some(error) = e.downcast_ref::<StratumError>() {
+            return error.clone();
+        }
+
+        if let Some(Error::Stratum(error)) = e.downcast_ref::<Error>() {
+            return error.clone();
+        }
+
+        StratumError::other("Internal error").with_policy(ErrorPolicy::Disconnect)
+    }
+}
+
+impl From<ErrorCode> for StratumError {
+    fn from(code: ErrorCode) -> Self {
+        StratumError::new(code, code.default_message())
+    }
+}
+
+impl Serialize for StratumError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&self.code.as_i32())?;
+        tuple.serialize_element(&self.message)?;
+        tuple.serialize_element(&())?;
+        tuple.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_as_error_array() {
+        let error = StratumError::job_not_found();
+
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!([21, "Job not found", null])
+        );
+    }
+
+    #[test]
+    fn handler_errors_keep_their_policy() {
+        let error = StratumError::duplicate_share().with_policy(ErrorPolicy::BanScore(5));
+
+        let converted = StratumError::from_handler_error(&error);
+        assert_eq!(converted.code(), ErrorCode::DuplicateShare);
+        assert_eq!(converted.policy(), ErrorPolicy::BanScore(5));
+
+        let wrapped = Error::from(StratumError::unauthorized());
+        let converted = StratumError::from_handler_error(&wrapped);
+        assert_eq!(converted.code(), ErrorCode::Unauthorized);
+        assert_eq!(converted.policy(), ErrorPolicy::Keep);
+    }
+
+    #[test]
+    fn untyped_errors_disconnect_without_details() {
+        let error = std::io::Error::new(std::io::ErrorKind::Other, "database offline");
+
+        let converted = StratumError::from_handler_error(&error);
+        assert_eq!(converted.code(), ErrorCode::Other);
+        assert_eq!(converted.message(), "Internal error");
+        assert_eq!(converted.policy(), ErrorPolicy::Disconnect);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::{
+    connection::{self, Transport},
+    connection_limiter::{ConnectionLimiter, ConnectionPermit},
     id_manager::IDManager,
+    message_limiter::MessageLimiter,
+    proxy_protocol::{self, ProxyHeader},
     router::Router,
     session::Session,
     types::{ConnectionID, GlobalVars},
-    BanManager, ConfigManager, Connection, Result, SessionList,
+    BanManager, ConfigManager, Connection, Error, Result, SessionList,
 };
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::time::{sleep, Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{enabled, error, trace, warn, Level};
@@ -22,6 +26,7 @@ where
     pub(crate) id_manager: IDManager,
     pub(crate) session_list: SessionList<CState>,
     pub(crate) config_manager: ConfigManager,
+    pub(crate) connection_limiter: ConnectionLimiter,
 
     // Not sure, but should test
     pub(crate) router: Arc<Router<State, CState>>,
@@ -32,23 +37,172 @@ where
     pub(crate) connection: Connection,
     pub(crate) cancel_token: CancellationToken,
     pub(crate) global_vars: GlobalVars,
+    pub(crate) transport: Transport,
 }
 
 impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync + 'static>
     Handler<State, CState>
 {
-    pub(crate) async fn run(mut self) -> Result<()> {
-        let address = if self.config_manager.proxy_protocol() {
-            self.connection.proxy_protocol().await?
-        } else {
-            self.connection.address
+    /// Connections accepted on the SV2 listener complete the Noise handshake before any frames are
+    /// read. It has to finish within the initial timeout.
+    #[cfg(feature = "v2")]
+    async fn v2_handshake(&mut self) -> Result<()> {
+        let Some(keys) = self
+            .config_manager
+            .v2_keys()
+            .filter(|_| self.transport == Transport::V2)
+        else {
+            return Ok(());
+        };
+
+        let timeout = Duration::from_secs(self.config_manager.connection_config().inital_timeout);
+        tokio::time::timeout(timeout, self.connection.v2_handshake(keys))
+            .await
+            .map_err(|_| crate::v2::Error::HandshakeTimeout)?
+    }
+
+    /// Connections accepted on the TLS listener complete the TLS handshake, after the PROXY header
+    /// if there is one. It has to finish within the initial timeout.
+    #[cfg(feature = "tls")]
+    async fn start_tls(mut self) -> Result<Self> {
+        let Some(tls) = self
+            .config_manager
+            .tls()
+            .filter(|_| self.transport == Transport::Tls)
+        else {
+            return Ok(self);
         };
 
+        let timeout = Duration::from_secs(self.config_manager.connection_config().inital_timeout);
+        self.connection = tokio::time::timeout(timeout, self.connection.start_tls(&tls.acceptor()))
+            .await
+            .map_err(|_| crate::Error::TlsHandshakeTimeout)??;
+
+        Ok(self)
+    }
+
+    /// Connections accepted on the WebSocket listener complete the upgrade, after the PROXY header
+    /// if there is one. It has to finish within the initial timeout.
+    #[cfg(feature = "websocket")]
+    async fn start_websocket(mut self) -> Result<Self> {
+        if self.transport != Transport::WebSocket {
+            return Ok(self);
+        }
+
+        let config = self.config_manager.connection_config();
+        let timeout = Duration::from_secs(config.inital_timeout);
+        self.connection = tokio::time::timeout(
+            timeout,
+            self.connection.start_websocket(config.max_frame_size),
+        )
+        .await
+        .map_err(|_| crate::Error::WebSocketHandshakeTimeout)??;
+
+        Ok(self)
+    }
+
+    /// Completes whatever handshake the listener's transport needs before frames are read.
+    #[allow(unused_mut)]
+    #[cfg_attr(
+        not(any(feature = "tls", feature = "v2", feature = "websocket")),
+        allow(clippy::unused_async)
+    )]
+    async fn handshake(mut self) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        {
+            self = self.start_tls().await?;
+        }
+
+        #[cfg(feature = "websocket")]
+        {
+            self = self.start_websocket().await?;
+        }
+
+        #[cfg(feature = "v2")]
+        self.v2_handshake().await?;
+
+        Ok(self)
+    }
+
+    /// Reads the PROXY header if the server expects one, returning the miner's address along with
+    /// it. It has to arrive within the initial timeout.
+    async fn proxy_protocol(&mut self) -> Result<(SocketAddr, Option<ProxyHeader>)> {
+        let peer = self.connection.address;
+        if !self.config_manager.proxy_protocol() {
+            return Ok((peer, None));
+        }
+
+        // Who may use a Unix socket is down to its file permissions.
+        let config = self.config_manager.connection_config();
+        if !connection::is_unix_peer(peer)
+            && !config.proxy_trusted_sources.is_empty()
+            && !config.proxy_trusted_sources.contains(&peer.ip())
+        {
+            return Err(proxy_protocol::Error::UntrustedSource(peer.ip()).into());
+        }
+
+        let timeout = Duration::from_secs(config.inital_timeout);
+        let header = tokio::time::timeout(timeout, self.connection.proxy_protocol())
+            .await
+            .map_err(|_| proxy_protocol::Error::Timeout)??;
+
+        if let (Some(expected), Some(destination)) =
+            (config.proxy_destination_port, header.destination)
+        {
+            if destination.port() != expected {
+                return Err(Error::StreamWrongPort);
+            }
+        }
+
+        Ok((header.source.unwrap_or(peer), Some(header)))
+    }
+
+    /// Logs why reading from the miner failed, banning its IP for oversized or trickled frames if
+    /// that is enabled.
+    fn read_error(config_manager: &ConfigManager, session: &Session<CState>, e: &Error) {
+        warn!(
+            ip = session.ip().to_string(),
+            "Session: {} errored with the following error: {}",
+            session.id(),
+            e
+        );
+
+        if matches!(e, Error::FrameTooLarge(_) | Error::FrameTimeout)
+            && config_manager.frame_violation_ban()
+        {
+            session.ban_ip();
+        }
+    }
+
+    /// Refuses miners banned by their address or IP, once the PROXY header has resolved it. A
+    /// miner behind a load balancer also takes its per IP connection slot here, as only the header
+    /// says who it is. Miners connecting directly are limited as they are accepted.
+    fn admit(
+        &self,
+        address: SocketAddr,
+        header: Option<&ProxyHeader>,
+    ) -> Result<Option<ConnectionPermit>> {
         if self.config_manager.ban_manager_enabled() {
             self.ban_manager.check_banned(address)?;
+            self.ban_manager.check_banned(address.ip())?;
         }
 
-        let (mut reader, tx, handle) = self.connection.init();
+        match header.and_then(|header| header.source) {
+            Some(source) => self
+                .connection_limiter
+                .acquire(source.ip(), &self.config_manager),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn run(mut self) -> Result<()> {
+        let (address, proxy_header) = self.proxy_protocol().await?;
+        // Held until the session ends.
+        let _permit = self.admit(address, proxy_header.as_ref())?;
+
+        self = self.handshake().await?;
+
+        let (mut reader, tx, handle) = self.connection.init(&self.config_manager);
 
         let session_id = self.id_manager.allocate_session_id()?;
 
@@ -64,9 +218,14 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
             self.connection_state,
         )?;
 
+        if let Some(header) = proxy_header {
+            session.set_proxy_header(header);
+        }
+
         trace!(
             id = ?self.id,
             ip = &address.to_string(),
+            transport = ?self.transport,
             "Connection initialized",
         );
 
@@ -77,6 +236,8 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
         ));
         tokio::pin!(sleep);
 
+        let mut limiter = MessageLimiter::new(&self.config_manager);
+
         //@todo we can return a value from this loop -> break can return a value, and so we may
         //want to return an error if there is one so that we can report it at the end.
         while !self.cancel_token.is_cancelled() {
@@ -85,14 +246,15 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
                 break;
             }
 
-            let maybe_frame = tokio::select! {
+            let frame = tokio::select! {
                     res = reader.read_frame() => {
                         match res {
                             Err(e) => {
-                                warn!(ip = session.ip().to_string(), "Session: {} errored with the following error: {}", session.id(), e);
+                                Self::read_error(&self.config_manager, &session, &e);
                                 break;
                             },
-                            Ok(frame) => frame,
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
                         }
                     },
                         () = &mut sleep => {
@@ -117,9 +279,10 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
                     }
                 };
 
-            let Some(frame) = maybe_frame else {
-                break;
-            };
+            // Refused frames don't count as activity, or a flood would keep the miner connected.
+            if limiter.limit(&frame, &session) {
+                continue;
+            }
 
             //Resets the Session's last active, to detect for unactive connections
             session.active();
@@ -150,8 +313,8 @@ impl<State: Clone + Send + Sync + 'static, CState: Default + Clone + Send + Sync
         self.session_list.remove_miner(address);
         self.id_manager.remove_session_id(session_id);
 
-        if session.needs_ban() {
-            self.ban_manager.add_ban(address);
+        if let Some(key) = session.ban_key() {
+            self.ban_manager.add_ban(key);
         }
 
         session.shutdown();
@@ -0,0 +1,190 @@
+use crate::{Error, Result};
+use parking_lot::RwLock;
+use std::{path::PathBuf, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::info;
+
+/// Settings for the TLS (stratum+ssl) listener.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub(crate) port: u16,
+    pub(crate) cert_path: PathBuf,
+    pub(crate) key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// Listens for TLS miners on `port`. `cert_path` is a PEM file with the certificate chain,
+    /// leaf first, and `key_path` a PEM file with its private key.
+    pub fn new(port: u16, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig {
+            port,
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+}
+
+/// The acceptor for the TLS listener. Certificates are read from disk when it is created, and
+/// again on every `reload`, so they can be rotated without restarting the server.
+#[derive(Clone)]
+pub(crate) struct Tls {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self> {
+        let acceptor = load(&config)?;
+
+        Ok(Tls {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// The acceptor for new connections. Connections that are already established keep using the
+    /// certificate they were accepted with.
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().clone()
+    }
+
+    /// Re-reads the certificate and key. On error the current certificate stays in use.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let acceptor = load(&self.config)?;
+        *self.acceptor.write() = acceptor;
+
+        info!(
+            "Reloaded TLS certificate from {}",
+            self.config.cert_path.display()
+        );
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+fn load(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(Iterator::collect::<std::result::Result<Vec<_>, _>>)
+        .map_err(|e| Error::InvalidTlsFile(config.cert_path.clone(), e.to_string()))?;
+
+    if certs.is_empty() {
+        return Err(Error::InvalidTlsFile(
+            config.cert_path.clone(),
+            "no certificates found".to_string(),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|e| Error::InvalidTlsFile(config.key_path.clone(), e.to_string()))?;
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{extract::Sess, StratumServer};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+    };
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    /// Writes a new self-signed certificate for localhost to `dir`, returning it in DER.
+    fn write_cert(dir: &std::path::Path) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        cert.cert.der().clone()
+    }
+
+    async fn connect(
+        address: std::net::SocketAddr,
+        cert: CertificateDer<'static>,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let stream = TcpStream::connect(address).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let dir = std::env::temp_dir().join(format!("stratum-tls-{}", uuid::Uuid::new_v4()));
+
+        assert!(matches!(
+            Tls::new(TlsConfig::new(0, dir.join("cert.pem"), dir.join("key.pem"))),
+            Err(Error::InvalidTlsFile(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_and_reloads_certificates() {
+        async fn subscribe(Sess(_): Sess<()>) -> Result<bool> {
+            Ok(true)
+        }
+
+        let dir = std::env::temp_dir().join(format!("stratum-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = write_cert(&dir);
+
+        let builder = StratumServer::<(), ()>::builder((), 1)
+            .with_host("127.0.0.1")
+            .with_port(0)
+            .with_tls(TlsConfig::new(0, dir.join("cert.pem"), dir.join("key.pem")));
+        #[cfg(feature = "api")]
+        let builder = builder.with_api_port(0);
+        let mut server = builder.build().await.unwrap();
+        server.add("mining.subscribe", subscribe);
+        let address = server.get_tls_address().unwrap();
+        let tls = server.listeners[0].config_manager.tls().unwrap().clone();
+        let handle = tokio::spawn(async move { server.start().await });
+
+        let stream = connect(address, first.clone()).await.unwrap();
+        let (reader, mut writer) = tokio::io::split(stream);
+        writer
+            .write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n")
+            .await
+            .unwrap();
+        writer.flush().await.unwrap();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await.unwrap();
+        assert!(line.contains("\"result\":true"), "{line}");
+
+        let second = write_cert(&dir);
+        tls.reload().unwrap();
+
+        assert!(connect(address, first).await.is_err());
+        assert!(connect(address, second).await.is_ok());
+
+        handle.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
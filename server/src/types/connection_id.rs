@@ -1,7 +1,7 @@
 use std::fmt::Display;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ConnectionID(Uuid);
 
 impl ConnectionID {
@@ -14,6 +14,21 @@ impl ID {
     pub fn null() -> ID {
         ID::Null(serde_json::Value::Null)
     }
+
+    #[must_use]
+    pub fn is_null(&self) -> bool {
+        matches!(self, ID::Null(serde_json::Value::Null))
+    }
+
+    /// Whether a request with this id is a notification, which is never answered. JSON-RPC only
+    /// allows a number or a string as the id of a request that expects a response.
+    #[must_use]
+    pub fn is_notification(&self) -> bool {
+        match self {
+            ID::Num(_) | ID::Str(_) => false,
+            ID::Null(value) => !value.is_number(),
+        }
+    }
 }
 
 impl std::fmt::Display for ID {
@@ -23,10 +23,18 @@ pub const EX_MAGIC_NUMBER: u8 = 0x7F;
 #[derive(Clone, Debug)]
 pub struct GlobalVars {
     pub server_id: u8,
+    /// The server wide connection to a parent pool, if one was configured.
+    #[cfg(feature = "upstream")]
+    pub upstream: Option<crate::upstream::Upstream>,
 }
 
 impl GlobalVars {
+    #[must_use]
     pub fn new(server_id: u8) -> Self {
-        GlobalVars { server_id }
+        GlobalVars {
+            server_id,
+            #[cfg(feature = "upstream")]
+            upstream: None,
+        }
     }
 }
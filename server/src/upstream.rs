@@ -1,110 +1,841 @@
-// pub use crate::ConnectionList;
-// use crate::{
-//     config::UpstreamConfig,
-//     connection::Connection,
-//     parsing::next_message,
-//     router::Router,
-//     types::{GlobalVars, MessageValue},
-//     Error, Result,
-// };
-// use futures::{
-//     channel::mpsc::{UnboundedReceiver, UnboundedSender},
-//     SinkExt, StreamExt,
-// };
-//
-// use serde_json::{Map, Value};
-// use std::sync::Arc;
-// use tokio::{
-//     io::{AsyncWriteExt, BufReader},
-//     net::{tcp::OwnedWriteHalf, TcpStream},
-// };
-// use tracing::{trace, warn};
-//
-// pub async fn upstream_message_handler<
-//     State: Clone + Send + Sync + 'static,
-//     CState: Clone + Send + Sync + 'static,
-// >(
-//     config: UpstreamConfig,
-//     upstream_router: Arc<Router<State, CState>>,
-//     urx: UnboundedReceiver<String>,
-//     state: State,
-//     connection: Arc<Connection<CState>>,
-//     mut urtx: UnboundedSender<Map<String, Value>>,
-//     global_vars: GlobalVars,
-// ) -> Result<()> {
-//     if config.enabled {
-//         let upstream = TcpStream::connect(config.url).await?;
-//
-//         let (urh, uwh) = upstream.into_split();
-//         let mut upstream_buffer_stream = BufReader::new(urh);
-//
-//         tokio::spawn(async move {
-//             match upstream_send_loop(urx, uwh).await {
-//                 //@todo not sure if we even want a info here, we need an ID tho.
-//                 Ok(_) => trace!("Upstream Send Loop is closing for connection"),
-//                 Err(e) => warn!(
-//                     "Upstream Send loop is closed for connection: {}, Reason: {}",
-//                     1, e
-//                 ),
-//             }
-//         });
-//
-//         tokio::spawn({
-//             let state = state.clone();
-//             let connection = connection.clone();
-//             let stop_token = connection.get_cancel_token();
-//
-//             async move {
-//                 loop {
-//                     // @todo actually think about a real timeout here as well.
-//                     let (method, values) = tokio::select! {
-//                         msg = next_message(&mut upstream_buffer_stream) => {
-//                         match msg {
-//                                 Ok(mv) => mv,
-//                                 Err(_) => {
-//                                     break;
-//                                 }
-//                             }
-//
-//                         }
-//                         _ = stop_token.cancelled() => {
-//                             break;
-//                         }
-//
-//                     };
-//
-//                     if method == "result" {
-//                         if let MessageValue::StratumV1(map) = values {
-//                             urtx.send(map).await?;
-//                         }
-//                         continue;
-//                     }
-//
-//                     upstream_router
-//                         .call(
-//                             &method,
-//                             values,
-//                             state.clone(),
-//                             connection.clone(),
-//                             global_vars.clone(),
-//                         )
-//                         .await;
-//                 }
-//                 Ok::<(), Error>(())
-//             }
-//         });
-//     }
-//     Ok(())
-// }
-//
-// pub async fn upstream_send_loop(
-//     mut rx: UnboundedReceiver<String>,
-//     mut rh: OwnedWriteHalf,
-// ) -> Result<()> {
-//     while let Some(msg) = rx.next().await {
-//         rh.write_all(msg.as_bytes()).await?;
-//         rh.write_all(b"\n").await?;
-//     }
-//
-//     Ok(())
-// }
+//! A managed connection to a parent pool.
+//!
+//! An `Upstream` can be held by the whole server (see `StratumServerBuilder::with_upstream`) or
+//! created per Session. Requests sent through it get a fresh id, and the pool's `result` for that
+//! id is routed back to whoever sent the request: `request` awaits it, `forward` relays it to a
+//! downstream Session with the miner's original id restored. Anything else the pool sends, such as
+//! `mining.notify`, goes through the upstream's own `Router`. The Session handed to those routes
+//! represents the pool, so responses and anything sent on it are written to the pool.
+//!
+//! The connection is re-established with exponential backoff whenever it drops. Requests that
+//! were waiting on the old connection fail with `Error::Disconnected`, and the requests set with
+//! `UpstreamConfig::with_login` are sent again before anything else.
+
+use crate::{
+    frame::Request,
+    router::Router,
+    send_queue::SendQueue,
+    session::SendInformation,
+    types::{ConnectionID, GlobalVars},
+    ConfigManager, ConnectionConfig, Frame, OverflowPolicy, Result, Session, SessionID,
+    StratumError, UpstreamConfig, ID,
+};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::oneshot,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Upstream pool disconnected before answering")]
+    Disconnected,
+    #[error("Upstream pool did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error("Upstream pool rejected the request: {0}")]
+    Rejected(Value),
+    #[error("Upstream connection is already running")]
+    AlreadyRunning,
+}
+
+#[derive(Clone)]
+pub struct Upstream {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: UpstreamConfig,
+    /// Shared with the pool's Session, so it outlives each connection. Whatever is still queued
+    /// when a connection drops is discarded along with the requests waiting on it. Requests are
+    /// refused rather than dropped once it is full.
+    sender: SendQueue,
+    /// Set by `run`, which may only be called once.
+    running: AtomicBool,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    next_id: AtomicU64,
+    connected: AtomicBool,
+    cancel_token: CancellationToken,
+}
+
+struct Reply {
+    result: Value,
+    error: Value,
+}
+
+impl Debug for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upstream")
+            .field("url", &self.inner.config.url)
+            .field("connected", &self.is_connected())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Upstream {
+    /// Creates the handle. Nothing connects until `run` is called.
+    #[must_use]
+    pub fn new(config: UpstreamConfig, cancel_token: CancellationToken) -> Self {
+        let sender = SendQueue::new(
+            ConnectionConfig::default().send_queue_capacity,
+            OverflowPolicy::Backpressure,
+        );
+
+        Upstream {
+            inner: Arc::new(Inner {
+                config,
+                sender,
+                running: AtomicBool::new(false),
+                pending: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(1),
+                connected: AtomicBool::new(false),
+                cancel_token,
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::Relaxed)
+    }
+
+    /// Stops `run`, closing the connection to the pool.
+    pub fn shutdown(&self) {
+        self.inner.cancel_token.cancel();
+    }
+
+    /// Sends a request to the pool and waits for its result.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let (id, reply) = self.send_request(method, params)?;
+
+        self.result(id, reply).await
+    }
+
+    /// Sends a miner's request to the pool. The pool's answer is sent to `session` as the response
+    /// to `id`, or an error if the pool never answers.
+    pub fn forward<CState: Clone + Send + Sync + 'static>(
+        &self,
+        session: &Session<CState>,
+        id: ID,
+        method: &str,
+        params: Value,
+    ) -> Result<()> {
+        let (upstream_id, reply) = self.send_request(method, params)?;
+
+        let upstream = self.clone();
+        let session = session.clone();
+        tokio::spawn(async move {
+            let response = match upstream.wait(upstream_id, reply).await {
+                Ok(reply) => json!({"id": id, "result": reply.result, "error": reply.error}),
+                Err(e) => {
+                    warn!(id = ?session.id(), cause = %e, "Forwarded request failed");
+                    json!({
+                        "id": id,
+                        "result": null,
+                        "error": StratumError::other("Upstream pool unavailable"),
+                    })
+                }
+            };
+
+            if let Err(e) = session.send(response) {
+                trace!(id = ?session.id(), cause = %e, "Session closed before upstream answered");
+            }
+        });
+
+        Ok(())
+    }
+
+    fn send_request(&self, method: &str, params: Value) -> Result<(u64, oneshot::Receiver<Reply>)> {
+        let (id, rx, request) = self.new_request(method, params);
+
+        if let Err(e) = self
+            .inner
+            .sender
+            .push(SendInformation::Json(request), false)
+        {
+            self.inner.pending.lock().remove(&id);
+            return Err(e);
+        }
+
+        Ok((id, rx))
+    }
+
+    /// Registers a request that is about to be sent, returning its id and encoded line.
+    fn new_request(&self, method: &str, params: Value) -> (u64, oneshot::Receiver<Reply>, Bytes) {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().insert(id, tx);
+
+        let mut request = json!({"id": id, "method": method});
+        request["params"] = params;
+
+        let mut request = request.to_string();
+        request.push('\n');
+
+        (id, rx, Bytes::from(request))
+    }
+
+    async fn result(&self, id: u64, reply: oneshot::Receiver<Reply>) -> Result<Value> {
+        let reply = self.wait(id, reply).await?;
+
+        if reply.error.is_null() {
+            Ok(reply.result)
+        } else {
+            Err(Error::Rejected(reply.error).into())
+        }
+    }
+
+    async fn wait(&self, id: u64, reply: oneshot::Receiver<Reply>) -> Result<Reply> {
+        let timeout = self.inner.config.request_timeout;
+
+        match tokio::time::timeout(timeout, reply).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(Error::Disconnected.into()),
+            Err(_) => {
+                self.inner.pending.lock().remove(&id);
+                Err(Error::Timeout(timeout).into())
+            }
+        }
+    }
+
+    /// Connects to the pool and keeps the connection up until `shutdown` is called. Messages from
+    /// the pool are routed through `router`, with a Session holding `session_state` that writes
+    /// back to the pool.
+    pub async fn run<State, CState>(
+        self,
+        router: Arc<Router<State, CState>>,
+        state: State,
+        session_state: CState,
+        global_vars: GlobalVars,
+    ) -> Result<()>
+    where
+        State: Clone + Send + Sync + 'static,
+        CState: Clone + Send + Sync + 'static,
+    {
+        if self.inner.running.swap(true, Ordering::Relaxed) {
+            return Err(Error::AlreadyRunning.into());
+        }
+
+        let session = Session::new(
+            ConnectionID::new(),
+            SessionID::default(),
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+            self.inner.sender.clone(),
+            ConfigManager::default(),
+            self.inner.cancel_token.child_token(),
+            session_state,
+        )?;
+
+        let cancel_token = self.inner.cancel_token.clone();
+        let config = &self.inner.config;
+        let mut backoff = config.min_backoff;
+
+        while !cancel_token.is_cancelled() {
+            let stream = tokio::select! {
+                stream = TcpStream::connect(&config.url) => stream,
+                () = cancel_token.cancelled() => break,
+            };
+
+            match stream {
+                Ok(stream) => {
+                    info!("Connected to upstream pool {}", config.url);
+                    backoff = config.min_backoff;
+                    self.inner.connected.store(true, Ordering::Relaxed);
+
+                    if let Err(e) = self
+                        .serve(stream, &router, &state, &session, &global_vars)
+                        .await
+                    {
+                        warn!(cause = %e, "Upstream pool {} connection failed", config.url);
+                    }
+
+                    self.inner.connected.store(false, Ordering::Relaxed);
+                    self.disconnected();
+                }
+                Err(e) => warn!(cause = %e, "Unable to connect to upstream pool {}", config.url),
+            }
+
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = cancel_token.cancelled() => break,
+            }
+
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+
+        info!("Upstream pool {} connection shut down", config.url);
+
+        Ok(())
+    }
+
+    async fn serve<State, CState>(
+        &self,
+        stream: TcpStream,
+        router: &Router<State, CState>,
+        state: &State,
+        session: &Session<CState>,
+        global_vars: &GlobalVars,
+    ) -> Result<()>
+    where
+        State: Clone + Send + Sync + 'static,
+        CState: Clone + Send + Sync + 'static,
+    {
+        let (reader, mut writer) = stream.into_split();
+        // Unlike `read_line`, `next_line` keeps a partly read line when it loses the select below.
+        let mut lines = BufReader::new(reader).lines();
+
+        self.login(&mut writer).await?;
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else {
+                        return Err(crate::Error::PeerResetConnection);
+                    };
+
+                    // The pool's Session never times out while the pool is talking to us.
+                    session.active();
+
+                    let message = line.trim();
+                    if !message.is_empty() {
+                        trace!("Received from upstream: {message}");
+                        self.handle(message, router, state, session, global_vars).await;
+                    }
+                }
+                Some(msg) = self.inner.sender.pop() => {
+                    match msg {
+                        SendInformation::Json(json) => writer.write_all(&json).await?,
+                        SendInformation::Text(text) => writer.write_all(text.as_bytes()).await?,
+                        SendInformation::Raw(buffer) => writer.write_all(&buffer).await?,
+                        #[cfg(feature = "v2")]
+                        SendInformation::V2(_) => trace!("Dropping SV2 frame sent upstream"),
+                    }
+                }
+                () = self.inner.cancel_token.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Writes the login requests straight to the new connection, ahead of anything queued, and
+    /// logs their results as they come in.
+    async fn login(&self, writer: &mut OwnedWriteHalf) -> Result<()> {
+        for (method, params) in &self.inner.config.login {
+            let (id, reply, request) = self.new_request(method, params.clone());
+            writer.write_all(&request).await?;
+
+            let upstream = self.clone();
+            let method = method.clone();
+            tokio::spawn(async move {
+                if let Err(e) = upstream.result(id, reply).await {
+                    warn!(cause = %e, "Upstream pool login {method} failed");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn handle<State, CState>(
+        &self,
+        message: &str,
+        router: &Router<State, CState>,
+        state: &State,
+        session: &Session<CState>,
+        global_vars: &GlobalVars,
+    ) where
+        State: Clone + Send + Sync + 'static,
+        CState: Clone + Send + Sync + 'static,
+    {
+        let value: Value = match serde_json::from_str(message) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!(cause = %e, "Upstream pool sent invalid JSON");
+                return;
+            }
+        };
+
+        if value.get("method").map_or(false, Value::is_string) {
+            match serde_json::from_value::<Request>(value) {
+                Ok(request) => {
+                    router
+                        .call(
+                            Frame::V1(request),
+                            state.clone(),
+                            session.clone(),
+                            global_vars.clone(),
+                        )
+                        .await;
+                }
+                Err(e) => warn!(cause = %e, "Upstream pool sent an invalid request"),
+            }
+            return;
+        }
+
+        let Some(id) = value.get("id").and_then(Value::as_u64) else {
+            trace!("Ignoring upstream message without a method or id");
+            return;
+        };
+
+        let Some(pending) = self.inner.pending.lock().remove(&id) else {
+            trace!("Ignoring upstream result for unknown id {id}");
+            return;
+        };
+
+        let _ = pending.send(Reply {
+            result: value.get("result").cloned().unwrap_or_default(),
+            error: value.get("error").cloned().unwrap_or_default(),
+        });
+    }
+
+    /// Fails everything that was waiting on the old connection.
+    fn disconnected(&self) {
+        self.inner.pending.lock().clear();
+        self.inner.sender.clear();
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::MockPool;
+
+#[cfg(any(test, feature = "test-utils"))]
+mod mock {
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::{
+            broadcast,
+            mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        },
+        task::JoinHandle,
+    };
+
+    #[derive(Clone)]
+    enum Command {
+        Send(String),
+        Disconnect,
+    }
+
+    /// A local stand-in for a parent pool, for tests. Every request is answered with the result
+    /// returned by `respond`, and notifications can be pushed to every connected client.
+    pub struct MockPool {
+        address: SocketAddr,
+        requests: UnboundedReceiver<Value>,
+        commands: broadcast::Sender<Command>,
+        handle: JoinHandle<()>,
+    }
+
+    impl MockPool {
+        pub async fn start<F>(respond: F) -> crate::Result<Self>
+        where
+            F: Fn(&Value) -> Value + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let address = listener.local_addr()?;
+            let (requests_tx, requests) = unbounded_channel();
+            let (commands, _) = broadcast::channel(16);
+            let respond = std::sync::Arc::new(respond);
+
+            let handle = tokio::spawn({
+                let commands = commands.clone();
+                async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        tokio::spawn(serve(
+                            stream,
+                            respond.clone(),
+                            requests_tx.clone(),
+                            commands.subscribe(),
+                        ));
+                    }
+                }
+            });
+
+            Ok(MockPool {
+                address,
+                requests,
+                commands,
+                handle,
+            })
+        }
+
+        #[must_use]
+        pub fn address(&self) -> SocketAddr {
+            self.address
+        }
+
+        /// The next request received from any client.
+        pub async fn next_request(&mut self) -> Option<Value> {
+            self.requests.recv().await
+        }
+
+        /// Sends a notification to every connected client.
+        pub fn notify(&self, method: &str, params: Value) {
+            let mut notification = json!({"id": null, "method": method});
+            notification["params"] = params;
+            let _ = self.commands.send(Command::Send(notification.to_string()));
+        }
+
+        /// Drops every connected client, which then has to reconnect.
+        pub fn disconnect_all(&self) {
+            let _ = self.commands.send(Command::Disconnect);
+        }
+    }
+
+    impl Drop for MockPool {
+        fn drop(&mut self) {
+            self.handle.abort();
+        }
+    }
+
+    async fn serve<F>(
+        stream: TcpStream,
+        respond: std::sync::Arc<F>,
+        requests: UnboundedSender<Value>,
+        mut commands: broadcast::Receiver<Command>,
+    ) -> crate::Result<()>
+    where
+        F: Fn(&Value) -> Value + Send + Sync + 'static,
+    {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else {
+                        return Ok(());
+                    };
+
+                    let request: Value = serde_json::from_str(line.trim())?;
+
+                    if !request["id"].is_null() {
+                        let response = json!({
+                            "id": request["id"],
+                            "result": respond(&request),
+                            "error": null,
+                        });
+                        writer.write_all(format!("{response}\n").as_bytes()).await?;
+                    }
+
+                    let _ = requests.send(request);
+                }
+                Ok(command) = commands.recv() => match command {
+                    Command::Send(message) => {
+                        writer.write_all(format!("{message}\n").as_bytes()).await?;
+                    }
+                    Command::Disconnect => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{Params, Sess};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    fn config(pool: &MockPool) -> UpstreamConfig {
+        UpstreamConfig::new(&pool.address().to_string())
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .with_request_timeout(Duration::from_secs(5))
+    }
+
+    async fn connected(upstream: &Upstream) {
+        while !upstream.is_connected() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_correlated_and_reconnect() {
+        let mut pool = MockPool::start(|request| request["params"][0].clone())
+            .await
+            .unwrap();
+        let upstream = Upstream::new(config(&pool), CancellationToken::new());
+
+        let handle = tokio::spawn(upstream.clone().run(
+            Arc::new(Router::<(), ()>::new()),
+            (),
+            (),
+            GlobalVars::new(1),
+        ));
+        connected(&upstream).await;
+
+        let (first, second) = tokio::join!(
+            upstream.request("mining.echo", json!(["first"])),
+            upstream.request("mining.echo", json!(["second"])),
+        );
+        assert_eq!(first.unwrap(), json!("first"));
+        assert_eq!(second.unwrap(), json!("second"));
+        assert_eq!(pool.next_request().await.unwrap()["method"], "mining.echo");
+
+        pool.disconnect_all();
+        while upstream.is_connected() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        connected(&upstream).await;
+
+        assert_eq!(
+            upstream
+                .request("mining.echo", json!(["again"]))
+                .await
+                .unwrap(),
+            json!("again")
+        );
+
+        upstream.shutdown();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn forwarded_results_restore_the_miner_id() {
+        let pool = MockPool::start(|_| json!(true)).await.unwrap();
+        let upstream = Upstream::new(config(&pool), CancellationToken::new());
+        tokio::spawn(upstream.clone().run(
+            Arc::new(Router::<(), ()>::new()),
+            (),
+            (),
+            GlobalVars::new(1),
+        ));
+        connected(&upstream).await;
+
+        let queue = SendQueue::new(1, OverflowPolicy::Backpressure);
+        let session = Session::new(
+            ConnectionID::new(),
+            SessionID::default(),
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            queue.clone(),
+            ConfigManager::default(),
+            CancellationToken::new(),
+            (),
+        )
+        .unwrap();
+
+        upstream
+            .forward(
+                &session,
+                ID::Num(42),
+                "mining.authorize",
+                json!(["user", "x"]),
+            )
+            .unwrap();
+
+        let Some(SendInformation::Json(response)) = queue.pop().await else {
+            panic!("expected a JSON response");
+        };
+        assert_eq!(
+            serde_json::from_slice::<Value>(&response).unwrap(),
+            json!({"id": 42, "result": true, "error": null})
+        );
+
+        upstream.shutdown();
+    }
+
+    #[tokio::test]
+    async fn server_forwards_miner_requests() {
+        use crate::{
+            extract::{Id, Pool},
+            StratumServer,
+        };
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        async fn authorize(
+            Pool(upstream): Pool,
+            Id(id): Id,
+            Params(params): Params<Value>,
+            Sess(session): Sess<()>,
+        ) -> Result<bool> {
+            upstream.forward(&session, id, "mining.authorize", params)?;
+            Ok(true)
+        }
+
+        let mut pool = MockPool::start(|request| json!(request["params"][0] == "user"))
+            .await
+            .unwrap();
+
+        let builder = StratumServer::<(), ()>::builder((), 1)
+            .with_host("127.0.0.1")
+            .with_port(0)
+            .with_upstream(config(&pool));
+        #[cfg(feature = "api")]
+        let builder = builder.with_api_port(0);
+
+        let mut server = builder.build().await.unwrap();
+        server.add_without_response("mining.authorize", authorize);
+        let address = server.get_address();
+        let upstream = server.get_upstream().unwrap();
+        let handle = tokio::spawn(async move { server.start().await });
+        connected(&upstream).await;
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(
+                b"{\"id\": 7, \"method\": \"mining.authorize\", \"params\": [\"user\", \"x\"]}\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        BufReader::new(reader)
+            .read_line(&mut response)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&response).unwrap(),
+            json!({"id": 7, "result": true, "error": null})
+        );
+
+        // The pool saw its own id, not the miner's.
+        assert_eq!(pool.next_request().await.unwrap()["id"], 1);
+
+        handle.abort();
+        upstream.shutdown();
+    }
+
+    #[tokio::test]
+    async fn notifications_reach_the_upstream_router() {
+        async fn set_difficulty(
+            Params((difficulty,)): Params<(u64,)>,
+            Sess(session): Sess<UnboundedSender<u64>>,
+        ) -> Result<bool> {
+            session.state().send(difficulty).unwrap();
+            Ok(true)
+        }
+
+        let pool = MockPool::start(|_| Value::Null).await.unwrap();
+        let upstream = Upstream::new(config(&pool), CancellationToken::new());
+
+        let mut router = Router::new();
+        router.add_without_response("mining.set_difficulty", set_difficulty);
+
+        let (tx, mut rx) = unbounded_channel();
+        tokio::spawn(
+            upstream
+                .clone()
+                .run(Arc::new(router), (), tx, GlobalVars::new(1)),
+        );
+        connected(&upstream).await;
+        // A round trip makes sure the pool is serving this connection before notifying it.
+        upstream
+            .request("mining.subscribe", json!([]))
+            .await
+            .unwrap();
+
+        pool.notify("mining.set_difficulty", json!([1024]));
+        assert_eq!(rx.recv().await, Some(1024));
+
+        upstream.shutdown();
+    }
+
+    #[tokio::test]
+    async fn split_lines_survive_outbound_messages() {
+        use tokio::{
+            io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+            net::TcpListener,
+        };
+
+        async fn set_difficulty(
+            Params((difficulty,)): Params<(u64,)>,
+            Sess(session): Sess<UnboundedSender<u64>>,
+        ) -> Result<bool> {
+            session.state().send(difficulty).unwrap();
+            Ok(true)
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = Upstream::new(
+            UpstreamConfig::new(&address.to_string()),
+            CancellationToken::new(),
+        );
+
+        let mut router = Router::new();
+        router.add_without_response("mining.set_difficulty", set_difficulty);
+        let (tx, mut rx) = unbounded_channel();
+        tokio::spawn(
+            upstream
+                .clone()
+                .run(Arc::new(router), (), tx, GlobalVars::new(1)),
+        );
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        // Half a notification arrives, then the upstream sends a request before the rest does.
+        writer
+            .write_all(b"{\"id\":null,\"method\":\"mining.set_difficulty\",")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let request = tokio::spawn({
+            let upstream = upstream.clone();
+            async move { upstream.request("mining.ping", json!([])).await }
+        });
+        let ping: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(ping["method"], "mining.ping");
+
+        writer.write_all(b"\"params\":[1024]}\n").await.unwrap();
+        let response = json!({"id": ping["id"], "result": true, "error": null});
+        writer
+            .write_all(format!("{response}\n").as_bytes())
+            .await
+            .unwrap();
+
+        let difficulty = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        assert_eq!(difficulty.unwrap(), Some(1024));
+        assert_eq!(request.await.unwrap().unwrap(), json!(true));
+
+        upstream.shutdown();
+    }
+
+    #[tokio::test]
+    async fn login_is_sent_again_after_reconnecting() {
+        let mut pool = MockPool::start(|_| json!(true)).await.unwrap();
+        let upstream = Upstream::new(
+            config(&pool)
+                .with_login("mining.subscribe", json!([]))
+                .with_login("mining.authorize", json!(["user", "x"])),
+            CancellationToken::new(),
+        );
+        tokio::spawn(upstream.clone().run(
+            Arc::new(Router::<(), ()>::new()),
+            (),
+            (),
+            GlobalVars::new(1),
+        ));
+
+        for _ in 0..2 {
+            assert_eq!(
+                pool.next_request().await.unwrap()["method"],
+                "mining.subscribe"
+            );
+            assert_eq!(
+                pool.next_request().await.unwrap()["method"],
+                "mining.authorize"
+            );
+            pool.disconnect_all();
+        }
+
+        upstream.shutdown();
+    }
+}
@@ -0,0 +1,38 @@
+//! Typed Stratum V1 messages.
+//!
+//! Stratum V1 sends `params` as positional arrays. Each type here (de)serializes to exactly that
+//! array, so it can be used directly with `StratumRequest::params` or sent to a miner with
+//! `Session::send_notification`.
+
+mod notifications;
+mod params;
+mod requests;
+
+pub use notifications::{Notify, Reconnect, SetDifficulty, SetExtranonce, ShowMessage};
+pub use requests::{Authorize, Submit, Subscribe, SubscribeResult};
+
+use crate::ID;
+use serde::Serialize;
+
+/// The Stratum method name that a set of params belongs to.
+pub trait Method {
+    const METHOD: &'static str;
+}
+
+/// A server initiated message. These always carry a `null` id, as the miner never answers them.
+#[derive(Serialize, Debug)]
+pub struct Notification<'a, T> {
+    pub id: ID,
+    pub method: &'static str,
+    pub params: &'a T,
+}
+
+impl<'a, T: Method> Notification<'a, T> {
+    pub fn new(params: &'a T) -> Self {
+        Notification {
+            id: ID::null(),
+            method: T::METHOD,
+            params,
+        }
+    }
+}
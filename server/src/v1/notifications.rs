@@ -0,0 +1,254 @@
+//! Messages sent from the server to the miner.
+
+use super::{
+    params::{self, Params},
+    Method,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// `mining.notify`: `[job_id, prev_hash, coinbase1, coinbase2, merkle_branches, version, nbits,
+/// ntime, clean_jobs]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Notify {
+    pub job_id: String,
+    pub prev_hash: String,
+    pub coinbase1: String,
+    pub coinbase2: String,
+    pub merkle_branches: Vec<String>,
+    pub version: String,
+    pub nbits: String,
+    pub ntime: String,
+    pub clean_jobs: bool,
+}
+
+impl Method for Notify {
+    const METHOD: &'static str = "mining.notify";
+}
+
+impl<'de> Deserialize<'de> for Notify {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(Notify {
+            job_id: params.string(0, "job_id")?,
+            prev_hash: params.string(1, "prev_hash")?,
+            coinbase1: params.string(2, "coinbase1")?,
+            coinbase2: params.string(3, "coinbase2")?,
+            merkle_branches: params.strings(4, "merkle_branches")?,
+            version: params.string(5, "version")?,
+            nbits: params.string(6, "nbits")?,
+            ntime: params.string(7, "ntime")?,
+            clean_jobs: params.bool(8, "clean_jobs")?,
+        })
+    }
+}
+
+impl Serialize for Notify {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (
+            &self.job_id,
+            &self.prev_hash,
+            &self.coinbase1,
+            &self.coinbase2,
+            &self.merkle_branches,
+            &self.version,
+            &self.nbits,
+            &self.ntime,
+            self.clean_jobs,
+        )
+            .serialize(serializer)
+    }
+}
+
+/// `mining.set_difficulty`: `[difficulty]`. Kept as a float as some pools send fractional
+/// difficulties.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SetDifficulty {
+    pub difficulty: f64,
+}
+
+impl Method for SetDifficulty {
+    const METHOD: &'static str = "mining.set_difficulty";
+}
+
+impl<'de> Deserialize<'de> for SetDifficulty {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(SetDifficulty {
+            difficulty: params.f64(0, "difficulty")?,
+        })
+    }
+}
+
+impl Serialize for SetDifficulty {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Whole difficulties are sent as integers, a number of miners can't parse `1024.0`.
+        if self.difficulty.fract() == 0.0
+            && self.difficulty >= 0.0
+            && self.difficulty < u64::MAX as f64
+        {
+            [self.difficulty as u64].serialize(serializer)
+        } else {
+            [self.difficulty].serialize(serializer)
+        }
+    }
+}
+
+/// `mining.set_extranonce`: `[extranonce1, extranonce2_size]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SetExtranonce {
+    pub extranonce1: String,
+    pub extranonce2_size: usize,
+}
+
+impl Method for SetExtranonce {
+    const METHOD: &'static str = "mining.set_extranonce";
+}
+
+impl<'de> Deserialize<'de> for SetExtranonce {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+        let extranonce2_size = params.u64(1, "extranonce2_size")?;
+
+        Ok(SetExtranonce {
+            extranonce1: params.string(0, "extranonce1")?,
+            extranonce2_size: usize::try_from(extranonce2_size).map_err(de::Error::custom)?,
+        })
+    }
+}
+
+impl Serialize for SetExtranonce {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (&self.extranonce1, self.extranonce2_size).serialize(serializer)
+    }
+}
+
+/// `client.reconnect`: `[host, port, wait_time]`. With no params the miner reconnects to the
+/// server it is currently connected to.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Reconnect {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Seconds the miner should wait before reconnecting.
+    pub wait_time: Option<u64>,
+}
+
+impl Method for Reconnect {
+    const METHOD: &'static str = "client.reconnect";
+}
+
+impl<'de> Deserialize<'de> for Reconnect {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(Reconnect {
+            host: params.opt_string(0, "host")?,
+            port: params.opt_u16(1, "port")?,
+            wait_time: params.opt_u64(2, "wait_time")?,
+        })
+    }
+}
+
+impl Serialize for Reconnect {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        params::serialize(
+            vec![
+                self.host.clone().into(),
+                self.port.into(),
+                self.wait_time.into(),
+            ],
+            serializer,
+        )
+    }
+}
+
+/// `client.show_message`: `[message]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ShowMessage {
+    pub message: String,
+}
+
+impl Method for ShowMessage {
+    const METHOD: &'static str = "client.show_message";
+}
+
+impl<'de> Deserialize<'de> for ShowMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(ShowMessage {
+            message: params.string(0, "message")?,
+        })
+    }
+}
+
+impl Serialize for ShowMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [&self.message].serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn notify_round_trips() {
+        let value = json!([
+            "bf",
+            "4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000",
+            "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff20020862062f503253482f04b8864e5008",
+            "072f736c7573682f000000000100f2052a010000001976a914d23fcdf86f7e756a64a7a9688ef9903327048ed988ac00000000",
+            [],
+            "00000002",
+            "1c2ac4af",
+            "504e86b9",
+            false
+        ]);
+
+        let notify: Notify = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(notify.job_id, "bf");
+        assert!(notify.merkle_branches.is_empty());
+        assert!(!notify.clean_jobs);
+
+        assert_eq!(serde_json::to_value(&notify).unwrap(), value);
+    }
+
+    #[test]
+    fn set_difficulty_integers_and_fractions() {
+        let set_difficulty = SetDifficulty { difficulty: 1024.0 };
+        assert_eq!(serde_json::to_value(set_difficulty).unwrap(), json!([1024]));
+
+        let set_difficulty = SetDifficulty { difficulty: 0.5 };
+        assert_eq!(serde_json::to_value(set_difficulty).unwrap(), json!([0.5]));
+
+        let set_difficulty: SetDifficulty = serde_json::from_value(json!(["2048"])).unwrap();
+        assert!((set_difficulty.difficulty - 2048.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn reconnect_omits_unset_params() {
+        assert_eq!(
+            serde_json::to_value(Reconnect::default()).unwrap(),
+            json!([])
+        );
+
+        let reconnect = Reconnect {
+            host: Some("stratum.example.com".to_owned()),
+            port: Some(3333),
+            wait_time: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&reconnect).unwrap(),
+            json!(["stratum.example.com", 3333])
+        );
+
+        let parsed: Reconnect =
+            serde_json::from_value(json!(["stratum.example.com", "3333", 5])).unwrap();
+        assert_eq!(parsed.port, Some(3333));
+        assert_eq!(parsed.wait_time, Some(5));
+    }
+}
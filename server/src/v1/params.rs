@@ -0,0 +1,130 @@
+//! Helpers for reading the positional `params` arrays that Stratum V1 uses. Miners in the wild
+//! disagree on details such as sending ports as strings or job ids as numbers, so these accept
+//! every reasonable representation and treat `null` the same as a missing trailing element.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+pub(crate) struct Params(Vec<Value>);
+
+impl Params {
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::Array(params) => Ok(Params(params)),
+            Value::Null => Ok(Params(Vec::new())),
+            other => Err(de::Error::custom(format!(
+                "expected positional params array, found {other}"
+            ))),
+        }
+    }
+
+    fn get(&self, idx: usize) -> Option<&Value> {
+        self.0.get(idx).filter(|value| !value.is_null())
+    }
+
+    pub(crate) fn string<E: de::Error>(&self, idx: usize, name: &str) -> Result<String, E> {
+        self.opt_string(idx, name)?
+            .ok_or_else(|| E::custom(format!("missing param {idx} ({name})")))
+    }
+
+    pub(crate) fn opt_string<E: de::Error>(
+        &self,
+        idx: usize,
+        name: &str,
+    ) -> Result<Option<String>, E> {
+        match self.get(idx) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(Value::Number(value)) => Ok(Some(value.to_string())),
+            Some(other) => Err(invalid(idx, name, other)),
+        }
+    }
+
+    pub(crate) fn u64<E: de::Error>(&self, idx: usize, name: &str) -> Result<u64, E> {
+        self.opt_u64(idx, name)?
+            .ok_or_else(|| E::custom(format!("missing param {idx} ({name})")))
+    }
+
+    pub(crate) fn opt_u64<E: de::Error>(&self, idx: usize, name: &str) -> Result<Option<u64>, E> {
+        match self.get(idx) {
+            None => Ok(None),
+            Some(Value::Number(value)) => value
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| invalid(idx, name, &Value::Number(value.clone()))),
+            Some(Value::String(value)) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(idx, name, &Value::String(value.clone()))),
+            Some(other) => Err(invalid(idx, name, other)),
+        }
+    }
+
+    pub(crate) fn opt_u16<E: de::Error>(&self, idx: usize, name: &str) -> Result<Option<u16>, E> {
+        self.opt_u64(idx, name)?
+            .map(|value| u16::try_from(value).map_err(|_| invalid(idx, name, &Value::from(value))))
+            .transpose()
+    }
+
+    pub(crate) fn f64<E: de::Error>(&self, idx: usize, name: &str) -> Result<f64, E> {
+        match self.get(idx) {
+            Some(Value::Number(value)) => value
+                .as_f64()
+                .ok_or_else(|| invalid(idx, name, &Value::Number(value.clone()))),
+            Some(Value::String(value)) => value
+                .trim()
+                .parse()
+                .map_err(|_| invalid(idx, name, &Value::String(value.clone()))),
+            Some(other) => Err(invalid(idx, name, other)),
+            None => Err(E::custom(format!("missing param {idx} ({name})"))),
+        }
+    }
+
+    pub(crate) fn bool<E: de::Error>(&self, idx: usize, name: &str) -> Result<bool, E> {
+        match self.get(idx) {
+            Some(Value::Bool(value)) => Ok(*value),
+            Some(Value::Number(value)) if value.as_u64() == Some(0) => Ok(false),
+            Some(Value::Number(value)) if value.as_u64() == Some(1) => Ok(true),
+            Some(other) => Err(invalid(idx, name, other)),
+            None => Err(E::custom(format!("missing param {idx} ({name})"))),
+        }
+    }
+
+    pub(crate) fn strings<E: de::Error>(&self, idx: usize, name: &str) -> Result<Vec<String>, E> {
+        match self.get(idx) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(value) => Ok(value.clone()),
+                    other => Err(invalid(idx, name, other)),
+                })
+                .collect(),
+            Some(other) => Err(invalid(idx, name, other)),
+        }
+    }
+
+    pub(crate) fn value(&self, idx: usize) -> Option<&Value> {
+        self.get(idx)
+    }
+}
+
+fn invalid<E: de::Error>(idx: usize, name: &str, value: &Value) -> E {
+    E::custom(format!("invalid param {idx} ({name}): {value}"))
+}
+
+/// Serializes positional params, dropping any trailing `null`s so that optional fields which were
+/// never set are omitted entirely.
+pub(crate) fn serialize<S: Serializer>(
+    mut params: Vec<Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    while matches!(params.last(), Some(Value::Null)) {
+        params.pop();
+    }
+
+    params.serialize(serializer)
+}
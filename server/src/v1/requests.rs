@@ -0,0 +1,295 @@
+//! Messages sent from the miner to the server.
+
+use super::{
+    params::{self, Params},
+    Method,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// `mining.subscribe`: `[user_agent, session_id, host, port]`, all of which are optional.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Subscribe {
+    pub user_agent: Option<String>,
+    /// The extranonce1 of a previous session that the miner would like to resume.
+    pub session_id: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl Method for Subscribe {
+    const METHOD: &'static str = "mining.subscribe";
+}
+
+impl<'de> Deserialize<'de> for Subscribe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(Subscribe {
+            user_agent: params.opt_string(0, "user_agent")?,
+            session_id: params.opt_string(1, "session_id")?,
+            host: params.opt_string(2, "host")?,
+            port: params.opt_u16(3, "port")?,
+        })
+    }
+}
+
+impl Serialize for Subscribe {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        params::serialize(
+            vec![
+                self.user_agent.clone().into(),
+                self.session_id.clone().into(),
+                self.host.clone().into(),
+                self.port.into(),
+            ],
+            serializer,
+        )
+    }
+}
+
+/// The result of a successful `mining.subscribe`:
+/// `[[["mining.set_difficulty", id], ["mining.notify", id]], extranonce1, extranonce2_size]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SubscribeResult {
+    /// Pairs of (method, subscription id).
+    pub subscriptions: Vec<(String, String)>,
+    pub extranonce1: String,
+    pub extranonce2_size: usize,
+}
+
+impl SubscribeResult {
+    /// Subscribes the miner to both `mining.set_difficulty` and `mining.notify` under a single
+    /// subscription id.
+    pub fn new(
+        subscription_id: &str,
+        extranonce1: impl Into<String>,
+        extranonce2_size: usize,
+    ) -> Self {
+        SubscribeResult {
+            subscriptions: vec![
+                (
+                    super::SetDifficulty::METHOD.to_owned(),
+                    subscription_id.to_owned(),
+                ),
+                (super::Notify::METHOD.to_owned(), subscription_id.to_owned()),
+            ],
+            extranonce1: extranonce1.into(),
+            extranonce2_size,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SubscribeResult {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        // Some pools send a single pair rather than a list of pairs.
+        let subscriptions = match params.value(0) {
+            None => Vec::new(),
+            Some(Value::Array(pairs)) if pairs.iter().all(Value::is_string) => {
+                vec![Vec::<String>::deserialize(Value::Array(pairs.clone()))
+                    .map_err(de::Error::custom)?]
+            }
+            Some(pairs) => {
+                Vec::<Vec<String>>::deserialize(pairs.clone()).map_err(de::Error::custom)?
+            }
+        }
+        .into_iter()
+        .filter_map(|pair| match pair.as_slice() {
+            [method, id, ..] => Some((method.clone(), id.clone())),
+            _ => None,
+        })
+        .collect();
+
+        let extranonce2_size = params.u64(2, "extranonce2_size")?;
+
+        Ok(SubscribeResult {
+            subscriptions,
+            extranonce1: params.string(1, "extranonce1")?,
+            extranonce2_size: usize::try_from(extranonce2_size).map_err(de::Error::custom)?,
+        })
+    }
+}
+
+impl Serialize for SubscribeResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let subscriptions: Vec<[&str; 2]> = self
+            .subscriptions
+            .iter()
+            .map(|(method, id)| [method.as_str(), id.as_str()])
+            .collect();
+
+        (&subscriptions, &self.extranonce1, self.extranonce2_size).serialize(serializer)
+    }
+}
+
+/// `mining.authorize`: `[username, password]`. Many miners omit the password entirely.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Authorize {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+impl Method for Authorize {
+    const METHOD: &'static str = "mining.authorize";
+}
+
+impl<'de> Deserialize<'de> for Authorize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(Authorize {
+            username: params.string(0, "username")?,
+            password: params.opt_string(1, "password")?,
+        })
+    }
+}
+
+impl Serialize for Authorize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        params::serialize(
+            vec![self.username.clone().into(), self.password.clone().into()],
+            serializer,
+        )
+    }
+}
+
+/// `mining.submit`: `[username, job_id, extranonce2, ntime, nonce, version_bits]`. The version
+/// bits are only sent by miners that negotiated version rolling.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Submit {
+    pub username: String,
+    pub job_id: String,
+    pub extranonce2: String,
+    pub ntime: String,
+    pub nonce: String,
+    pub version_bits: Option<String>,
+}
+
+impl Method for Submit {
+    const METHOD: &'static str = "mining.submit";
+}
+
+impl<'de> Deserialize<'de> for Submit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+
+        Ok(Submit {
+            username: params.string(0, "username")?,
+            job_id: params.string(1, "job_id")?,
+            extranonce2: params.string(2, "extranonce2")?,
+            ntime: params.string(3, "ntime")?,
+            nonce: params.string(4, "nonce")?,
+            version_bits: params.opt_string(5, "version_bits")?,
+        })
+    }
+}
+
+impl Serialize for Submit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        params::serialize(
+            vec![
+                self.username.clone().into(),
+                self.job_id.clone().into(),
+                self.extranonce2.clone().into(),
+                self.ntime.clone().into(),
+                self.nonce.clone().into(),
+                self.version_bits.clone().into(),
+            ],
+            serializer,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn subscribe_accepts_empty_and_partial_params() {
+        let subscribe: Subscribe = serde_json::from_value(json!([])).unwrap();
+        assert_eq!(subscribe, Subscribe::default());
+
+        let subscribe: Subscribe = serde_json::from_value(json!(null)).unwrap();
+        assert_eq!(subscribe, Subscribe::default());
+
+        let subscribe: Subscribe =
+            serde_json::from_value(json!(["cgminer/4.10.0", null, "pool.example.com", "3333"]))
+                .unwrap();
+        assert_eq!(subscribe.user_agent.as_deref(), Some("cgminer/4.10.0"));
+        assert_eq!(subscribe.session_id, None);
+        assert_eq!(subscribe.port, Some(3333));
+    }
+
+    #[test]
+    fn subscribe_result_round_trips() {
+        let result = SubscribeResult::new("deadbeef", "08000002", 4);
+        let value = serde_json::to_value(&result).unwrap();
+
+        assert_eq!(
+            value,
+            json!([
+                [
+                    ["mining.set_difficulty", "deadbeef"],
+                    ["mining.notify", "deadbeef"]
+                ],
+                "08000002",
+                4
+            ])
+        );
+        assert_eq!(
+            serde_json::from_value::<SubscribeResult>(value).unwrap(),
+            result
+        );
+
+        let single: SubscribeResult = serde_json::from_value(json!([
+            ["mining.notify", "ae6812eb4cd7735a302a8a9dd95cf71f"],
+            "08000002",
+            4
+        ]))
+        .unwrap();
+        assert_eq!(single.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn authorize_without_password() {
+        let authorize: Authorize = serde_json::from_value(json!(["slush.miner1"])).unwrap();
+        assert_eq!(authorize.username, "slush.miner1");
+        assert_eq!(authorize.password, None);
+
+        assert!(serde_json::from_value::<Authorize>(json!([])).is_err());
+    }
+
+    #[test]
+    fn submit_with_and_without_version_bits() {
+        let submit: Submit = serde_json::from_value(json!([
+            "slush.miner1",
+            "bf",
+            "00000001",
+            "504e86ed",
+            "b2957c02"
+        ]))
+        .unwrap();
+        assert_eq!(submit.version_bits, None);
+        assert_eq!(
+            serde_json::to_value(&submit).unwrap(),
+            json!(["slush.miner1", "bf", "00000001", "504e86ed", "b2957c02"])
+        );
+
+        let submit: Submit = serde_json::from_value(json!([
+            "slush.miner1",
+            191,
+            "00000001",
+            "504e86ed",
+            "b2957c02",
+            "00002000"
+        ]))
+        .unwrap();
+        assert_eq!(submit.job_id, "191");
+        assert_eq!(submit.version_bits.as_deref(), Some("00002000"));
+
+        assert!(serde_json::from_value::<Submit>(json!(["slush.miner1", "bf"])).is_err());
+    }
+}
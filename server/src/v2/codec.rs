@@ -0,0 +1,277 @@
+//! The SV2 frame header and the primitive data types used by message payloads. Everything is
+//! little endian.
+
+use super::{Error, Message};
+use bytes::{BufMut, Bytes};
+
+/// `extension_type: U16, msg_type: U8, msg_length: U24`.
+pub const HEADER_SIZE: usize = 6;
+/// The largest payload that fits in the 24-bit `msg_length`.
+pub const MAX_PAYLOAD_SIZE: usize = 0xFF_FFFF;
+
+/// The high bit of `extension_type` marks messages addressed to a specific channel.
+const CHANNEL_MSG_BIT: u16 = 0x8000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    /// The extension type, including the channel message bit.
+    pub extension_type: u16,
+    pub msg_type: u8,
+    pub msg_length: u32,
+}
+
+impl Header {
+    #[must_use]
+    pub fn decode(buf: [u8; HEADER_SIZE]) -> Self {
+        Header {
+            extension_type: u16::from_le_bytes([buf[0], buf[1]]),
+            msg_type: buf[2],
+            msg_length: u32::from_le_bytes([buf[3], buf[4], buf[5], 0]),
+        }
+    }
+
+    #[must_use]
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let extension_type = self.extension_type.to_le_bytes();
+        let msg_length = self.msg_length.to_le_bytes();
+
+        [
+            extension_type[0],
+            extension_type[1],
+            self.msg_type,
+            msg_length[0],
+            msg_length[1],
+            msg_length[2],
+        ]
+    }
+
+    /// The extension type without the channel message bit. `0` is the core protocol.
+    #[must_use]
+    pub fn extension(&self) -> u16 {
+        self.extension_type & !CHANNEL_MSG_BIT
+    }
+
+    #[must_use]
+    pub fn channel_msg(&self) -> bool {
+        self.extension_type & CHANNEL_MSG_BIT != 0
+    }
+}
+
+/// A single SV2 frame with its payload still encoded.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub header: Header,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_message<M: Message>(message: &M) -> Result<Self, Error> {
+        let mut payload = Vec::new();
+        message.encode(&mut payload)?;
+
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(Error::PayloadTooLarge(payload.len()));
+        }
+
+        let extension_type = if M::CHANNEL_MSG {
+            M::EXTENSION_TYPE | CHANNEL_MSG_BIT
+        } else {
+            M::EXTENSION_TYPE
+        };
+
+        Ok(Frame {
+            header: Header {
+                extension_type,
+                msg_type: M::MSG_TYPE,
+                msg_length: payload.len() as u32,
+            },
+            payload: payload.into(),
+        })
+    }
+
+    /// Decodes the payload as `M`, checking that the frame actually carries that message type.
+    pub fn message<M: Message>(&self) -> Result<M, Error> {
+        if self.header.extension() != M::EXTENSION_TYPE || self.header.msg_type != M::MSG_TYPE {
+            return Err(Error::WrongMessageType {
+                expected: M::MSG_TYPE,
+                found: self.header.msg_type,
+            });
+        }
+
+        M::decode(&self.payload)
+    }
+
+    /// The name the frame is routed by. Messages of unknown extensions or types are routed as
+    /// `"Unknown"`, which ends up at the Router's fallback unless a route is added for it.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        if self.header.extension() != 0 {
+            return "Unknown";
+        }
+
+        match self.header.msg_type {
+            0x00 => "SetupConnection",
+            0x01 => "SetupConnection.Success",
+            0x02 => "SetupConnection.Error",
+            0x03 => "ChannelEndpointChanged",
+            0x10 => "OpenStandardMiningChannel",
+            0x11 => "OpenStandardMiningChannel.Success",
+            0x12 => "OpenMiningChannel.Error",
+            0x13 => "OpenExtendedMiningChannel",
+            0x14 => "OpenExtendedMiningChannel.Success",
+            0x15 => "NewMiningJob",
+            0x16 => "UpdateChannel",
+            0x17 => "UpdateChannel.Error",
+            0x18 => "CloseChannel",
+            0x19 => "SetExtranoncePrefix",
+            0x1a => "SubmitSharesStandard",
+            0x1b => "SubmitSharesExtended",
+            0x1c => "SubmitShares.Success",
+            0x1d => "SubmitShares.Error",
+            0x1f => "NewExtendedMiningJob",
+            0x20 => "SetNewPrevHash",
+            0x21 => "SetTarget",
+            0x22 => "SetCustomMiningJob",
+            0x23 => "SetCustomMiningJob.Success",
+            0x24 => "SetCustomMiningJob.Error",
+            0x25 => "Reconnect",
+            0x26 => "SetGroupChannel",
+            _ => "Unknown",
+        }
+    }
+
+    /// The plaintext frame, header followed by payload.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        buf.extend_from_slice(&self.header.encode());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+/// Reads the primitive SV2 data types off a payload.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    read: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, read: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::UnexpectedEnd(self.read + self.buf.len()));
+        }
+
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        self.read += len;
+
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u256(&mut self) -> Result<[u8; 32], Error> {
+        self.array()
+    }
+
+    /// `B0_255`, a byte string prefixed with a `U8` length.
+    pub(crate) fn b0_255(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.u8()?;
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    /// `STR0_255`, a UTF-8 string prefixed with a `U8` length.
+    pub(crate) fn str0_255(&mut self, name: &'static str) -> Result<String, Error> {
+        String::from_utf8(self.b0_255()?).map_err(|_| Error::InvalidString(name))
+    }
+
+    /// `OPTION[U32]`, encoded as a sequence of zero or one elements.
+    pub(crate) fn option_u32(&mut self) -> Result<Option<u32>, Error> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u32()?)),
+        }
+    }
+}
+
+/// Writes the variable length SV2 data types. Fixed width types are written with `BufMut`.
+pub(crate) trait WriteExt: BufMut {
+    fn put_b0_255(&mut self, value: &[u8], name: &'static str) -> Result<(), Error> {
+        let len = u8::try_from(value.len()).map_err(|_| Error::FieldTooLong(name))?;
+        self.put_u8(len);
+        self.put_slice(value);
+        Ok(())
+    }
+
+    fn put_option_u32(&mut self, value: Option<u32>) {
+        match value {
+            Some(value) => {
+                self.put_u8(1);
+                self.put_u32_le(value);
+            }
+            None => self.put_u8(0),
+        }
+    }
+}
+
+impl WriteExt for Vec<u8> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header {
+            extension_type: 0x8000,
+            msg_type: 0x1a,
+            msg_length: 0x01_0203,
+        };
+
+        let encoded = header.encode();
+        assert_eq!(encoded, [0x00, 0x80, 0x1a, 0x03, 0x02, 0x01]);
+
+        let decoded = Header::decode(encoded);
+        assert_eq!(decoded, header);
+        assert!(decoded.channel_msg());
+        assert_eq!(decoded.extension(), 0);
+    }
+
+    #[test]
+    fn reader_reports_short_payloads() {
+        let mut reader = Reader::new(&[1, 0, 0]);
+
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert!(matches!(reader.u32(), Err(Error::UnexpectedEnd(3))));
+    }
+}
@@ -0,0 +1,45 @@
+use super::{noise::NoiseKeys, Error};
+use std::time::Duration;
+
+/// Settings for the SV2 listener.
+#[derive(Clone, Debug)]
+pub struct V2Config {
+    pub(crate) port: u16,
+    pub(crate) authority_secret_key: [u8; 32],
+    pub(crate) static_secret_key: Option<[u8; 32]>,
+    pub(crate) cert_validity: Duration,
+}
+
+impl V2Config {
+    /// Listens for SV2 miners on `port`. Miners verify the server with the public key of
+    /// `authority_secret_key`, see [`V2Config::authority_public_key`].
+    #[must_use]
+    pub fn new(port: u16, authority_secret_key: [u8; 32]) -> Self {
+        V2Config {
+            port,
+            authority_secret_key,
+            static_secret_key: None,
+            cert_validity: Duration::from_secs(3600),
+        }
+    }
+
+    /// The server's static Noise key. A new key is generated on startup if this is not set, which
+    /// is fine as miners only pin the authority key.
+    #[must_use]
+    pub fn with_static_secret_key(mut self, key: [u8; 32]) -> Self {
+        self.static_secret_key = Some(key);
+        self
+    }
+
+    /// How long the certificate sent during each handshake stays valid.
+    #[must_use]
+    pub fn with_cert_validity(mut self, validity: Duration) -> Self {
+        self.cert_validity = validity;
+        self
+    }
+
+    /// The x-only authority public key that miners need to be configured with.
+    pub fn authority_public_key(&self) -> Result<[u8; 32], Error> {
+        Ok(NoiseKeys::new(self)?.authority_public_key())
+    }
+}
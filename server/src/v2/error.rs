@@ -0,0 +1,23 @@
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Frame ended after {0} bytes, expected more")]
+    UnexpectedEnd(usize),
+    #[error("Frame payload of {0} bytes exceeds the maximum length")]
+    PayloadTooLarge(usize),
+    #[error("Field {0} is longer than its encoding allows")]
+    FieldTooLong(&'static str),
+    #[error("Field {0} is not valid UTF-8")]
+    InvalidString(&'static str),
+    #[error("Expected message type {expected:#04x}, found {found:#04x}")]
+    WrongMessageType { expected: u8, found: u8 },
+    #[error("Request is not an SV2 frame")]
+    NotV2Frame,
+    #[error("Invalid key: {0}")]
+    InvalidKey(#[from] secp256k1::Error),
+    #[error("Noise handshake timed out")]
+    HandshakeTimeout,
+    #[error("Failed to decrypt Noise message")]
+    Decrypt,
+    #[error("Failed to encrypt Noise message")]
+    Encrypt,
+}
@@ -0,0 +1,577 @@
+//! Typed payloads for the common and mining protocol messages.
+
+use super::{
+    codec::{Reader, WriteExt},
+    Error,
+};
+use bytes::BufMut;
+
+/// An SV2 message payload.
+pub trait Message: Sized {
+    /// The extension the message belongs to, without the channel message bit.
+    const EXTENSION_TYPE: u16 = 0;
+    const MSG_TYPE: u8;
+    /// Whether the message is addressed to a specific channel.
+    const CHANNEL_MSG: bool;
+    /// The name used to route the message, as it appears in the specification.
+    const NAME: &'static str;
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error>;
+
+    fn decode(payload: &[u8]) -> Result<Self, Error>;
+}
+
+/// The first message sent by a client, negotiating the protocol version and features.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SetupConnection {
+    /// `0` for the mining protocol.
+    pub protocol: u8,
+    pub min_version: u16,
+    pub max_version: u16,
+    pub flags: u32,
+    pub endpoint_host: String,
+    pub endpoint_port: u16,
+    pub vendor: String,
+    pub hardware_version: String,
+    pub firmware: String,
+    pub device_id: String,
+}
+
+impl Message for SetupConnection {
+    const MSG_TYPE: u8 = 0x00;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "SetupConnection";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u8(self.protocol);
+        buf.put_u16_le(self.min_version);
+        buf.put_u16_le(self.max_version);
+        buf.put_u32_le(self.flags);
+        buf.put_b0_255(self.endpoint_host.as_bytes(), "endpoint_host")?;
+        buf.put_u16_le(self.endpoint_port);
+        buf.put_b0_255(self.vendor.as_bytes(), "vendor")?;
+        buf.put_b0_255(self.hardware_version.as_bytes(), "hardware_version")?;
+        buf.put_b0_255(self.firmware.as_bytes(), "firmware")?;
+        buf.put_b0_255(self.device_id.as_bytes(), "device_id")
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SetupConnection {
+            protocol: reader.u8()?,
+            min_version: reader.u16()?,
+            max_version: reader.u16()?,
+            flags: reader.u32()?,
+            endpoint_host: reader.str0_255("endpoint_host")?,
+            endpoint_port: reader.u16()?,
+            vendor: reader.str0_255("vendor")?,
+            hardware_version: reader.str0_255("hardware_version")?,
+            firmware: reader.str0_255("firmware")?,
+            device_id: reader.str0_255("device_id")?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SetupConnectionSuccess {
+    pub used_version: u16,
+    pub flags: u32,
+}
+
+impl Message for SetupConnectionSuccess {
+    const MSG_TYPE: u8 = 0x01;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "SetupConnection.Success";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u16_le(self.used_version);
+        buf.put_u32_le(self.flags);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SetupConnectionSuccess {
+            used_version: reader.u16()?,
+            flags: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SetupConnectionError {
+    /// The flags the server doesn't support.
+    pub flags: u32,
+    pub error_code: String,
+}
+
+impl Message for SetupConnectionError {
+    const MSG_TYPE: u8 = 0x02;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "SetupConnection.Error";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.flags);
+        buf.put_b0_255(self.error_code.as_bytes(), "error_code")
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SetupConnectionError {
+            flags: reader.u32()?,
+            error_code: reader.str0_255("error_code")?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChannelEndpointChanged {
+    pub channel_id: u32,
+}
+
+impl Message for ChannelEndpointChanged {
+    const MSG_TYPE: u8 = 0x03;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "ChannelEndpointChanged";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        Ok(ChannelEndpointChanged {
+            channel_id: Reader::new(payload).u32()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct OpenStandardMiningChannel {
+    pub request_id: u32,
+    pub user_identity: String,
+    /// Expected hashes per second of the device.
+    pub nominal_hash_rate: f32,
+    pub max_target: [u8; 32],
+}
+
+impl Message for OpenStandardMiningChannel {
+    const MSG_TYPE: u8 = 0x10;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "OpenStandardMiningChannel";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.request_id);
+        buf.put_b0_255(self.user_identity.as_bytes(), "user_identity")?;
+        buf.put_f32_le(self.nominal_hash_rate);
+        buf.put_slice(&self.max_target);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(OpenStandardMiningChannel {
+            request_id: reader.u32()?,
+            user_identity: reader.str0_255("user_identity")?,
+            nominal_hash_rate: reader.f32()?,
+            max_target: reader.u256()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OpenStandardMiningChannelSuccess {
+    pub request_id: u32,
+    pub channel_id: u32,
+    pub target: [u8; 32],
+    /// At most 32 bytes.
+    pub extranonce_prefix: Vec<u8>,
+    pub group_channel_id: u32,
+}
+
+impl Message for OpenStandardMiningChannelSuccess {
+    const MSG_TYPE: u8 = 0x11;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "OpenStandardMiningChannel.Success";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        if self.extranonce_prefix.len() > 32 {
+            return Err(Error::FieldTooLong("extranonce_prefix"));
+        }
+
+        buf.put_u32_le(self.request_id);
+        buf.put_u32_le(self.channel_id);
+        buf.put_slice(&self.target);
+        buf.put_b0_255(&self.extranonce_prefix, "extranonce_prefix")?;
+        buf.put_u32_le(self.group_channel_id);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        let request_id = reader.u32()?;
+        let channel_id = reader.u32()?;
+        let target = reader.u256()?;
+        let extranonce_prefix = reader.b0_255()?;
+
+        if extranonce_prefix.len() > 32 {
+            return Err(Error::FieldTooLong("extranonce_prefix"));
+        }
+
+        Ok(OpenStandardMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target,
+            extranonce_prefix,
+            group_channel_id: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OpenMiningChannelError {
+    pub request_id: u32,
+    pub error_code: String,
+}
+
+impl Message for OpenMiningChannelError {
+    const MSG_TYPE: u8 = 0x12;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "OpenMiningChannel.Error";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.request_id);
+        buf.put_b0_255(self.error_code.as_bytes(), "error_code")
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(OpenMiningChannelError {
+            request_id: reader.u32()?,
+            error_code: reader.str0_255("error_code")?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NewMiningJob {
+    pub channel_id: u32,
+    pub job_id: u32,
+    /// `None` for future jobs, which only become active with a later `SetNewPrevHash`.
+    pub min_ntime: Option<u32>,
+    pub version: u32,
+    pub merkle_root: [u8; 32],
+}
+
+impl Message for NewMiningJob {
+    const MSG_TYPE: u8 = 0x15;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "NewMiningJob";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_u32_le(self.job_id);
+        buf.put_option_u32(self.min_ntime);
+        buf.put_u32_le(self.version);
+        buf.put_slice(&self.merkle_root);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(NewMiningJob {
+            channel_id: reader.u32()?,
+            job_id: reader.u32()?,
+            min_ntime: reader.option_u32()?,
+            version: reader.u32()?,
+            merkle_root: reader.u256()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SetNewPrevHash {
+    pub channel_id: u32,
+    pub job_id: u32,
+    pub prev_hash: [u8; 32],
+    pub min_ntime: u32,
+    pub nbits: u32,
+}
+
+impl Message for SetNewPrevHash {
+    const MSG_TYPE: u8 = 0x20;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "SetNewPrevHash";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_u32_le(self.job_id);
+        buf.put_slice(&self.prev_hash);
+        buf.put_u32_le(self.min_ntime);
+        buf.put_u32_le(self.nbits);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SetNewPrevHash {
+            channel_id: reader.u32()?,
+            job_id: reader.u32()?,
+            prev_hash: reader.u256()?,
+            min_ntime: reader.u32()?,
+            nbits: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SetTarget {
+    pub channel_id: u32,
+    pub maximum_target: [u8; 32],
+}
+
+impl Message for SetTarget {
+    const MSG_TYPE: u8 = 0x21;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "SetTarget";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_slice(&self.maximum_target);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SetTarget {
+            channel_id: reader.u32()?,
+            maximum_target: reader.u256()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SubmitSharesStandard {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub job_id: u32,
+    pub nonce: u32,
+    pub ntime: u32,
+    pub version: u32,
+}
+
+impl Message for SubmitSharesStandard {
+    const MSG_TYPE: u8 = 0x1a;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "SubmitSharesStandard";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_u32_le(self.sequence_number);
+        buf.put_u32_le(self.job_id);
+        buf.put_u32_le(self.nonce);
+        buf.put_u32_le(self.ntime);
+        buf.put_u32_le(self.version);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SubmitSharesStandard {
+            channel_id: reader.u32()?,
+            sequence_number: reader.u32()?,
+            job_id: reader.u32()?,
+            nonce: reader.u32()?,
+            ntime: reader.u32()?,
+            version: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SubmitSharesSuccess {
+    pub channel_id: u32,
+    pub last_sequence_number: u32,
+    pub new_submits_accepted_count: u32,
+    pub new_shares_sum: u64,
+}
+
+impl Message for SubmitSharesSuccess {
+    const MSG_TYPE: u8 = 0x1c;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "SubmitShares.Success";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_u32_le(self.last_sequence_number);
+        buf.put_u32_le(self.new_submits_accepted_count);
+        buf.put_u64_le(self.new_shares_sum);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SubmitSharesSuccess {
+            channel_id: reader.u32()?,
+            last_sequence_number: reader.u32()?,
+            new_submits_accepted_count: reader.u32()?,
+            new_shares_sum: reader.u64()?,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SubmitSharesError {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub error_code: String,
+}
+
+impl Message for SubmitSharesError {
+    const MSG_TYPE: u8 = 0x1d;
+    const CHANNEL_MSG: bool = true;
+    const NAME: &'static str = "SubmitShares.Error";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_u32_le(self.channel_id);
+        buf.put_u32_le(self.sequence_number);
+        buf.put_b0_255(self.error_code.as_bytes(), "error_code")
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(SubmitSharesError {
+            channel_id: reader.u32()?,
+            sequence_number: reader.u32()?,
+            error_code: reader.str0_255("error_code")?,
+        })
+    }
+}
+
+/// Asks the client to reconnect, to `new_host:new_port` if set or to the same server otherwise.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Reconnect {
+    pub new_host: String,
+    pub new_port: u16,
+}
+
+impl Message for Reconnect {
+    const MSG_TYPE: u8 = 0x25;
+    const CHANNEL_MSG: bool = false;
+    const NAME: &'static str = "Reconnect";
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.put_b0_255(self.new_host.as_bytes(), "new_host")?;
+        buf.put_u16_le(self.new_port);
+        Ok(())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(payload);
+
+        Ok(Reconnect {
+            new_host: reader.str0_255("new_host")?,
+            new_port: reader.u16()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v2::Frame;
+
+    #[test]
+    fn setup_connection_round_trips() {
+        let setup = SetupConnection {
+            protocol: 0,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0001,
+            endpoint_host: "pool.example.com".to_owned(),
+            endpoint_port: 3336,
+            vendor: "Bitmain".to_owned(),
+            hardware_version: "S19".to_owned(),
+            firmware: "braiins-os".to_owned(),
+            device_id: "some-device-uuid".to_owned(),
+        };
+
+        let frame = Frame::from_message(&setup).unwrap();
+        assert_eq!(frame.name(), SetupConnection::NAME);
+        assert!(!frame.header.channel_msg());
+        assert_eq!(frame.header.msg_length as usize, frame.payload.len());
+        assert_eq!(frame.message::<SetupConnection>().unwrap(), setup);
+
+        assert!(matches!(
+            frame.message::<SetupConnectionSuccess>(),
+            Err(Error::WrongMessageType { .. })
+        ));
+    }
+
+    #[test]
+    fn submit_shares_is_a_channel_message() {
+        let submit = SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 2,
+            job_id: 3,
+            nonce: 4,
+            ntime: 5,
+            version: 6,
+        };
+
+        let frame = Frame::from_message(&submit).unwrap();
+        assert!(frame.header.channel_msg());
+        assert_eq!(frame.name(), SubmitSharesStandard::NAME);
+        assert_eq!(frame.payload.len(), 24);
+        assert_eq!(frame.message::<SubmitSharesStandard>().unwrap(), submit);
+    }
+
+    #[test]
+    fn new_mining_job_optional_ntime() {
+        let job = NewMiningJob {
+            channel_id: 1,
+            job_id: 2,
+            min_ntime: None,
+            version: 0x2000_0000,
+            merkle_root: [7; 32],
+        };
+
+        let mut payload = Vec::new();
+        job.encode(&mut payload).unwrap();
+        assert_eq!(payload.len(), 4 + 4 + 1 + 4 + 32);
+        assert_eq!(NewMiningJob::decode(&payload).unwrap(), job);
+
+        let job = NewMiningJob {
+            min_ntime: Some(1_700_000_000),
+            ..job
+        };
+        let mut payload = Vec::new();
+        job.encode(&mut payload).unwrap();
+        assert_eq!(NewMiningJob::decode(&payload).unwrap(), job);
+    }
+
+    #[test]
+    fn strings_longer_than_255_bytes_are_rejected() {
+        let error = SetupConnectionError {
+            flags: 0,
+            error_code: "x".repeat(256),
+        };
+
+        assert!(matches!(
+            error.encode(&mut Vec::new()),
+            Err(Error::FieldTooLong("error_code"))
+        ));
+    }
+}
@@ -0,0 +1,22 @@
+//! Stratum V2.
+//!
+//! SV2 miners connect on their own port (see `V2Config`) and complete a Noise NX handshake before
+//! sending any frames. After that every frame arrives as `Frame::V2` through the same `Router` as
+//! V1, routed by the message name, e.g. `server.add(v2::SubmitSharesStandard::NAME, handler)`.
+//! Frames are sent back with `Session::send_v2`.
+
+mod codec;
+mod config;
+mod error;
+mod messages;
+pub(crate) mod noise;
+
+pub use codec::{Frame, Header, HEADER_SIZE, MAX_PAYLOAD_SIZE};
+pub use config::V2Config;
+pub use error::Error;
+pub use messages::{
+    ChannelEndpointChanged, Message, NewMiningJob, OpenMiningChannelError,
+    OpenStandardMiningChannel, OpenStandardMiningChannelSuccess, Reconnect, SetNewPrevHash,
+    SetTarget, SetupConnection, SetupConnectionError, SetupConnectionSuccess, SubmitSharesError,
+    SubmitSharesStandard, SubmitSharesSuccess,
+};
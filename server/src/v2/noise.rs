@@ -0,0 +1,600 @@
+//! The responder side of the SV2 Noise NX handshake
+//! (`Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256`), and the encrypted transport that follows it.
+//!
+//! The handshake is a single round trip. The miner sends its `ElligatorSwift` encoded ephemeral key
+//! and we answer with our own ephemeral key, our encrypted static key and an encrypted
+//! certificate, which is our static key signed by the authority key the miner has been configured
+//! with.
+
+use super::{Error, Header, V2Config, HEADER_SIZE};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use secp256k1::{
+    ellswift::{ElligatorSwift, ElligatorSwiftParty},
+    rand, All, Keypair, Secp256k1,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256";
+const ELLSWIFT_SIZE: usize = 64;
+const MAC_SIZE: usize = 16;
+/// `version: U16, valid_from: U32, not_valid_after: U32, signature: 64 bytes`.
+const CERTIFICATE_SIZE: usize = 74;
+/// Encrypted payloads are split into chunks of at most this many bytes, MAC included.
+const CHUNK_SIZE: usize = 65535;
+
+pub(crate) const INITIATOR_MESSAGE_SIZE: usize = ELLSWIFT_SIZE;
+pub(crate) const RESPONDER_MESSAGE_SIZE: usize =
+    ELLSWIFT_SIZE + ELLSWIFT_SIZE + MAC_SIZE + CERTIFICATE_SIZE + MAC_SIZE;
+pub(crate) const ENCRYPTED_HEADER_SIZE: usize = HEADER_SIZE + MAC_SIZE;
+
+/// The authority and static keys loaded from `V2Config`.
+#[derive(Clone)]
+pub(crate) struct NoiseKeys {
+    secp: Secp256k1<All>,
+    authority: Keypair,
+    static_key: Keypair,
+    cert_validity: Duration,
+}
+
+impl Debug for NoiseKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKeys")
+            .field("authority", &self.authority.x_only_public_key().0)
+            .field("static_key", &self.static_key.x_only_public_key().0)
+            .field("cert_validity", &self.cert_validity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NoiseKeys {
+    pub(crate) fn new(config: &V2Config) -> Result<Self, Error> {
+        let secp = Secp256k1::new();
+
+        let authority = Keypair::from_seckey_slice(&secp, &config.authority_secret_key)?;
+        let static_key = match config.static_secret_key {
+            Some(key) => Keypair::from_seckey_slice(&secp, &key)?,
+            None => Keypair::new(&secp, &mut rand::thread_rng()),
+        };
+
+        Ok(NoiseKeys {
+            secp,
+            authority,
+            static_key,
+            cert_validity: config.cert_validity,
+        })
+    }
+
+    pub(crate) fn authority_public_key(&self) -> [u8; 32] {
+        self.authority.x_only_public_key().0.serialize()
+    }
+
+    /// Signs our static key with the authority key, valid from now for `cert_validity`.
+    fn certificate(&self) -> [u8; CERTIFICATE_SIZE] {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let valid_from = u32::try_from(now).unwrap_or(u32::MAX);
+        let not_valid_after = u32::try_from(now + self.cert_validity.as_secs()).unwrap_or(u32::MAX);
+
+        let mut certificate = [0; CERTIFICATE_SIZE];
+        certificate[0..2].copy_from_slice(&0_u16.to_le_bytes());
+        certificate[2..6].copy_from_slice(&valid_from.to_le_bytes());
+        certificate[6..10].copy_from_slice(&not_valid_after.to_le_bytes());
+
+        let mut hasher = Sha256::new();
+        hasher.update(&certificate[0..10]);
+        hasher.update(self.static_key.x_only_public_key().0.serialize());
+        let message = secp256k1::Message::from_digest(hasher.finalize().into());
+
+        let signature = self.secp.sign_schnorr(&message, &self.authority);
+        certificate[10..].copy_from_slice(signature.as_ref());
+
+        certificate
+    }
+}
+
+/// Answers the miner's first handshake message. Returns our reply along with the transport
+/// ciphers to use once it has been sent.
+pub(crate) fn respond(
+    keys: &NoiseKeys,
+    initiator_message: [u8; INITIATOR_MESSAGE_SIZE],
+) -> Result<(Vec<u8>, Encryptor, Decryptor), Error> {
+    let mut state = SymmetricState::new();
+
+    let remote_ephemeral = ElligatorSwift::from_array(initiator_message);
+    state.mix_hash(&initiator_message);
+    state.decrypt_and_hash(&mut Vec::new())?;
+
+    let mut reply = Vec::with_capacity(RESPONDER_MESSAGE_SIZE);
+
+    let ephemeral = Keypair::new(&keys.secp, &mut rand::thread_rng());
+    let ephemeral_ellswift = ElligatorSwift::from_pubkey(ephemeral.public_key());
+    reply.extend_from_slice(&ephemeral_ellswift.to_array());
+    state.mix_hash(&ephemeral_ellswift.to_array());
+    state.mix_key(&ecdh(remote_ephemeral, ephemeral_ellswift, &ephemeral));
+
+    let static_ellswift = ElligatorSwift::from_pubkey(keys.static_key.public_key());
+    let mut encrypted_static = static_ellswift.to_array().to_vec();
+    state.encrypt_and_hash(&mut encrypted_static)?;
+    reply.extend_from_slice(&encrypted_static);
+    state.mix_key(&ecdh(remote_ephemeral, static_ellswift, &keys.static_key));
+
+    let mut certificate = keys.certificate().to_vec();
+    state.encrypt_and_hash(&mut certificate)?;
+    reply.extend_from_slice(&certificate);
+
+    // The first cipher encrypts from the initiator to us, the second from us to the initiator.
+    let (initiator, responder) = state.split();
+
+    Ok((reply, Encryptor(responder), Decryptor(initiator)))
+}
+
+/// `ElligatorSwift` x-only ECDH as the responder, as described in BIP324.
+fn ecdh(initiator: ElligatorSwift, responder: ElligatorSwift, keypair: &Keypair) -> [u8; 32] {
+    ElligatorSwift::shared_secret(
+        initiator,
+        responder,
+        keypair.secret_key(),
+        ElligatorSwiftParty::B,
+        None,
+    )
+    .to_secret_bytes()
+}
+
+/// Encrypts outgoing frames.
+#[derive(Debug)]
+pub(crate) struct Encryptor(CipherState);
+
+impl Encryptor {
+    /// Encrypts a plaintext frame, the header on its own followed by the payload in chunks.
+    pub(crate) fn encrypt_frame(&mut self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        if frame.len() < HEADER_SIZE {
+            return Err(Error::UnexpectedEnd(frame.len()));
+        }
+
+        let (header, payload) = frame.split_at(HEADER_SIZE);
+        let mut encrypted =
+            Vec::with_capacity(ENCRYPTED_HEADER_SIZE + encrypted_len(payload.len()));
+
+        let mut chunk = header.to_vec();
+        self.0.encrypt(&[], &mut chunk)?;
+        encrypted.extend_from_slice(&chunk);
+
+        for plaintext in payload.chunks(CHUNK_SIZE - MAC_SIZE) {
+            let mut chunk = plaintext.to_vec();
+            self.0.encrypt(&[], &mut chunk)?;
+            encrypted.extend_from_slice(&chunk);
+        }
+
+        Ok(encrypted)
+    }
+}
+
+/// Decrypts incoming frames.
+#[derive(Debug)]
+pub(crate) struct Decryptor(CipherState);
+
+impl Decryptor {
+    pub(crate) fn decrypt_header(
+        &mut self,
+        encrypted: [u8; ENCRYPTED_HEADER_SIZE],
+    ) -> Result<Header, Error> {
+        let mut header = encrypted.to_vec();
+        self.0.decrypt(&[], &mut header)?;
+
+        let mut plaintext = [0; HEADER_SIZE];
+        plaintext.copy_from_slice(&header);
+
+        Ok(Header::decode(plaintext))
+    }
+
+    /// Decrypts a payload of `encrypted_len(header.msg_length)` bytes.
+    pub(crate) fn decrypt_payload(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::with_capacity(encrypted.len());
+
+        for ciphertext in encrypted.chunks(CHUNK_SIZE) {
+            let mut chunk = ciphertext.to_vec();
+            self.0.decrypt(&[], &mut chunk)?;
+            payload.extend_from_slice(&chunk);
+        }
+
+        Ok(payload)
+    }
+}
+
+/// The size of a payload of `len` bytes once encrypted, including a MAC for every chunk.
+pub(crate) fn encrypted_len(len: usize) -> usize {
+    let chunks = (len + CHUNK_SIZE - MAC_SIZE - 1) / (CHUNK_SIZE - MAC_SIZE);
+    len + chunks * MAC_SIZE
+}
+
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl Debug for CipherState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CipherState")
+            .field("nonce", &self.nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            nonce: 0,
+        }
+    }
+
+    /// 32 bits of zeros followed by the little endian counter.
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        nonce.into()
+    }
+
+    fn encrypt(&mut self, ad: &[u8], buf: &mut Vec<u8>) -> Result<(), Error> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt_in_place(&nonce, ad, buf)
+            .map_err(|_| Error::Encrypt)
+    }
+
+    fn decrypt(&mut self, ad: &[u8], buf: &mut Vec<u8>) -> Result<(), Error> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt_in_place(&nonce, ad, buf)
+            .map_err(|_| Error::Decrypt)
+    }
+}
+
+struct SymmetricState {
+    h: [u8; 32],
+    ck: [u8; 32],
+    k: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let ck: [u8; 32] = Sha256::digest(PROTOCOL_NAME).into();
+
+        let mut state = SymmetricState { h: ck, ck, k: None };
+        // The prologue is empty.
+        state.mix_hash(&[]);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (ck, k) = hkdf(&self.ck, input_key_material);
+        self.ck = ck;
+        self.k = Some(CipherState::new(k));
+    }
+
+    fn encrypt_and_hash(&mut self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        if let Some(k) = &mut self.k {
+            k.encrypt(&self.h, buf)?;
+        }
+        self.mix_hash(buf);
+        Ok(())
+    }
+
+    fn decrypt_and_hash(&mut self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let ciphertext = buf.clone();
+        if let Some(k) = &mut self.k {
+            k.decrypt(&self.h, buf)?;
+        }
+        self.mix_hash(&ciphertext);
+        Ok(())
+    }
+
+    fn split(self) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        (CipherState::new(k1), CipherState::new(k2))
+    }
+}
+
+fn hmac(key: &[u8; 32], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for data in data {
+        mac.update(data);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// HKDF with two outputs, as defined by Noise.
+fn hkdf(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp_key = hmac(chaining_key, &[input_key_material]);
+    let output1 = hmac(&temp_key, &[&[0x01]]);
+    let output2 = hmac(&temp_key, &[&output1, &[0x02]]);
+    (output1, output2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v2::{Frame, SetupConnectionSuccess};
+    use secp256k1::{schnorr::Signature, PublicKey, XOnlyPublicKey};
+
+    /// The miner's side of the handshake.
+    fn initiate(
+        authority: &XOnlyPublicKey,
+        respond: impl FnOnce([u8; INITIATOR_MESSAGE_SIZE]) -> Vec<u8>,
+    ) -> (CipherState, CipherState) {
+        let secp = Secp256k1::new();
+        let mut state = SymmetricState::new();
+
+        let ephemeral = Keypair::new(&secp, &mut rand::thread_rng());
+        let ephemeral_ellswift = ElligatorSwift::from_pubkey(ephemeral.public_key());
+        state.mix_hash(&ephemeral_ellswift.to_array());
+        state.encrypt_and_hash(&mut Vec::new()).unwrap();
+
+        let reply = respond(ephemeral_ellswift.to_array());
+        assert_eq!(reply.len(), RESPONDER_MESSAGE_SIZE);
+
+        let remote_ephemeral =
+            ElligatorSwift::from_array(reply[..ELLSWIFT_SIZE].try_into().unwrap());
+        state.mix_hash(&reply[..ELLSWIFT_SIZE]);
+        state.mix_key(
+            &ElligatorSwift::shared_secret(
+                ephemeral_ellswift,
+                remote_ephemeral,
+                ephemeral.secret_key(),
+                ElligatorSwiftParty::A,
+                None,
+            )
+            .to_secret_bytes(),
+        );
+
+        let static_end = ELLSWIFT_SIZE * 2 + MAC_SIZE;
+        let mut remote_static = reply[ELLSWIFT_SIZE..static_end].to_vec();
+        state.decrypt_and_hash(&mut remote_static).unwrap();
+        let remote_static = ElligatorSwift::from_array(remote_static.try_into().unwrap());
+        state.mix_key(
+            &ElligatorSwift::shared_secret(
+                ephemeral_ellswift,
+                remote_static,
+                ephemeral.secret_key(),
+                ElligatorSwiftParty::A,
+                None,
+            )
+            .to_secret_bytes(),
+        );
+
+        let mut certificate = reply[static_end..].to_vec();
+        state.decrypt_and_hash(&mut certificate).unwrap();
+
+        let mut hasher = Sha256::new();
+        hasher.update(&certificate[0..10]);
+        hasher.update(
+            PublicKey::from_ellswift(remote_static)
+                .x_only_public_key()
+                .0
+                .serialize(),
+        );
+        let message = secp256k1::Message::from_digest(hasher.finalize().into());
+        let signature = Signature::from_slice(&certificate[10..]).unwrap();
+        secp.verify_schnorr(&signature, &message, authority)
+            .expect("certificate is signed by the authority");
+
+        state.split()
+    }
+
+    #[test]
+    fn handshake_and_transport() {
+        let config = V2Config::new(0, [0x42; 32]);
+        let keys = NoiseKeys::new(&config).unwrap();
+        let authority =
+            XOnlyPublicKey::from_slice(&config.authority_public_key().unwrap()).unwrap();
+
+        let mut server = None;
+        let (mut to_server, mut from_server) = initiate(&authority, |message| {
+            let (reply, encryptor, decryptor) = respond(&keys, message).unwrap();
+            server = Some((encryptor, decryptor));
+            reply
+        });
+        let (mut encryptor, mut decryptor) = server.unwrap();
+
+        // Miner to server.
+        let frame = Frame::from_message(&SetupConnectionSuccess {
+            used_version: 2,
+            flags: 0,
+        })
+        .unwrap();
+        let plaintext = frame.encode();
+
+        let mut header = plaintext[..HEADER_SIZE].to_vec();
+        to_server.encrypt(&[], &mut header).unwrap();
+        let mut payload = plaintext[HEADER_SIZE..].to_vec();
+        to_server.encrypt(&[], &mut payload).unwrap();
+
+        let decrypted = decryptor
+            .decrypt_header(header.try_into().unwrap())
+            .unwrap();
+        assert_eq!(decrypted, frame.header);
+        assert_eq!(payload.len(), encrypted_len(frame.payload.len()));
+        assert_eq!(decryptor.decrypt_payload(&payload).unwrap(), frame.payload);
+
+        // Server to miner.
+        let mut encrypted = encryptor.encrypt_frame(&plaintext).unwrap();
+        let mut payload = encrypted.split_off(ENCRYPTED_HEADER_SIZE);
+        from_server.decrypt(&[], &mut encrypted).unwrap();
+        from_server.decrypt(&[], &mut payload).unwrap();
+        assert_eq!([encrypted, payload].concat(), plaintext);
+
+        // A tampered frame is rejected.
+        let mut tampered = plaintext[..HEADER_SIZE].to_vec();
+        to_server.encrypt(&[], &mut tampered).unwrap();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            decryptor.decrypt_header(tampered.try_into().unwrap()),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn setup_connection_over_tcp() {
+        use crate::{
+            extract::{Sess, V2Message},
+            v2::SetupConnection,
+            StratumServer,
+        };
+        use std::io::{Read, Write};
+
+        async fn setup(
+            V2Message(setup): V2Message<SetupConnection>,
+            Sess(session): Sess<()>,
+        ) -> crate::Result<bool> {
+            session.send_v2(&SetupConnectionSuccess {
+                used_version: setup.max_version,
+                flags: 0,
+            })?;
+            Ok(true)
+        }
+
+        let config = V2Config::new(0, [0x42; 32]);
+        let authority =
+            XOnlyPublicKey::from_slice(&config.authority_public_key().unwrap()).unwrap();
+
+        let builder = StratumServer::<(), ()>::builder((), 1)
+            .with_host("127.0.0.1")
+            .with_port(0)
+            .with_v2(config);
+        #[cfg(feature = "api")]
+        let builder = builder.with_api_port(0);
+
+        let mut server = builder.build().await.unwrap();
+        server.add_without_response("SetupConnection", setup);
+        let address = server.get_v2_address().unwrap();
+        let handle = tokio::spawn(async move { server.start().await });
+
+        let success = tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            let (mut to_server, mut from_server) = initiate(&authority, |message| {
+                stream.write_all(&message).unwrap();
+                let mut reply = vec![0; RESPONDER_MESSAGE_SIZE];
+                stream.read_exact(&mut reply).unwrap();
+                reply
+            });
+
+            let plaintext = Frame::from_message(&SetupConnection {
+                max_version: 2,
+                min_version: 2,
+                ..Default::default()
+            })
+            .unwrap()
+            .encode();
+            let mut header = plaintext[..HEADER_SIZE].to_vec();
+            to_server.encrypt(&[], &mut header).unwrap();
+            let mut payload = plaintext[HEADER_SIZE..].to_vec();
+            to_server.encrypt(&[], &mut payload).unwrap();
+            stream.write_all(&[header, payload].concat()).unwrap();
+
+            let mut header = vec![0; ENCRYPTED_HEADER_SIZE];
+            stream.read_exact(&mut header).unwrap();
+            from_server.decrypt(&[], &mut header).unwrap();
+            let header = Header::decode(header.try_into().unwrap());
+
+            let mut payload = vec![0; encrypted_len(header.msg_length as usize)];
+            stream.read_exact(&mut payload).unwrap();
+            from_server.decrypt(&[], &mut payload).unwrap();
+
+            Frame {
+                header,
+                payload: payload.into(),
+            }
+            .message::<SetupConnectionSuccess>()
+            .unwrap()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            success,
+            SetupConnectionSuccess {
+                used_version: 2,
+                flags: 0
+            }
+        );
+
+        handle.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_are_bounded() {
+        use crate::connection::{read_v2_frame, Stream};
+        use tokio::io::{AsyncWriteExt, BufReader};
+
+        let config = V2Config::new(0, [0x42; 32]);
+        let keys = NoiseKeys::new(&config).unwrap();
+        let authority =
+            XOnlyPublicKey::from_slice(&config.authority_public_key().unwrap()).unwrap();
+
+        let mut server = None;
+        let (mut to_server, _) = initiate(&authority, |message| {
+            let (reply, encryptor, decryptor) = respond(&keys, message).unwrap();
+            server = Some((encryptor, decryptor));
+            reply
+        });
+        let (_, mut decryptor) = server.unwrap();
+
+        let (stream, mut miner) = tokio::io::duplex(1024);
+        let stream: Box<dyn Stream> = Box::new(stream);
+        let (read_half, _write_half) = tokio::io::split(BufReader::new(stream));
+        let mut reader = BufReader::new(read_half);
+
+        let mut header = |msg_length| {
+            let mut header = Header {
+                extension_type: 0,
+                msg_type: 0,
+                msg_length,
+            }
+            .encode()
+            .to_vec();
+            to_server.encrypt(&[], &mut header).unwrap();
+            header
+        };
+
+        // A payload that arrives too slowly times out.
+        miner.write_all(&header(16)).await.unwrap();
+        let result = read_v2_frame(&mut reader, &mut decryptor, 1024, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(crate::Error::FrameTimeout)));
+
+        // A length over the limit is refused before its payload is read.
+        miner.write_all(&header(1 << 20)).await.unwrap();
+        let result = read_v2_frame(&mut reader, &mut decryptor, 1024, Duration::from_secs(5)).await;
+        assert!(matches!(result, Err(crate::Error::FrameTooLarge(1024))));
+    }
+
+    #[test]
+    fn large_payloads_are_chunked() {
+        assert_eq!(encrypted_len(0), 0);
+        assert_eq!(encrypted_len(1), 17);
+        assert_eq!(encrypted_len(CHUNK_SIZE - MAC_SIZE), CHUNK_SIZE);
+        assert_eq!(
+            encrypted_len(CHUNK_SIZE - MAC_SIZE + 1),
+            CHUNK_SIZE + 1 + MAC_SIZE
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use bytes::{Buf, BytesMut};
+use futures::{ready, Sink, Stream as _};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{self, Message},
+    WebSocketStream,
+};
+
+/// Exposes a WebSocket as a newline delimited byte stream, so that WebSocket miners go through
+/// the same reader and write loop as TCP miners.
+///
+/// Every text (or binary) message read is one frame and is followed by a newline. Writes are
+/// buffered until they are flushed, and each line is then sent as its own text message. Pings and
+/// close frames are answered while reading, and a closed socket reads as the end of the stream.
+pub(crate) struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+}
+
+impl<S> WebSocket<S> {
+    pub(crate) fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocket {
+            inner,
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Takes the next line out of the write buffer, or everything left if there is no newline,
+    /// e.g. for raw buffers. Returns `None` once the buffer is empty.
+    fn next_message(&mut self) -> Option<Message> {
+        while !self.write_buffer.is_empty() {
+            let message = match self.write_buffer.iter().position(|byte| *byte == b'\n') {
+                Some(newline) => {
+                    let line = self.write_buffer.split_to(newline).freeze();
+                    self.write_buffer.advance(1);
+                    line
+                }
+                None => self.write_buffer.split().freeze(),
+            };
+
+            if message.is_empty() {
+                continue;
+            }
+
+            return Some(match tungstenite::Utf8Bytes::try_from(message.clone()) {
+                Ok(text) => Message::Text(text),
+                Err(_) => Message::Binary(message),
+            });
+        }
+
+        None
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.read_buffer.is_empty() {
+            let message = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(
+                    tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed,
+                ))
+                | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            };
+
+            match message {
+                Message::Text(text) => {
+                    this.read_buffer.extend_from_slice(text.as_bytes());
+                    this.read_buffer.extend_from_slice(b"\n");
+                }
+                Message::Binary(data) => {
+                    this.read_buffer.extend_from_slice(&data);
+                    this.read_buffer.extend_from_slice(b"\n");
+                }
+                // The reply to a close frame is sent on the next poll, which then ends the stream.
+                Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => {}
+            }
+        }
+
+        let len = buf.remaining().min(this.read_buffer.len());
+        buf.put_slice(&this.read_buffer.split_to(len));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().write_buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.write_buffer.is_empty() {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io_error)?;
+
+            if let Some(message) = this.next_message() {
+                Pin::new(&mut this.inner)
+                    .start_send(message)
+                    .map_err(io_error)?;
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{extract::Sess, Result, StratumServer};
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::{
+        protocol::frame::coding::{Data, OpCode},
+        Message,
+    };
+
+    #[tokio::test]
+    async fn websocket_miners_reach_handlers() {
+        async fn subscribe(Sess(_): Sess<()>) -> Result<bool> {
+            Ok(true)
+        }
+
+        let builder = StratumServer::<(), ()>::builder((), 1)
+            .with_host("127.0.0.1")
+            .with_port(0)
+            .with_websocket(0);
+        #[cfg(feature = "api")]
+        let builder = builder.with_api_port(0);
+        let mut server = builder.build().await.unwrap();
+        server.add("mining.subscribe", subscribe);
+        let address = server.get_websocket_address().unwrap();
+        let miners = server.get_miner_list();
+        let handle = tokio::spawn(async move { server.start().await });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut socket, _) = tokio_tungstenite::client_async(format!("ws://{address}/"), stream)
+            .await
+            .unwrap();
+
+        socket
+            .send(Message::text(
+                r#"{"id":1,"method":"mining.subscribe","params":[]}"#,
+            ))
+            .await
+            .unwrap();
+        let Some(Ok(Message::Text(response))) = socket.next().await else {
+            panic!("expected a text response");
+        };
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], true);
+
+        socket
+            .send(Message::Ping(b"ping"[..].into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Pong(b"ping"[..].into())
+        );
+
+        socket.close(None).await.unwrap();
+        assert!(matches!(
+            socket.next().await,
+            Some(Ok(Message::Close(_))) | None
+        ));
+
+        // Closing the WebSocket ends the session.
+        for _ in 0..50 {
+            if miners.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(miners.is_empty());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_the_socket() {
+        let builder = StratumServer::<(), ()>::builder((), 1)
+            .with_host("127.0.0.1")
+            .with_port(0)
+            .with_websocket(0)
+            .with_max_frame_size(1024);
+        #[cfg(feature = "api")]
+        let builder = builder.with_api_port(0);
+        let mut server = builder.build().await.unwrap();
+        let address = server.get_websocket_address().unwrap();
+        let handle = tokio::spawn(async move { server.start().await });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut socket, _) = tokio_tungstenite::client_async(format!("ws://{address}/"), stream)
+            .await
+            .unwrap();
+
+        // Sent in fragments, none of which is over the limit on its own, and never finished, so
+        // that nothing would reach the codec if tungstenite kept buffering them.
+        let message = "a".repeat(2048);
+        for (i, chunk) in message.as_bytes().chunks(512).enumerate() {
+            let data = tokio_tungstenite::tungstenite::protocol::frame::Frame::message(
+                chunk.to_vec(),
+                if i == 0 {
+                    OpCode::Data(Data::Text)
+                } else {
+                    OpCode::Data(Data::Continue)
+                },
+                false,
+            );
+            if socket.send(Message::Frame(data)).await.is_err() {
+                break;
+            }
+        }
+
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+
+        handle.abort();
+    }
+}
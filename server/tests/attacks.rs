@@ -1,5 +1,14 @@
-mod common;
-use std::time::Instant;
+pub mod common;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use stratum_server::{extract::Id, MessageRateLimit, Result, StratumServer};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task::JoinHandle,
+};
 use tokio_test::assert_ok;
 
 #[tokio::test]
@@ -30,3 +39,230 @@ async fn test_long_running_sockets() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+async fn auth(Id(_): Id) -> Result<bool> {
+    Ok(true)
+}
+
+/// Spawns a server that bans frame violations, with a 1 KiB line limit and a 500ms frame timeout.
+async fn spawn_limited_server() -> anyhow::Result<(SocketAddr, JoinHandle<Result<()>>)> {
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_max_frame_size(1024)
+        .with_frame_timeout(Duration::from_millis(500))
+        .with_ban_manager(true)
+        .with_frame_violation_ban(true);
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+
+    let mut server = builder.build().await?;
+    server.add("auth", auth);
+    let address = server.get_address();
+
+    Ok((address, tokio::spawn(async move { server.start().await })))
+}
+
+async fn is_closed(stream: &mut TcpStream) -> bool {
+    let mut rest = Vec::new();
+    matches!(
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await,
+        Ok(Ok(0) | Err(_))
+    )
+}
+
+/// Whether the server answers a valid request on a new connection.
+async fn is_answered(address: SocketAddr) -> anyhow::Result<bool> {
+    let mut stream = TcpStream::connect(address).await?;
+    // The write fails if the server already closed the connection.
+    let _ = stream
+        .write_all(b"{\"id\":1,\"method\":\"auth\",\"params\":[]}\n")
+        .await;
+
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response)).await;
+
+    Ok(!response.is_empty())
+}
+
+#[tokio::test]
+async fn test_oversized_frame() -> anyhow::Result<()> {
+    common::init();
+
+    let (addr, server_handle) = spawn_limited_server().await?;
+    assert!(is_answered(addr).await?);
+
+    // No newline ever arrives, the server must give up at the limit rather than buffer it all.
+    let mut stream = TcpStream::connect(addr).await?;
+    let _ = stream.write_all(&[b'a'; 64 * 1024]).await;
+    assert!(is_closed(&mut stream).await);
+    assert!(!is_answered(addr).await?);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trickled_frame() -> anyhow::Result<()> {
+    common::init();
+
+    let (addr, server_handle) = spawn_limited_server().await?;
+
+    // A byte every 100ms never completes a line, but would keep an idle timeout from firing.
+    let now = Instant::now();
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = b"{\"id\":1,\"method\":\"auth\",\"params\":[]}\n";
+    for byte in request {
+        if stream.write_all(&[*byte]).await.is_err() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(is_closed(&mut stream).await);
+    assert!(now.elapsed() < Duration::from_secs(2));
+
+    // The ban is on the IP, a new connection from another port is refused as well.
+    assert!(!is_answered(addr).await?);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connections_per_ip() -> anyhow::Result<()> {
+    common::init();
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_max_connections_per_ip(2)
+        .with_ban_manager(true)
+        .with_connection_limit_ban(2);
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+
+    let mut server = builder.build().await?;
+    server.add("auth", auth);
+    let addr = server.get_address();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let _first = TcpStream::connect(addr).await?;
+    let second = TcpStream::connect(addr).await?;
+    let mut third = TcpStream::connect(addr).await?;
+    assert!(is_closed(&mut third).await);
+
+    // The second refusal bans the address, so even a free slot doesn't let it back in.
+    let mut fourth = TcpStream::connect(addr).await?;
+    assert!(is_closed(&mut fourth).await);
+    drop(second);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!is_answered(addr).await?);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connections_per_proxied_ip() -> anyhow::Result<()> {
+    common::init();
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_proxy(true)
+        .with_max_connections_per_ip(1);
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+
+    let mut server = builder.build().await?;
+    server.add("auth", auth);
+    let addr = server.get_address();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    // Every connection comes from the load balancer, only the PROXY header tells them apart.
+    let proxied = |source: &'static str| async move {
+        let mut stream = TcpStream::connect(addr).await?;
+        let header = format!("PROXY TCP4 {source} 192.0.2.1 4000 3333\r\n");
+        stream.write_all(header.as_bytes()).await?;
+        anyhow::Ok(stream)
+    };
+
+    let mut first = proxied("203.0.113.7").await?;
+    first
+        .write_all(b"{\"id\":1,\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+    let mut line = String::new();
+    BufReader::new(&mut first).read_line(&mut line).await?;
+    assert!(line.contains("\"result\":true"));
+
+    let mut second = proxied("203.0.113.7").await?;
+    assert!(is_closed(&mut second).await);
+
+    let mut other = proxied("203.0.113.8").await?;
+    other
+        .write_all(b"{\"id\":1,\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+    let mut line = String::new();
+    BufReader::new(&mut other).read_line(&mut line).await?;
+    assert!(line.contains("\"result\":true"));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_flood() -> anyhow::Result<()> {
+    common::init();
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_message_rate_limit(MessageRateLimit::new().with_rate(1.0, 2).with_ban(4))
+        .with_ban_manager(true);
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+
+    let mut server = builder.build().await?;
+    server.add("auth", auth);
+    let addr = server.get_address();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+    let mut requests = String::new();
+    for id in 1..=4 {
+        requests.push_str(&format!(
+            "{{\"id\":{id},\"method\":\"auth\",\"params\":[]}}\n"
+        ));
+    }
+    stream.write_all(requests.as_bytes()).await?;
+
+    // The burst is handled, what follows is answered with an error.
+    for id in 1..=4 {
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        let response: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(response["id"], id);
+        if id <= 2 {
+            assert_eq!(response["result"], true);
+        } else {
+            assert_eq!(response["error"][1], "Rate limit exceeded");
+        }
+    }
+
+    // Keeping it up gets the session banned.
+    let _ = stream
+        .write_all(b"{\"id\":5,\"method\":\"auth\",\"params\":[]}\n{\"id\":6,\"method\":\"auth\",\"params\":[]}\n")
+        .await;
+    let mut rest = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut rest)).await??;
+    assert!(!rest.contains("\"id\":6"));
+    assert!(!is_answered(addr).await?);
+
+    server_handle.abort();
+
+    Ok(())
+}
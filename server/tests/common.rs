@@ -2,7 +2,10 @@
 #![allow(clippy::redundant_async_block)]
 
 use std::{net::SocketAddr, sync::Once, time::Duration};
-use stratum_server::{Result, Session, SessionList, StratumRequest, StratumServer};
+use stratum_server::{
+    extract::{Authorized, Id, Params},
+    Result, Session, SessionList, StratumRequest, StratumServer, StratumServerBuilder,
+};
 use tokio::{io::AsyncReadExt, net::TcpStream, task::JoinHandle, time::sleep};
 use tokio_util::sync::CancellationToken;
 use tracing::subscriber::set_global_default;
@@ -60,6 +63,14 @@ pub async fn handle_auth(
     Ok(login)
 }
 
+pub async fn handle_submit(
+    _: Authorized,
+    Id(id): Id,
+    Params((_username, job_id)): Params<(String, String)>,
+) -> Result<String> {
+    Ok(format!("{id}:{job_id}"))
+}
+
 pub async fn poll_global(
     _state: State,
     _connection_list: SessionList<ConnectionState>,
@@ -123,6 +134,7 @@ pub async fn spawn_full_server() -> Result<(SocketAddr, JoinHandle<Result<()>>,
     let address = server.get_address();
 
     server.add("auth", handle_auth);
+    server.add("mining.submit", handle_submit);
     server.global("Poll Global", poll_global);
 
     let handle = tokio::spawn(async move { server.start().await });
@@ -132,6 +144,36 @@ pub async fn spawn_full_server() -> Result<(SocketAddr, JoinHandle<Result<()>>,
     Ok((address, handle, cancel_token))
 }
 
+/// A builder for a server on a free port of 127.0.0.1, with the API on a free port as well.
+pub fn local_builder<State, CState>(state: State) -> StratumServerBuilder<State, CState>
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Default + Clone + Send + Sync + 'static,
+{
+    let builder = StratumServer::builder(state, 1)
+        .with_host("127.0.0.1")
+        .with_port(0);
+
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+
+    builder
+}
+
+/// Starts `server` in the background, returning its address along with the task running it.
+pub fn spawn_server<State, CState>(
+    mut server: StratumServer<State, CState>,
+) -> (SocketAddr, JoinHandle<Result<()>>)
+where
+    State: Clone + Send + Sync + 'static,
+    CState: Default + Clone + Send + Sync + 'static,
+{
+    let address = server.get_address();
+    let handle = tokio::spawn(async move { server.start().await });
+
+    (address, handle)
+}
+
 //@note these connections do not send any messages.
 pub async fn generate_connections<A: Into<SocketAddr>>(
     num: usize,
@@ -1,5 +1,723 @@
 pub mod common;
 
+use std::time::Duration;
+use stratum_server::{ReadyIndicator, SocketOptions, StratumServer};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_test::assert_ok;
+
+#[tokio::test]
+async fn test_response_reuses_request_id() -> anyhow::Result<()> {
+    common::init();
+
+    let (addr, server_handle, shutdown) = assert_ok!(common::spawn_full_server().await);
+
+    let stream = TcpStream::connect(addr).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    write_half
+        .write_all(b"{\"id\":7,\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let response: serde_json::Value = serde_json::from_str(&line)?;
+    assert_eq!(
+        response,
+        serde_json::json!({"id": 7, "result": true, "error": null})
+    );
+
+    // Notifications have a null id and must not be answered, nor may requests with an id that
+    // is neither a number nor a string.
+    write_half
+        .write_all(b"{\"id\":null,\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+    write_half
+        .write_all(b"{\"id\":[1],\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+    write_half
+        .write_all(b"{\"id\":{\"a\":1},\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+
+    let mut line = String::new();
+    let read = tokio::time::timeout(Duration::from_millis(500), reader.read_line(&mut line)).await;
+    assert!(read.is_err(), "received unexpected response: {line}");
+
+    shutdown.cancel();
+
+    let server_result = assert_ok!(server_handle.await);
+
+    assert_ok!(server_result);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_extractor_returns_error() -> anyhow::Result<()> {
+    common::init();
+
+    let (addr, server_handle, shutdown) = assert_ok!(common::spawn_full_server().await);
+
+    let stream = TcpStream::connect(addr).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    // Submitting before authorizing is rejected by the `Authorized` extractor, but the connection
+    // is kept open.
+    write_half
+        .write_all(b"{\"id\":2,\"method\":\"mining.submit\",\"params\":[\"user\",\"bf\"]}\n")
+        .await?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let response: serde_json::Value = serde_json::from_str(&line)?;
+    assert_eq!(
+        response,
+        serde_json::json!({"id": 2, "result": null, "error": [24, "Unauthorized worker", null]})
+    );
+
+    write_half
+        .write_all(b"{\"id\":3,\"method\":\"auth\",\"params\":[]}\n")
+        .await?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&line)?,
+        serde_json::json!({"id": 3, "result": true, "error": null})
+    );
+
+    shutdown.cancel();
+
+    let server_result = assert_ok!(server_handle.await);
+
+    assert_ok!(server_result);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_method_is_answered_and_scored() -> anyhow::Result<()> {
+    common::init();
+
+    let (addr, server_handle, shutdown) = assert_ok!(common::spawn_full_server().await);
+
+    let stream = TcpStream::connect(addr).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    write_half
+        .write_all(b"{\"id\":4,\"method\":\"mining.configure\",\"params\":[]}\n")
+        .await?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let response: serde_json::Value = serde_json::from_str(&line)?;
+    assert_eq!(
+        response,
+        serde_json::json!({"id": 4, "result": null, "error": [-32601, "Method not found", null]})
+    );
+
+    // Each unknown method adds 10 to the ban score, the default allowance is 100.
+    for id in 5..14 {
+        let msg = format!("{{\"id\":{id},\"method\":\"probe.{id}\",\"params\":[]}}\n");
+        write_half.write_all(msg.as_bytes()).await?;
+    }
+
+    let mut responses = 0;
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok::<_, std::io::Error>(());
+            }
+            responses += 1;
+        }
+    })
+    .await;
+
+    assert!(matches!(closed, Ok(Ok(()))), "connection was not closed");
+    // The probe that tips the ban score is still answered before the connection closes.
+    assert_eq!(responses, 9);
+
+    shutdown.cancel();
+
+    let server_result = assert_ok!(server_handle.await);
+
+    assert_ok!(server_result);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_connections_are_enforced() -> anyhow::Result<()> {
+    common::init();
+
+    let ready_indicator = ReadyIndicator::new(true);
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_max_connections(1)
+        .with_overflow_reconnect("overflow.example.com", 3333)
+        .with_ready_indicator(ready_indicator.create_new());
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    let addr = server.get_address();
+    let sessions = server.get_miner_list();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let first = TcpStream::connect(addr).await?;
+    wait_for(|| sessions.len() == 1).await;
+    assert!(!ready_indicator.status(), "a full server must not be ready");
+
+    // The second miner is sent to the overflow host, then disconnected.
+    let mut reader = BufReader::new(TcpStream::connect(addr).await?);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&line)?,
+        serde_json::json!({
+            "id": null,
+            "method": "client.reconnect",
+            "params": ["overflow.example.com", 3333],
+        })
+    );
+    assert_eq!(reader.read_line(&mut line).await?, 0);
+    assert_eq!(sessions.rejected_connections(), 1);
+
+    drop(first);
+    wait_for(|| sessions.is_empty()).await;
+    assert!(
+        ready_indicator.status(),
+        "a drained server must be ready again"
+    );
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshaking_connections_count_toward_max_connections() -> anyhow::Result<()> {
+    common::init();
+
+    let ready_indicator = ReadyIndicator::new(true);
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_max_connections(1)
+        .with_proxy(true)
+        .with_ready_indicator(ready_indicator.create_new());
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    let addr = server.get_address();
+    let sessions = server.get_miner_list();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    // Never sends its PROXY header, so it never becomes a session.
+    let first = TcpStream::connect(addr).await?;
+    wait_for(|| sessions.connection_stats().open == 1).await;
+    assert!(sessions.is_empty());
+    assert!(sessions.is_full());
+    assert!(!ready_indicator.status(), "a full server must not be ready");
+
+    let mut second = TcpStream::connect(addr).await?;
+    let mut rest = Vec::new();
+    assert_eq!(second.read_to_end(&mut rest).await?, 0);
+    assert_eq!(sessions.connection_stats().rejected, 1);
+
+    drop(first);
+    wait_for(|| sessions.connection_stats().open == 0).await;
+    assert!(
+        ready_indicator.status(),
+        "a drained server must be ready again"
+    );
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_parallel_acceptors() -> anyhow::Result<()> {
+    common::init();
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_acceptors(4)
+        .with_socket_options(
+            SocketOptions::new()
+                .with_nodelay(true)
+                .with_keepalive(Duration::from_secs(30), Duration::from_secs(5)),
+        );
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    let addr = server.get_address();
+    let sessions = server.get_miner_list();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let mut clients = Vec::new();
+    for _ in 0..32 {
+        clients.push(TcpStream::connect(addr).await?);
+    }
+    wait_for(|| sessions.len() == 32).await;
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_listener() -> anyhow::Result<()> {
+    use stratum_server::ListenerConfig;
+    use tokio::net::UnixStream;
+
+    common::init();
+
+    let directory = std::env::temp_dir();
+    let plain = directory.join(format!("stratum-{}.sock", std::process::id()));
+    let proxied = directory.join(format!("stratum-{}-proxy.sock", std::process::id()));
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_listener(ListenerConfig::unix("relay", &plain))
+        .with_listener(ListenerConfig::unix("proxied", &proxied).with_proxy(true));
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    assert_eq!(server.get_listener_path("relay"), Some(plain.clone()));
+    assert_eq!(server.get_listener_address("relay"), None);
+    let sessions = server.get_miner_list();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    // Unix socket peers are each given an address of their own.
+    let _first = UnixStream::connect(&plain).await?;
+    let _second = UnixStream::connect(&plain).await?;
+    wait_for(|| sessions.len() == 2).await;
+    for session in sessions.get_all_miners() {
+        let std::net::IpAddr::V6(ip) = session.ip().ip() else {
+            panic!("Unix socket peers have IPv6 addresses");
+        };
+        assert_eq!(ip.segments()[..4], [0x100, 0, 0, 0]);
+    }
+
+    // Behind a relay, the PROXY header says who the miner is.
+    let mut stream = UnixStream::connect(&proxied).await?;
+    stream
+        .write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 4000 3333\r\n")
+        .await?;
+    wait_for(|| sessions.len() == 3).await;
+    assert!(sessions
+        .get_all_miners()
+        .iter()
+        .any(|session| session.ip() == "203.0.113.7:4000".parse().unwrap()));
+
+    server_handle.abort();
+    let _ = std::fs::remove_file(plain);
+    let _ = std::fs::remove_file(proxied);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_handoff_stops_accepting_and_drains() -> anyhow::Result<()> {
+    use stratum_server::HandoffConfig;
+
+    common::init();
+
+    let output = std::env::temp_dir().join(format!("stratum-{}-handoff", std::process::id()));
+    let script = format!(
+        r#"[ -S /dev/fd/3 ] && echo "$LISTEN_FDS $LISTEN_FDNAMES" > {}"#,
+        output.display()
+    );
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_handoff(HandoffConfig::new("/bin/sh", ["-c", &script]));
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    let addr = server.get_address();
+    let sessions = server.get_miner_list();
+    let handoff = server.get_handoff().unwrap();
+    #[cfg(feature = "api")]
+    let api = ("127.0.0.1", server.get_api_address().port());
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let miner = TcpStream::connect(addr).await?;
+    wait_for(|| sessions.len() == 1).await;
+    #[cfg(feature = "api")]
+    assert!(livez(api).await?);
+
+    handoff.trigger();
+    wait_for(|| output.exists()).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let expected = if cfg!(feature = "api") {
+        "2 stratum:api\n"
+    } else {
+        "1 stratum\n"
+    };
+    assert_eq!(std::fs::read_to_string(&output)?, expected);
+
+    // The API socket belongs to the replacement now.
+    #[cfg(feature = "api")]
+    assert!(!livez(api).await?);
+
+    // Sessions are left to finish, after which the server exits.
+    assert!(!server_handle.is_finished());
+    drop(miner);
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await???;
+
+    let _ = std::fs::remove_file(output);
+
+    Ok(())
+}
+
+/// Whether the API answers `/livez`.
+#[cfg(all(target_os = "linux", feature = "api"))]
+async fn livez(address: (&str, u16)) -> anyhow::Result<bool> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(b"GET /livez HTTP/1.0\r\n\r\n").await?;
+
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response)).await;
+
+    Ok(response.starts_with(b"HTTP/1.0 200") || response.starts_with(b"HTTP/1.1 200"))
+}
+
+#[cfg(feature = "v1")]
+#[tokio::test]
+async fn test_drain_redirects_sessions() -> anyhow::Result<()> {
+    use stratum_server::{DrainConfig, DrainProgress};
+
+    common::init();
+
+    let ready = ReadyIndicator::new(true);
+    let siblings = [("10.0.0.1", 3333), ("10.0.0.2", 3334)];
+
+    let builder = StratumServer::<(), ()>::builder((), 1)
+        .with_host("127.0.0.1")
+        .with_port(0)
+        .with_ready_indicator(ready.create_new())
+        .with_shutdown_timeout(Duration::from_secs(5))
+        .with_drain(DrainConfig::siblings(siblings).with_window(Duration::from_millis(200)));
+    #[cfg(feature = "api")]
+    let builder = builder.with_api_port(0);
+    let mut server = builder.build().await?;
+    let addr = server.get_address();
+    let sessions = server.get_miner_list();
+    let drain = server.get_drain();
+    let server_handle = tokio::spawn(async move { server.start().await });
+
+    let mut miners = Vec::new();
+    for _ in 0..2 {
+        miners.push(BufReader::new(TcpStream::connect(addr).await?));
+    }
+    wait_for(|| sessions.len() == 2).await;
+
+    drain.trigger();
+
+    for miner in &mut miners {
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_secs(1), miner.read_line(&mut line)).await??;
+
+        let message: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(message["method"], "client.reconnect");
+        // Both miners are on 127.0.0.1, so they are sent to the same sibling.
+        let (_, port) = siblings
+            .iter()
+            .find(|(host, _)| message["params"][0] == *host)
+            .unwrap();
+        assert_eq!(message["params"][1], *port);
+    }
+
+    assert!(!ready.status());
+    assert_eq!(
+        drain.progress(),
+        DrainProgress {
+            draining: true,
+            sessions: 2,
+            redirected: 2,
+            remaining: 2,
+        }
+    );
+
+    // The server waits for the miners to move, then exits.
+    assert!(!server_handle.is_finished());
+    drop(miners);
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await???;
+    assert_eq!(drain.progress().remaining, 0);
+
+    Ok(())
+}
+
+async fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+#[tokio::test]
+async fn test_sessions_use_the_settings_of_their_listener() -> anyhow::Result<()> {
+    use stratum_server::{extract::Sess, ListenerConfig, Result, SessionID};
+
+    async fn subscribe(Sess(session): Sess<()>) -> Result<serde_json::Value> {
+        let session_id = SessionID::from(1);
+        session.register_worker(session_id, None, None, uuid::Uuid::new_v4());
+        let difficulty = session.get_difficulties(session_id).unwrap().current();
+        Ok(serde_json::json!([session.listener(), difficulty.as_u64()]))
+    }
+
+    async fn request(address: std::net::SocketAddr) -> anyhow::Result<serde_json::Value> {
+        let mut stream = BufReader::new(TcpStream::connect(address).await?);
+        stream
+            .write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n")
+            .await?;
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        Ok(serde_json::from_str::<serde_json::Value>(&line)?["result"].clone())
+    }
+
+    common::init();
+
+    let mut server = common::local_builder::<(), ()>(())
+        .with_listener(ListenerConfig::new("high", "127.0.0.1", 0).with_initial_difficulty(1 << 20))
+        .build()
+        .await?;
+    server.add("mining.subscribe", subscribe);
+    let high = server.get_listener_address("high").unwrap();
+    assert!(server.get_listener_address("low").is_none());
+    let (addr, server_handle) = common::spawn_server(server);
+
+    let default = request(addr).await?;
+    assert_eq!(default[0], serde_json::Value::Null);
+
+    assert_eq!(request(high).await?, serde_json::json!(["high", 1 << 20]));
+    assert_ne!(default[1], 1 << 20);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicate_listener_names_are_rejected() {
+    use stratum_server::{Error, ListenerConfig};
+
+    common::init();
+
+    let result = common::local_builder::<(), ()>(())
+        .with_listener(ListenerConfig::new("high", "127.0.0.1", 0))
+        .with_listener(ListenerConfig::new("high", "127.0.0.1", 0))
+        .build()
+        .await;
+
+    assert!(matches!(result, Err(Error::DuplicateListener(name)) if name == "high"));
+}
+
+/// A PROXY v2 header for a TCP4 connection from 92.118.161.17:55867 to 172.20.42.228:8080,
+/// followed by `tlvs`.
+fn proxy_v2_header(tlvs: &[u8]) -> Vec<u8> {
+    let mut payload = vec![92, 118, 161, 17, 172, 20, 42, 228];
+    payload.extend_from_slice(&55867u16.to_be_bytes());
+    payload.extend_from_slice(&8080u16.to_be_bytes());
+    payload.extend_from_slice(tlvs);
+
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.extend_from_slice(&[0x21, 0x11]);
+    header.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_be_bytes());
+    header.extend_from_slice(&payload);
+    header
+}
+
+#[tokio::test]
+async fn test_proxied_sessions_use_the_miner_address() -> anyhow::Result<()> {
+    use stratum_server::{extract::Sess, proxy_protocol::TLV_AUTHORITY, Result};
+
+    async fn subscribe(Sess(session): Sess<()>) -> Result<String> {
+        let header = session.proxy_header().unwrap();
+        Ok(format!(
+            "{} {:?}",
+            session.ip(),
+            header.tlv(TLV_AUTHORITY).map(<[u8]>::to_vec)
+        ))
+    }
+
+    common::init();
+
+    let mut server = common::local_builder::<(), ()>(())
+        .with_proxy(true)
+        .with_proxy_trusted_sources(&["127.0.0.1".parse()?])
+        .with_proxy_destination_port(8080)
+        .build()
+        .await?;
+    server.add("mining.subscribe", subscribe);
+    let (addr, server_handle) = common::spawn_server(server);
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(&proxy_v2_header(&[TLV_AUTHORITY, 0, 1, b'a']))
+        .await?;
+    stream
+        .write_all(b"{\"id\":1,\"method\":\"mining.subscribe\",\"params\":[]}\n")
+        .await?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).await?;
+    assert!(
+        response.contains("92.118.161.17:55867 Some([97])"),
+        "{response}"
+    );
+
+    // Connections that were meant for another port are dropped.
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(b"PROXY TCP4 92.118.161.17 172.20.42.228 55867 3333\r\n")
+        .await?;
+    // The server may reset the connection, as it closes it without reading everything.
+    let mut rest = Vec::new();
+    assert!(matches!(
+        stream.read_to_end(&mut rest).await,
+        Ok(0) | Err(_)
+    ));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_untrusted_proxy_sources_are_dropped() -> anyhow::Result<()> {
+    common::init();
+
+    let server = common::local_builder::<(), ()>(())
+        .with_proxy(true)
+        .with_proxy_trusted_sources(&["10.0.0.1".parse()?])
+        .build()
+        .await?;
+    let (addr, server_handle) = common::spawn_server(server);
+
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(b"PROXY TCP4 92.118.161.17 172.20.42.228 55867 8080\r\n")
+        .await?;
+    // The server may reset the connection, as it closes it without reading everything.
+    let mut rest = Vec::new();
+    assert!(matches!(
+        stream.read_to_end(&mut rest).await,
+        Ok(0) | Err(_)
+    ));
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[cfg(feature = "btcagent")]
+#[tokio::test]
+async fn test_agent_workers_reach_handlers() -> anyhow::Result<()> {
+    use stratum_server::{
+        btcagent::{
+            ExMessage, MiningSetDiff, RegisterWorker, SubmitShare, REGISTER_WORKER, SUBMIT_SHARE,
+        },
+        extract::{AgentMessage, Sess, WorkerSession},
+        Result,
+    };
+
+    async fn register(
+        AgentMessage(message): AgentMessage,
+        WorkerSession(session_id): WorkerSession,
+        Sess(session): Sess<()>,
+    ) -> Result<bool> {
+        let ExMessage::RegisterWorker(register) = message else {
+            return Ok(false);
+        };
+
+        session.register_worker(
+            session_id,
+            Some(register.client_agent),
+            Some(register.worker_name),
+            uuid::Uuid::new_v4(),
+        );
+        Ok(true)
+    }
+
+    async fn submit(
+        WorkerSession(session_id): WorkerSession,
+        Sess(session): Sess<()>,
+    ) -> Result<bool> {
+        let Some(worker) = session.get_worker_by_session_id(session_id) else {
+            return Ok(false);
+        };
+
+        let session_id = u16::try_from(worker.session_id().as_u32()).unwrap();
+        session.send_ex_message(&ExMessage::MiningSetDiff(MiningSetDiff {
+            diff_exp: 14,
+            session_ids: vec![session_id],
+        }))?;
+        Ok(true)
+    }
+
+    common::init();
+
+    let mut server = common::local_builder::<(), ()>(()).build().await?;
+    server.add_without_response(REGISTER_WORKER, register);
+    server.add_without_response(SUBMIT_SHARE, submit);
+    let (addr, server_handle) = common::spawn_server(server);
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let register = ExMessage::RegisterWorker(RegisterWorker {
+        session_id: 9,
+        client_agent: String::from("cgminer/4.10.0"),
+        worker_name: String::from("user.worker1"),
+    });
+    let submit = ExMessage::SubmitShare(SubmitShare {
+        job_id: 1,
+        session_id: 9,
+        extra_nonce2: 2,
+        nonce: 3,
+        time: Some(4),
+        version_mask: None,
+    });
+    stream
+        .write_all(&[register.encode()?, submit.encode()?].concat())
+        .await?;
+
+    let mut reply = vec![0; 9];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut reply)).await??;
+
+    assert_eq!(
+        ExMessage::decode(&reply)?,
+        ExMessage::MiningSetDiff(MiningSetDiff {
+            diff_exp: 14,
+            session_ids: vec![9],
+        })
+    );
+
+    server_handle.abort();
+
+    Ok(())
+}
+
 // #[tokio::test]
 // async fn test_basic_server() {
 //     //@todo remove this because we
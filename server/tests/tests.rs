@@ -1,8 +1,8 @@
 //@todo test to ensure startup time is under some limit (Set that as a const in tests thne.)
 //@todo see Vector tests and tikv and linkered.
 //
-////@todo tests for various allocators. as well as some benchmarks
-mod common;
+//@todo tests for various allocators. as well as some benchmarks
+pub mod common;
 
 use std::time::Duration;
 